@@ -1,7 +1,57 @@
 //! # minipre
 //!
-//! minipre is a C-like generic preprocessor for Rust. It supports macros, #if, #elif, #else and
-//! #endif.
+//! minipre is a C-like generic preprocessor for Rust. It supports macros, #define, #undef,
+//! #include, #if, #elif, #else and #endif. `#ifdef NAME`, `#ifndef NAME`, `#elifdef NAME` and
+//! `#elifndef NAME` are shorthand for `#if defined(NAME)` and so on, and may be mixed with `#elif`.
+//!
+//! Conditions are integer or string expressions, and may use `defined`, `+`, `-`, `*`, `/`, `%`,
+//! `!`, `&&`, `||`, `==`, `!=`, `<`, `>`, `<=`, `>=`, `?:` and parentheses. Adding two strings joins
+//! them, and zero and the empty string are false. Comparisons give `1` or `0`, and group from the
+//! left as in C, so `2 == 2 == 2` is `(2 == 2) == 2`, which is false. The right-hand side of `&&` and `||` is not
+//! evaluated when the result is already known, so `#if defined(FOO) && FOO > 2` is fine when `FOO`
+//! is undefined. Integers may be written in decimal, in octal with a leading zero, or as a
+//! character literal such as `'A'`. `__has_include("file")` or `__has_include(<file>)` checks
+//! whether the include resolver can find a file, and is false when no resolver is set.
+//!
+//! A macro whose value contains line breaks expands to several lines, each of which is then
+//! processed as if it were in the input, so the expansion may contain directives.
+//!
+//! A line with only a `#` is a null directive, which does nothing.
+//!
+//! `__LINE__` and `__FILE__` are built-in macros, which expand to the current line number and the
+//! name of the current file. They can't be redefined, by directives or through `Context`.
+//!
+//! Names joined with `##` in the operand of `defined` are each expanded and then pasted together,
+//! so `defined(PREFIX ## _FAST)` checks whether `FEATURE_FAST` is defined when `PREFIX` is
+//! `FEATURE`.
+//!
+//! `min(a, b)` and `max(a, b)` give the smaller and larger of two integers.
+//!
+//! `LIST contains element` checks whether a macro defined as a comma-separated list, such as
+//! `a, b, c`, contains an element. The element is taken as it is, rather than being expanded,
+//! while a list macro defined as the name of another macro is followed to that macro's list.
+//!
+//! `#set NAME = expression` defines a macro as the value of an expression, where `#define` would
+//! use the text of the expression as it is. A string value is written as a quoted string literal,
+//! so that it's still a string when the macro is used in a condition.
+//!
+//! `#switch expression` starts a block of `#case value` branches, with an optional `#default`
+//! branch, ending with `#endswitch`. The first case whose value equals the value of the expression
+//! is taken, or otherwise the default, and there is no fall-through between cases.
+//!
+//! `#include_str "file"` inserts the contents of a file as they are, without preprocessing them.
+//! As with `#include`, a last line without a line terminator is ended, rather than being joined
+//! to the line after the directive.
+//!
+//! `#output "name"` sends the content after it to a named output, when processing with
+//! `process_with_outputs`.
+//!
+//! `#assert expression` fails with an error when the expression is false, and otherwise does
+//! nothing.
+//!
+//! `#repeat N` ... `#endrepeat` writes the lines in between `N` times, where `N` may be an
+//! expression. Within the block `__REPEAT_INDEX__` expands to the number of the current iteration,
+//! counting from zero, and within a nested block it refers to the innermost one.
 //!
 //! Process text with the `process` and `process_str` functions.
 //!
@@ -24,17 +74,51 @@
 //! ```
 
 extern crate regex;
+#[cfg(feature = "serde")]
+extern crate serde;
 
-use std::collections::BTreeMap;
+use std::borrow::Cow;
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::convert::TryFrom;
+use std::env;
 use std::error;
 use std::fmt;
-use std::io::{self, BufRead, Write};
+use std::io::{self, BufRead, Read, Write};
+use std::mem;
+use std::panic::RefUnwindSafe;
+use std::path::PathBuf;
+use std::sync::atomic::{self, AtomicBool};
+use std::sync::{Arc, Mutex};
+
+use regex::Regex;
+
+// Limit on the nesting of `#include` directives, to catch files which include themselves
+const MAX_INCLUDE_DEPTH: usize = 200;
+
+// Content lines longer than this are processed in pieces where possible, rather than being held
+// in memory as a whole
+const LONG_LINE: usize = 64 * 1024;
 
-use regex::{Captures, Regex, Replacer};
+// The byte order mark which may start a UTF-8 file
+const BOM: &str = "\u{feff}";
+
+// Macros whose values are provided by the preprocessor, and which can't be redefined
+const BUILTIN_MACROS: &[&str] = &["__FILE__", "__LINE__"];
+
+// Defined as the number of the current iteration within a `#repeat` block
+const REPEAT_INDEX: &str = "__REPEAT_INDEX__";
+
+// The last parameter of a variadic function-like macro, which takes any remaining arguments
+const VARIADIC: &str = "...";
+
+// Replaced by the remaining arguments to a variadic function-like macro, separated by commas
+const VA_ARGS: &str = "__VA_ARGS__";
 
 /// The context for preprocessing a file.
 ///
-/// Contains a list of macros and their definitions.
+/// Contains a list of macros and their definitions. Cloning a context copies its macros, while the
+/// include resolver and trace writer are shared between the clones.
 ///
 /// # Example
 ///
@@ -45,7 +129,436 @@ use regex::{Captures, Regex, Replacer};
 /// ```
 #[derive(Debug, Clone)]
 pub struct Context {
-    defs: BTreeMap<String, String>,
+    // Shared between copies of the context until one of them changes the macros
+    defs: Arc<BTreeMap<String, Macro>>,
+    trim_trailing_newline: bool,
+    unicode_identifiers: bool,
+    dedent_conditional_blocks: bool,
+    undefined_mode: UndefinedMode,
+    validate_inactive_branches: bool,
+    no_directive_injection: bool,
+    identifier_boundaries: bool,
+    list_delimiter: char,
+    bare_word_comparisons: bool,
+    annotate_directives: bool,
+    output_newline: Newline,
+    expand_directive_comments: bool,
+    recognized_directives: Option<Vec<String>>,
+    define_comments: bool,
+    define_trim: TrimPolicy,
+    interpret_escapes_in_defines: bool,
+    require_switch_default: bool,
+    max_expansions_per_line: Option<usize>,
+    max_output_size: Option<usize>,
+    block_scoped_defines: bool,
+    forbidden_names: Arc<Vec<String>>,
+    case_insensitive_string_compare: bool,
+    cpp_compat: bool,
+    leading_comment_prefix: Option<String>,
+    strip_bom: bool,
+    markers: Option<(String, String)>,
+    // Applied to content after other macros, in the order they were defined
+    patterns: Arc<Vec<(Regex, String)>>,
+    expand_content: bool,
+    directive_names: DirectiveNames,
+    // The value of `__FILE__`, which changes within included files
+    file_name: String,
+    include_resolver: Option<Shared<IncludeResolver>>,
+    // The characters which may start and continue an identifier, if they've been set
+    identifier_chars: Option<(Shared<CharPredicate>, Shared<CharPredicate>)>,
+    trace: Option<Shared<Mutex<Box<dyn Write + Send>>>>,
+    // The names of the macros expanded, while they're being recorded for a report
+    used_macros: Option<Shared<Mutex<BTreeSet<String>>>>,
+    // The undefined identifiers evaluated in expressions, if they're being collected, in which
+    // case they're `0`
+    undefined_names: Option<Shared<Mutex<BTreeSet<String>>>>,
+    // Set within a `FrozenContext`, so that directives can't change the macros
+    frozen: bool,
+    // Built on demand, and discarded whenever the set of macros changes
+    regex: Option<Regex>,
+}
+
+// A macro's definition, along with a description of where it came from
+#[derive(Debug, Clone)]
+struct Macro {
+    def: Def,
+    source: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+enum Def {
+    // Replaced by fixed text
+    Text(String),
+    // Replaced by the value of an environment variable at the time of expansion
+    Env(String),
+    // Replaced by the body, with each parameter replaced by the corresponding argument
+    Function { params: Vec<String>, body: String },
+}
+
+impl Def {
+    fn value(&self) -> Cow<'_, str> {
+        match self {
+            Def::Text(value) => Cow::Borrowed(value),
+            Def::Env(var) => Cow::Owned(env::var(var).unwrap_or_default()),
+            Def::Function { body, .. } => Cow::Borrowed(body),
+        }
+    }
+}
+
+/// The line terminator written after each line of output.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Newline {
+    /// `\n`
+    Lf,
+    /// `\r\n`
+    Crlf,
+    /// Whichever terminator the line had in the input.
+    Preserve,
+}
+
+/// The keywords which start each directive, set by `Context::directive_names`.
+///
+/// Each keyword should start with a symbol such as `#` or `%`. Lines starting with any of these
+/// symbols are treated as directives.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DirectiveNames {
+    /// Defaults to `#if`.
+    pub if_: String,
+    /// Defaults to `#elif`.
+    pub elif: String,
+    /// Defaults to `#ifdef`.
+    pub ifdef: String,
+    /// Defaults to `#ifndef`.
+    pub ifndef: String,
+    /// Defaults to `#elifdef`.
+    pub elifdef: String,
+    /// Defaults to `#elifndef`.
+    pub elifndef: String,
+    /// Defaults to `#else`.
+    pub else_: String,
+    /// Defaults to `#endif`.
+    pub endif: String,
+    /// Defaults to `#switch`.
+    pub switch: String,
+    /// Defaults to `#case`.
+    pub case: String,
+    /// Defaults to `#default`.
+    pub default: String,
+    /// Defaults to `#endswitch`.
+    pub endswitch: String,
+    /// Defaults to `#define`.
+    pub define: String,
+    /// Defaults to `#set`.
+    pub set: String,
+    /// Defaults to `#undef`.
+    pub undef: String,
+    /// Defaults to `#push_defs`.
+    pub push_defs: String,
+    /// Defaults to `#pop_defs`.
+    pub pop_defs: String,
+    /// Defaults to `#repeat`.
+    pub repeat: String,
+    /// Defaults to `#endrepeat`.
+    pub endrepeat: String,
+    /// Defaults to `#include`.
+    pub include: String,
+    /// Defaults to `#include_str`.
+    pub include_str: String,
+    /// Defaults to `#assert`.
+    pub assert: String,
+    /// Defaults to `#output`.
+    pub output: String,
+}
+
+impl Default for DirectiveNames {
+    fn default() -> Self {
+        DirectiveNames {
+            if_: "#if".into(),
+            elif: "#elif".into(),
+            ifdef: "#ifdef".into(),
+            ifndef: "#ifndef".into(),
+            elifdef: "#elifdef".into(),
+            elifndef: "#elifndef".into(),
+            else_: "#else".into(),
+            endif: "#endif".into(),
+            switch: "#switch".into(),
+            case: "#case".into(),
+            default: "#default".into(),
+            endswitch: "#endswitch".into(),
+            define: "#define".into(),
+            set: "#set".into(),
+            undef: "#undef".into(),
+            push_defs: "#push_defs".into(),
+            pop_defs: "#pop_defs".into(),
+            repeat: "#repeat".into(),
+            endrepeat: "#endrepeat".into(),
+            include: "#include".into(),
+            include_str: "#include_str".into(),
+            assert: "#assert".into(),
+            output: "#output".into(),
+        }
+    }
+}
+
+impl DirectiveNames {
+    /// Creates the default keywords, with `prefix` in place of the `#`.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// assert_eq!(minipre::DirectiveNames::with_prefix("%").endif, "%endif");
+    /// ```
+    pub fn with_prefix(prefix: &str) -> Self {
+        let mut names = DirectiveNames::default();
+        for name in names.keywords_mut() {
+            name.replace_range(..1, prefix);
+        }
+        names
+    }
+    fn keywords_mut(&mut self) -> [&mut String; 23] {
+        [
+            &mut self.if_,
+            &mut self.elif,
+            &mut self.ifdef,
+            &mut self.ifndef,
+            &mut self.elifdef,
+            &mut self.elifndef,
+            &mut self.else_,
+            &mut self.endif,
+            &mut self.switch,
+            &mut self.case,
+            &mut self.default,
+            &mut self.endswitch,
+            &mut self.define,
+            &mut self.set,
+            &mut self.undef,
+            &mut self.push_defs,
+            &mut self.pop_defs,
+            &mut self.repeat,
+            &mut self.endrepeat,
+            &mut self.include,
+            &mut self.include_str,
+            &mut self.assert,
+            &mut self.output,
+        ]
+    }
+    // Each keyword, along with the default it stands for
+    fn keywords(&self) -> [(&str, &'static str); 23] {
+        [
+            (&self.if_, "#if"),
+            (&self.elif, "#elif"),
+            (&self.ifdef, "#ifdef"),
+            (&self.ifndef, "#ifndef"),
+            (&self.elifdef, "#elifdef"),
+            (&self.elifndef, "#elifndef"),
+            (&self.else_, "#else"),
+            (&self.endif, "#endif"),
+            (&self.switch, "#switch"),
+            (&self.case, "#case"),
+            (&self.default, "#default"),
+            (&self.endswitch, "#endswitch"),
+            (&self.define, "#define"),
+            (&self.set, "#set"),
+            (&self.undef, "#undef"),
+            (&self.push_defs, "#push_defs"),
+            (&self.pop_defs, "#pop_defs"),
+            (&self.repeat, "#repeat"),
+            (&self.endrepeat, "#endrepeat"),
+            (&self.include, "#include"),
+            (&self.include_str, "#include_str"),
+            (&self.assert, "#assert"),
+            (&self.output, "#output"),
+        ]
+    }
+    // Gives the default name of the directive a keyword starts
+    fn canonical(&self, keyword: &str) -> Option<&'static str> {
+        self.keywords()
+            .iter()
+            .find(|&&(name, _)| name == keyword)
+            .map(|&(_, canonical)| canonical)
+    }
+    // Whether a line starts with the symbol which starts any of the keywords
+    fn is_directive(&self, line: &str) -> bool {
+        let line = line.trim_start();
+        self.keywords()
+            .iter()
+            .any(|&(name, _)| name.chars().next().is_some_and(|c| line.starts_with(c)))
+    }
+}
+
+/// The macros defined in a context at some point, saved by `Context::snapshot`.
+#[derive(Debug, Clone)]
+pub struct ContextSnapshot {
+    defs: Arc<BTreeMap<String, Macro>>,
+}
+
+/// A context which can't be changed, made by `Context::freeze`. It's `Send` and `Sync`, so it can
+/// be shared between threads without locking.
+#[derive(Debug, Clone)]
+pub struct FrozenContext {
+    context: Context,
+}
+
+impl FrozenContext {
+    /// Gets the context which was frozen.
+    pub fn context(&self) -> &Context {
+        &self.context
+    }
+}
+
+/// The form of the file name given to an `#include` directive.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IncludeKind {
+    /// `#include "file"`
+    Quoted,
+    /// `#include <file>`, conventionally used for system files.
+    Angled,
+}
+
+/// How identifiers in conditions which aren't defined macros are treated.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UndefinedMode {
+    /// An undefined identifier is an error.
+    Error,
+    /// An undefined identifier is `0`, as it would be to a C preprocessor.
+    Zero,
+    /// A directive containing an undefined identifier is written to the output as it is, to be
+    /// handled by a later pass.
+    Passthrough,
+}
+
+/// How whitespace around the value of a macro defined by a `#define` directive is trimmed, set by
+/// `Context::define_trim`. The whitespace separating the value from the macro's name is never
+/// part of the value.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TrimPolicy {
+    /// The value is kept as it is.
+    None,
+    /// Whitespace at the end of the value is removed.
+    Trailing,
+    /// Whitespace at both ends of the value is removed.
+    Both,
+}
+
+impl TrimPolicy {
+    fn apply(self, value: &str) -> &str {
+        match self {
+            TrimPolicy::None => value,
+            TrimPolicy::Trailing => value.trim_end(),
+            TrimPolicy::Both => value.trim(),
+        }
+    }
+}
+
+/// A summary of preprocessing, made by `process_with_report`. With the `serde` feature, it can be
+/// serialized.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct ProcessReport {
+    /// The number of lines of input, not counting included files.
+    pub lines_in: u32,
+    /// The number of lines written to the output.
+    pub lines_out: usize,
+    /// The result of each `#if` and `#elif` condition which was evaluated, by line number.
+    pub conditions: BTreeMap<u32, bool>,
+    /// The names of the macros which were expanded.
+    pub macros_used: BTreeSet<String>,
+    /// The files loaded by `#include` directives, in the order they were first included.
+    pub files_included: Vec<PathBuf>,
+}
+
+/// Statistics about the conditional blocks in some input, found by `analyze`. With the `serde`
+/// feature, it can be serialized.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct Analysis {
+    /// The number of `#if` directives, including `#ifdef` and `#ifndef`.
+    pub ifs: usize,
+    /// The number of `#elif` directives, including `#elifdef` and `#elifndef`.
+    pub elifs: usize,
+    /// The number of `#else` directives.
+    pub elses: usize,
+    /// The number of `#endif` directives.
+    pub endifs: usize,
+    /// The greatest number of conditional blocks open at once.
+    pub max_depth: usize,
+    /// The distinct names used in the conditions of these directives.
+    pub macros: BTreeSet<String>,
+}
+
+/// Where a directive is in the input, found by `process_with_spans`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DirectiveSpan {
+    /// The line number of the directive.
+    pub line: u32,
+    /// The canonical name of the directive, such as `#if`.
+    pub name: String,
+    /// The byte offset of the start of the directive, after any indentation.
+    pub start: usize,
+    /// The byte offset of the end of the directive, before any trailing whitespace or newline.
+    pub end: usize,
+}
+
+/// A conditional block in some input, from its `#if` to its `#endif`, found by
+/// `parse_structure`. With the `serde` feature, it can be serialized.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct ConditionalBlock {
+    /// The line number of the `#if` directive.
+    pub start: u32,
+    /// The line number of the `#endif` directive.
+    pub end: u32,
+    /// The branches of the block, starting with the `#if` branch.
+    pub branches: Vec<ConditionalBranch>,
+}
+
+/// A branch of a conditional block, found by `parse_structure`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct ConditionalBranch {
+    /// The name of the directive starting the branch, such as `#if`, `#elifdef` or `#else`.
+    pub directive: String,
+    /// The condition of the directive as it's written, without any comment, or `None` if it
+    /// has none.
+    pub condition: Option<String>,
+    /// The line number of the directive.
+    pub line: u32,
+    /// The conditional blocks within the branch.
+    pub blocks: Vec<ConditionalBlock>,
+}
+
+/// A line of the difference between two outputs, found by `process_diff`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DiffLine {
+    /// A line in both outputs.
+    Unchanged(String),
+    /// A line only in the first output.
+    Removed(String),
+    /// A line only in the second output.
+    Added(String),
+}
+
+// Handlers are unwind safe, so that a context is too
+type CharPredicate = dyn Fn(char) -> bool + Send + Sync + RefUnwindSafe;
+
+type IncludeResolver = dyn Fn(&str, IncludeKind) -> io::Result<Option<(PathBuf, String)>>
+    + Send
+    + Sync
+    + RefUnwindSafe;
+
+// A shared handler, so that a context can still be cloned and debug-printed
+struct Shared<F: ?Sized>(Arc<F>);
+
+impl<F: ?Sized> Clone for Shared<F> {
+    fn clone(&self) -> Self {
+        Shared(self.0.clone())
+    }
+}
+
+impl<F: ?Sized> fmt::Debug for Shared<F> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("<shared>")
+    }
 }
 
 /// Errors returned from preprocessing.
@@ -69,28 +582,50 @@ pub enum Error {
     /// An error caused by malformed preprocessor syntax, with a line showing where the error
     /// occurred and a string explaining the error further.
     Syntax { line: u32, msg: &'static str },
+    /// An identifier in a condition which isn't a defined macro, with the line it's on and the
+    /// identifier itself.
+    UndefinedIdentifier { line: u32, name: String },
+    /// An error raised by a directive in the input, such as a failed `#assert`, with the line it's
+    /// on and a message describing it.
+    Directive { line: u32, msg: String },
+    /// Processing was stopped by setting the cancellation flag given to `process_cancellable`.
+    Cancelled,
+}
+
+impl Error {
+    // Whether the error is in the input, so that processing can carry on past it
+    fn is_recoverable(&self) -> bool {
+        match *self {
+            Error::Syntax { .. } | Error::UndefinedIdentifier { .. } | Error::Directive { .. } => {
+                true
+            }
+            Error::Io(_) | Error::Cancelled => false,
+        }
+    }
 }
 
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
-            &Error::Io(ref e) => e.fmt(f),
-            &Error::Syntax { msg, line } => write!(f, "{} on line {}", msg, line),
+            Error::Io(e) => e.fmt(f),
+            Error::Syntax { msg, line } => write!(f, "{} on line {}", msg, line),
+            Error::Directive { msg, line } => write!(f, "{} on line {}", msg, line),
+            Error::UndefinedIdentifier { line, name } => {
+                write!(f, "Undefined identifier '{}' on line {}", name, line)
+            }
+            Error::Cancelled => f.write_str("Processing was cancelled"),
         }
     }
 }
 
 impl error::Error for Error {
-    fn description(&self) -> &str {
-        match self {
-            &Error::Io(ref e) => e.description(),
-            &Error::Syntax { msg, .. } => msg,
-        }
-    }
-    fn cause(&self) -> Option<&dyn error::Error> {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
         match self {
-            &Error::Io(ref e) => Some(e),
-            &Error::Syntax { .. } => None,
+            Error::Io(e) => Some(e),
+            Error::Syntax { .. }
+            | Error::UndefinedIdentifier { .. }
+            | Error::Directive { .. }
+            | Error::Cancelled => None,
         }
     }
 }
@@ -101,11 +636,74 @@ impl From<io::Error> for Error {
     }
 }
 
+impl Default for Context {
+    fn default() -> Self {
+        Context::new()
+    }
+}
+
+/// Creates a context from lines of the form `NAME=VALUE`, as with `Context::define_from_str`.
+///
+/// # Example
+///
+/// ```
+/// use std::convert::TryFrom;
+///
+/// let context = minipre::Context::try_from("FOO=1\nBAR=2").unwrap();
+/// assert_eq!(context.get_macro("BAR").unwrap(), "2");
+/// ```
+impl<'a> TryFrom<&'a str> for Context {
+    type Error = Error;
+
+    fn try_from(text: &'a str) -> Result<Self, Error> {
+        let mut context = Context::new();
+        context.define_from_str(text)?;
+        Ok(context)
+    }
+}
+
 impl Context {
     /// Creates a new, empty context with no macros defined.
     pub fn new() -> Self {
         Context {
-            defs: BTreeMap::new(),
+            defs: Arc::default(),
+            trim_trailing_newline: false,
+            unicode_identifiers: false,
+            dedent_conditional_blocks: false,
+            undefined_mode: UndefinedMode::Error,
+            validate_inactive_branches: false,
+            no_directive_injection: false,
+            identifier_boundaries: false,
+            list_delimiter: ',',
+            bare_word_comparisons: false,
+            annotate_directives: false,
+            output_newline: Newline::Preserve,
+            expand_directive_comments: false,
+            recognized_directives: None,
+            define_comments: true,
+            define_trim: TrimPolicy::Trailing,
+            interpret_escapes_in_defines: false,
+            require_switch_default: false,
+            max_expansions_per_line: None,
+            max_output_size: None,
+            block_scoped_defines: false,
+            forbidden_names: Arc::default(),
+            case_insensitive_string_compare: false,
+            cpp_compat: false,
+            leading_comment_prefix: None,
+            strip_bom: true,
+            markers: None,
+            patterns: Arc::default(),
+            expand_content: true,
+            directive_names: DirectiveNames::default(),
+            file_name: String::new(),
+            include_resolver: None,
+            identifier_chars: None,
+            trace: None,
+            used_macros: None,
+            undefined_names: None,
+            frozen: false,
+            regex: None,
         }
     }
     /// Defines a macro within a context. As this function returns &mut Self, it can be chained
@@ -116,101 +714,2206 @@ impl Context {
     /// ```
     /// assert_eq!(minipre::Context::new().define("foo", "bar").define("quaz", "quux").get_macro("foo").unwrap(), "bar");
     /// ```
+    ///
+    /// The name of a built-in macro, such as `__LINE__`, or a name which has been forbidden with
+    /// `forbid_names`, is ignored, leaving the context as it was, while `try_define` fails
+    /// instead.
     pub fn define<N: Into<String>, V: Into<String>>(&mut self, name: N, value: V) -> &mut Self {
-        self.defs.insert(name.into(), value.into());
+        self.insert(name.into(), Def::Text(value.into()), None)
+    }
+    /// Defines a macro like `define`, but fails rather than ignoring the name if it can't be
+    /// defined.
+    ///
+    /// # Errors
+    ///
+    /// Fails with a syntax error on line 0 if the name is that of a built-in macro, such as
+    /// `__LINE__`, or has been forbidden with `forbid_names`.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// let mut context = minipre::Context::new();
+    /// context.forbid_names(&["if"]);
+    /// assert!(context.try_define("if", "1").is_err());
+    /// assert_eq!(context.try_define("FOO", "1").unwrap().get_macro("FOO").unwrap(), "1");
+    /// ```
+    pub fn try_define<N: Into<String>, V: Into<String>>(
+        &mut self,
+        name: N,
+        value: V,
+    ) -> Result<&mut Self, Error> {
+        self.try_insert(name.into(), Def::Text(value.into()), None)
+    }
+    /// Defines a macro like `define`, recording a description of where the definition came from,
+    /// such as a file name and line, which can be retrieved with `get_macro_source`.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// let mut context = minipre::Context::new();
+    /// context.define_with_source("FOO", "1", "config.h:3");
+    /// assert_eq!(context.get_macro("FOO").unwrap(), "1");
+    /// assert_eq!(context.get_macro_source("FOO"), Some("config.h:3"));
+    /// ```
+    ///
+    /// As with `define`, a built-in or forbidden name is ignored.
+    pub fn define_with_source<N: Into<String>, V: Into<String>, S: Into<String>>(
+        &mut self,
+        name: N,
+        value: V,
+        source: S,
+    ) -> &mut Self {
+        self.insert(name.into(), Def::Text(value.into()), Some(source.into()))
+    }
+    // Whether a macro may be defined with a name, which isn't built in or forbidden
+    fn can_define(&self, name: &str) -> bool {
+        !BUILTIN_MACROS.contains(&name) && !self.forbidden_names.iter().any(|n| n == name)
+    }
+    // Defines a macro, unless its name can't be defined
+    fn insert(&mut self, name: String, def: Def, source: Option<String>) -> &mut Self {
+        if self.can_define(&name) {
+            Arc::make_mut(&mut self.defs).insert(name, Macro { def, source });
+            self.regex = None;
+        }
         self
     }
-    /// Gets a macro that may or may not be defined from a context.
-    pub fn get_macro<N: Into<String>>(&self, name: N) -> Option<&String> {
-        self.defs.get(&name.into())
+    fn try_insert(
+        &mut self,
+        name: String,
+        def: Def,
+        source: Option<String>,
+    ) -> Result<&mut Self, Error> {
+        if BUILTIN_MACROS.contains(&name.as_str()) {
+            return Err(Error::Syntax {
+                line: 0,
+                msg: "Cannot redefine built-in macro",
+            });
+        }
+        if self.forbidden_names.contains(&name) {
+            return Err(Error::Syntax {
+                line: 0,
+                msg: "Cannot define forbidden macro name",
+            });
+        }
+        Ok(self.insert(name, def, source))
     }
-    fn build_regex(&self) -> Regex {
-        if self.defs.is_empty() {
-            Regex::new("$_").expect("Regex should be valid")
-        } else {
-            let pat: String = self
-                .defs
-                .keys()
-                .flat_map(|k| vec!["|", &k])
-                .skip(1)
-                .collect();
-            Regex::new(&format!("\\b(?:{})\\b", pat)).expect("Regex should be valid")
+    /// Defines a function-like macro, which takes arguments. Wherever the name of the macro is
+    /// followed by a parenthesised list of arguments, the whole is replaced by the body of the
+    /// macro, with each parameter replaced by the corresponding argument. The name alone is left
+    /// as it is.
+    ///
+    /// Arguments are separated by commas, except within nested parentheses and string or
+    /// character literals, so `F((a, b), "c, d")` has two arguments. This is equivalent
+    /// to `#define NAME(PARAMS...) BODY`. As the macro has no fixed value, `get_macro` returns
+    /// `None` for it.
+    ///
+    /// If the last parameter is `...`, the macro is variadic: it takes any number of arguments
+    /// after the others, and `__VA_ARGS__` in the body is replaced by them, separated by `, `.
+    /// As an extension, `, ## __VA_ARGS__` drops the comma when there are none.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// let mut context = minipre::Context::new();
+    /// context.define_fn("SQUARE", vec!["x".to_string()], "((x)*(x))");
+    /// assert_eq!(minipre::process_str("SQUARE(4) SQUARE", &mut context).unwrap(), "((4)*(4)) SQUARE");
+    ///
+    /// context.define_fn("LOG", vec!["fmt".to_string(), "...".to_string()], "printf(fmt, ##__VA_ARGS__)");
+    /// assert_eq!(minipre::process_str("LOG(\"%d\", 1)\nLOG(\"\")", &mut context).unwrap(), "printf(\"%d\",1)\nprintf(\"\")");
+    /// ```
+    ///
+    /// As with `define`, a built-in or forbidden name is ignored. Use `try_define_fn` to get an
+    /// error instead.
+    pub fn define_fn<N: Into<String>, B: Into<String>>(
+        &mut self,
+        name: N,
+        params: Vec<String>,
+        body: B,
+    ) -> &mut Self {
+        let body = body.into();
+        self.insert(name.into(), Def::Function { params, body }, None)
+    }
+    /// Defines a function-like macro like `define_fn`, but fails rather than ignoring the name if it
+    /// can't be defined.
+    ///
+    /// # Errors
+    ///
+    /// Fails with a syntax error on line 0 if the name is that of a built-in macro, such as
+    /// `__LINE__`, or has been forbidden with `forbid_names`.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// let mut context = minipre::Context::new();
+    /// context.forbid_names(&["for"]);
+    /// assert!(context.try_define_fn("for", vec!["x".to_string()], "x").is_err());
+    /// ```
+    pub fn try_define_fn<N: Into<String>, B: Into<String>>(
+        &mut self,
+        name: N,
+        params: Vec<String>,
+        body: B,
+    ) -> Result<&mut Self, Error> {
+        let body = body.into();
+        self.try_insert(name.into(), Def::Function { params, body }, None)
+    }
+    /// Defines a macro which expands to the value of an environment variable, or to nothing if
+    /// the variable isn't set. The variable is read each time the macro is expanded, so changes
+    /// made to the environment after the macro is defined are seen.
+    ///
+    /// As the macro has no fixed value, `get_macro` returns `None` for it.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// std::env::set_var("MINIPRE_EXAMPLE_USER", "someone");
+    /// let mut context = minipre::Context::new();
+    /// context.define_env_passthrough("USER", "MINIPRE_EXAMPLE_USER");
+    /// assert_eq!(minipre::process_str("Hello USER", &mut context).unwrap(), "Hello someone");
+    /// ```
+    ///
+    /// As with `define`, a built-in or forbidden name is ignored. Use
+    /// `try_define_env_passthrough` to get an error instead.
+    pub fn define_env_passthrough<N: Into<String>, V: Into<String>>(
+        &mut self,
+        name: N,
+        env_var: V,
+    ) -> &mut Self {
+        self.insert(name.into(), Def::Env(env_var.into()), None)
+    }
+    /// Defines a macro like `define_env_passthrough`, but fails rather than ignoring the
+    /// name if it can't be defined.
+    ///
+    /// # Errors
+    ///
+    /// Fails with a syntax error on line 0 if the name is that of a built-in macro, such as
+    /// `__LINE__`, or has been forbidden with `forbid_names`.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// let mut context = minipre::Context::new();
+    /// context.forbid_names(&["USER"]);
+    /// assert!(context.try_define_env_passthrough("USER", "MINIPRE_EXAMPLE_USER").is_err());
+    /// ```
+    pub fn try_define_env_passthrough<N: Into<String>, V: Into<String>>(
+        &mut self,
+        name: N,
+        env_var: V,
+    ) -> Result<&mut Self, Error> {
+        self.try_insert(name.into(), Def::Env(env_var.into()), None)
+    }
+    /// Defines a macro matching a regular expression rather than a single name. Each match in
+    /// content is replaced by `template`, where `$1` or `${name}` stands for a capture group, as
+    /// in `Regex::replace`.
+    ///
+    /// Pattern macros are applied after other macros have been expanded, in the order they were
+    /// defined, and the text they produce isn't expanded again. They don't apply within
+    /// directives.
+    ///
+    /// # Errors
+    ///
+    /// Fails if `pattern` isn't a valid regular expression.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// let mut context = minipre::Context::new();
+    /// context.define_pattern(r"\bLOG_(\w+)", "log_$1()").unwrap();
+    /// assert_eq!(minipre::process_str("LOG_INFO;\n", &mut context).unwrap(), "log_INFO();\n");
+    /// ```
+    pub fn define_pattern<T: Into<String>>(
+        &mut self,
+        pattern: &str,
+        template: T,
+    ) -> Result<&mut Self, regex::Error> {
+        let pattern = Regex::new(pattern)?;
+        Arc::make_mut(&mut self.patterns).push((pattern, template.into()));
+        Ok(self)
+    }
+    /// Defines every macro from an iterator of name and value pairs. Like `define`, this can be
+    /// chained.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// let defs = vec![("FOO", "1"), ("BAR", "2"), ("BAZ", "3")];
+    /// let mut context = minipre::Context::new();
+    /// context.define_all(defs).define("QUUX", "4");
+    /// assert_eq!(context.get_macro("FOO").unwrap(), "1");
+    /// assert_eq!(context.get_macro("BAR").unwrap(), "2");
+    /// assert_eq!(context.get_macro("BAZ").unwrap(), "3");
+    /// ```
+    pub fn define_all<I, N, V>(&mut self, defs: I) -> &mut Self
+    where
+        I: IntoIterator<Item = (N, V)>,
+        N: Into<String>,
+        V: Into<String>,
+    {
+        for (name, value) in defs {
+            self.define(name, value);
         }
+        self
     }
-    fn replacer<'a>(&'a self) -> impl Replacer + 'a {
-        move |captures: &Captures| {
-            self.defs
-                .get(captures.get(0).expect("At least one capture").as_str())
-                .expect("Found def for match")
-                .clone()
+    /// Defines macros from lines of the form `NAME=VALUE`. A line with only a name defines the
+    /// macro as empty, and blank lines are ignored.
+    ///
+    /// # Errors
+    ///
+    /// Fails with a syntax error if a name isn't a valid identifier, is that of a built-in macro,
+    /// or has been forbidden with `forbid_names`. Macros on earlier lines are still defined.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// let mut context = minipre::Context::new();
+    /// context.define_from_str("FOO=1\nBAR = two words\n\nEMPTY").unwrap();
+    /// assert_eq!(context.get_macro("BAR").unwrap(), "two words");
+    /// assert_eq!(context.get_macro("EMPTY").unwrap(), "");
+    /// assert!(context.define_from_str("1=2").is_err());
+    /// ```
+    pub fn define_from_str(&mut self, text: &str) -> Result<&mut Self, Error> {
+        for (index, def) in text.lines().enumerate() {
+            if def.trim().is_empty() {
+                continue;
+            }
+            let (name, value) = match def.find('=') {
+                Some(index) => (&def[..index], &def[index + 1..]),
+                None => (def, ""),
+            };
+            let name = name.trim();
+            self.check_macro_name(name, index as u32 + 1, false)?;
+            self.define(name, value.trim());
         }
+        Ok(self)
     }
-    fn skip_whitespace(&self, expr: &mut &str) {
-        *expr = expr.trim_start();
+    /// Removes a macro from a context, if it was defined.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// assert!(minipre::Context::new().define("foo", "bar").undefine("foo").get_macro("foo").is_none());
+    /// ```
+    pub fn undefine<N: AsRef<str>>(&mut self, name: N) -> &mut Self {
+        // The macros are only copied if they're shared and one of them is removed
+        if self.defs.contains_key(name.as_ref()) {
+            Arc::make_mut(&mut self.defs).remove(name.as_ref());
+            self.regex = None;
+        }
+        self
     }
-    fn eval_term(&self, expr: &mut &str, line: u32) -> Result<bool, Error> {
-        self.skip_whitespace(expr);
-
-        let index = expr
-            .find(|c: char| !c.is_ascii_alphanumeric() && c != '_')
-            .unwrap_or(expr.len());
-        let term = &expr[0..index];
-        *expr = &expr[index..];
-
-        if term
-            .chars()
-            .next()
-            .ok_or_else(|| Error::Syntax {
-                line,
-                msg: "Expected term, found nothing",
-            })?
-            .is_digit(10)
-        {
-            Ok(term == "1")
-        } else {
-            Err(Error::Syntax {
-                line,
-                msg: "Undefined identifier",
-            })
+    /// Moves a macro's definition from one name to another, replacing any existing macro with
+    /// the new name. Returns `Err(())`, leaving the macros as they are, if no macro with the old
+    /// name is defined, or if the new name is that of a built-in macro or has been forbidden with
+    /// `forbid_names`.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// let mut context = minipre::Context::new();
+    /// context.define("foo", "bar").rename("foo", "baz").unwrap();
+    /// assert!(context.get_macro("foo").is_none());
+    /// assert_eq!(context.get_macro("baz").unwrap(), "bar");
+    /// assert!(context.rename("foo", "qux").is_err());
+    /// assert!(context.forbid_names(&["if"]).rename("baz", "if").is_err());
+    /// ```
+    #[allow(clippy::result_unit_err)]
+    pub fn rename<O: AsRef<str>, N: Into<String>>(
+        &mut self,
+        old: O,
+        new: N,
+    ) -> Result<&mut Self, ()> {
+        let new = new.into();
+        if !self.can_define(&new) {
+            return Err(());
+        }
+        if !self.defs.contains_key(old.as_ref()) {
+            return Err(());
         }
+        let defs = Arc::make_mut(&mut self.defs);
+        let def = defs.remove(old.as_ref()).expect("The macro is defined");
+        defs.insert(new, def);
+        self.regex = None;
+        Ok(self)
     }
-    fn eval_unary(&self, expr: &mut &str, line: u32) -> Result<bool, Error> {
-        let mut negate = false;
-        self.skip_whitespace(expr);
-        while expr.starts_with("!") {
-            *expr = &expr[1..];
-            negate = !negate;
-            self.skip_whitespace(expr);
+    /// Saves the macros currently defined, so that they can be restored later. Unlike cloning the
+    /// context, this doesn't copy its options or handlers.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// let mut context = minipre::Context::new();
+    /// let snapshot = context.define("FOO", "1").snapshot();
+    /// context.define("FOO", "2").define("BAR", "3");
+    /// context.restore(snapshot);
+    /// assert_eq!(context.get_macro("FOO").unwrap(), "1");
+    /// assert!(context.get_macro("BAR").is_none());
+    /// ```
+    pub fn snapshot(&self) -> ContextSnapshot {
+        ContextSnapshot {
+            defs: self.defs.clone(),
         }
-
-        Ok(negate ^ self.eval_term(expr, line)?)
     }
-    fn eval_eq(&self, expr: &mut &str, line: u32) -> Result<bool, Error> {
-        let mut result = self.eval_unary(expr, line)?;
-        self.skip_whitespace(expr);
-        while expr.starts_with("==") {
-            *expr = &expr[2..];
-            result ^= !self.eval_unary(expr, line)?;
-            self.skip_whitespace(expr);
+    /// Replaces the macros defined in a context with those saved by `snapshot`.
+    pub fn restore(&mut self, snapshot: ContextSnapshot) -> &mut Self {
+        self.defs = snapshot.defs;
+        self.regex = None;
+        self
+    }
+    /// Makes a copy of a context which can't be changed, and which can be shared between threads
+    /// to process text with `process_frozen`. The copy is prepared for expanding macros in
+    /// advance, rather than each time it's used.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// let frozen = minipre::Context::new().define("FOO", "1").freeze();
+    /// assert_eq!(frozen.context().get_macro("FOO").unwrap(), "1");
+    /// ```
+    pub fn freeze(&self) -> FrozenContext {
+        let mut context = self.clone();
+        if context.regex.is_none() {
+            context.regex = Some(context.build_regex());
         }
-        Ok(result)
+        context.frozen = true;
+        FrozenContext { context }
     }
-    fn evaluate(&self, mut expr: &str, line: u32) -> Result<bool, Error> {
-        let result = self.eval_eq(&mut expr, line)?;
-        self.skip_whitespace(&mut expr);
-        if !expr.is_empty() {
-            return Err(Error::Syntax {
-                line,
-                msg: "Expected end-of-line",
-            });
+    /// Gets a macro that may or may not be defined from a context.
+    ///
+    /// The name is borrowed rather than converted into a `String`, so lookups don't allocate.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// let mut context = minipre::Context::new();
+    /// context.define("foo", "bar");
+    /// assert_eq!(context.get_macro("foo").unwrap(), "bar");
+    /// assert_eq!(context.get_macro(String::from("foo")).unwrap(), "bar");
+    /// assert!(context.get_macro("quaz").is_none());
+    /// ```
+    pub fn get_macro<N: AsRef<str>>(&self, name: N) -> Option<&String> {
+        match self.defs.get(name.as_ref()) {
+            Some(Macro {
+                def: Def::Text(value),
+                ..
+            }) => Some(value),
+            _ => None,
         }
-        Ok(result)
     }
-}
-
-#[derive(Eq, PartialEq, Debug, Copy, Clone)]
-enum State {
-    // A condition already matched, skip remaining clauses
+    /// Gets the value of a macro, first defining it as the result of `f` if it doesn't have a
+    /// fixed value, as with `get_macro`. A function-like macro of the same name is replaced.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// let mut context = minipre::Context::new();
+    /// context.define("FOO", "1");
+    /// assert_eq!(context.get_or_define("FOO", || "2".into()), "1");
+    /// assert_eq!(context.get_or_define("BAR", || "3".into()), "3");
+    /// assert_eq!(context.get_macro("BAR").unwrap(), "3");
+    /// ```
+    ///
+    /// If the macro has to be defined but its name is that of a built-in macro, or has been
+    /// forbidden with `forbid_names`, the result of `f` is returned without being defined.
+    pub fn get_or_define<N: Into<String>, F: FnOnce() -> String>(
+        &mut self,
+        name: N,
+        f: F,
+    ) -> Cow<'_, str> {
+        let name = name.into();
+        if self.get_macro(&name).is_none() {
+            let value = f();
+            if !self.can_define(&name) {
+                return Cow::Owned(value);
+            }
+            self.insert(name.clone(), Def::Text(value), None);
+        }
+        Cow::Borrowed(self.get_macro(name).expect("The macro was just defined"))
+    }
+    /// Gets a map from the name of each macro with a fixed value, as with `get_macro`, to its
+    /// value, allowing range queries and other map operations. The map is allocated each time
+    /// this is called, although its names and values borrow from the context, and it leaves out
+    /// function-like macros and those passed through from the environment.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// let mut context = minipre::Context::new();
+    /// context.define("HAVE_A", "1").define("HAVE_B", "0").define("OTHER", "2");
+    ///
+    /// let defs = context.defs();
+    /// let have: Vec<_> = defs.range("HAVE_"..="HAVE_~").map(|(name, _)| *name).collect();
+    /// assert_eq!(have, ["HAVE_A", "HAVE_B"]);
+    /// for (name, value) in &defs {
+    ///     assert_eq!(context.get_macro(name).unwrap(), value);
+    /// }
+    ///
+    /// context.define_fn("F", vec![], "1");
+    /// assert!(!context.defs().contains_key("F"));
+    /// ```
+    pub fn defs(&self) -> BTreeMap<&str, &str> {
+        self.defs
+            .iter()
+            .filter_map(|(name, m)| match m.def {
+                Def::Text(ref value) => Some((name.as_str(), value.as_str())),
+                _ => None,
+            })
+            .collect()
+    }
+    /// Gets the name and value of each macro with a fixed value, as with `get_macro`, which
+    /// isn't defined at all in `other`, in order of name.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// let mut release = minipre::Context::new();
+    /// release.define("NDEBUG", "1").define("VERSION", "2");
+    /// let mut debug = minipre::Context::new();
+    /// debug.define("VERSION", "3").define("TRACE", "1");
+    ///
+    /// assert_eq!(release.difference(&debug).collect::<Vec<_>>(), [("NDEBUG", "1")]);
+    /// ```
+    pub fn difference<'a>(
+        &'a self,
+        other: &'a Context,
+    ) -> impl Iterator<Item = (&'a str, &'a str)> {
+        self.defs
+            .iter()
+            .filter(move |&(name, _)| !other.defs.contains_key(name))
+            .filter_map(|(name, m)| match m.def {
+                Def::Text(ref value) => Some((name.as_str(), value.as_str())),
+                _ => None,
+            })
+    }
+    /// Gets the description of where a macro was defined, if it was defined with
+    /// `define_with_source`. Redefining the macro in any other way removes the description.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// let mut context = minipre::Context::new();
+    /// context.define_with_source("FOO", "1", "command line").define("BAR", "2");
+    /// assert_eq!(context.get_macro_source("FOO"), Some("command line"));
+    /// assert_eq!(context.get_macro_source("BAR"), None);
+    /// ```
+    pub fn get_macro_source<N: AsRef<str>>(&self, name: N) -> Option<&str> {
+        self.defs
+            .get(name.as_ref())
+            .and_then(|m| m.source.as_deref())
+    }
+    /// Gets the value of a macro, or `default` if it isn't defined, or has no fixed value as
+    /// with `get_macro`.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// let mut context = minipre::Context::new();
+    /// context.define("foo", "bar");
+    /// assert_eq!(context.get_macro_or("foo", "none"), "bar");
+    /// assert_eq!(context.get_macro_or("quaz", "none"), "none");
+    /// ```
+    pub fn get_macro_or<'a>(&'a self, name: &str, default: &'a str) -> &'a str {
+        self.get_macro(name).map_or(default, String::as_str)
+    }
+    /// Gets the value of a macro with every macro within it expanded in turn, as in a directive's
+    /// expression. A macro isn't expanded again within its own expansion, so self-referential
+    /// macros are left in place. Returns `None` if the macro isn't defined, or is function-like.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// let mut context = minipre::Context::new();
+    /// context.define("A", "B").define("B", "C");
+    /// assert_eq!(context.expand_macro("A").unwrap(), "C");
+    /// assert_eq!(context.get_macro("A").unwrap(), "B");
+    /// ```
+    pub fn expand_macro(&self, name: &str) -> Option<String> {
+        let (name, m) = self.defs.get_key_value(name)?;
+        match m.def {
+            Def::Function { .. } => None,
+            ref def => Some(self.expand_recursive(&def.value(), &mut vec![name.as_str()])),
+        }
+    }
+    /// Gets the macros defined in a context as `-D` flags for a C preprocessor or compiler, in
+    /// order of name. A flag containing spaces or other characters special to a POSIX shell is
+    /// quoted, and an empty macro gives a flag with only its name.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// let mut context = minipre::Context::new();
+    /// context.define("FOO", "1").define("BAR", "two words").define("EMPTY", "");
+    /// assert_eq!(context.to_cli_flags(), ["'-DBAR=two words'", "-DEMPTY", "-DFOO=1"]);
+    /// ```
+    pub fn to_cli_flags(&self) -> Vec<String> {
+        self.defs
+            .iter()
+            .map(|(name, m)| {
+                let name = match m.def {
+                    Def::Function { ref params, .. } => format!("{}({})", name, params.join(",")),
+                    _ => name.clone(),
+                };
+                let value = m.def.value();
+                let flag = if value.is_empty() {
+                    format!("-D{}", name)
+                } else {
+                    format!("-D{}={}", name, value)
+                };
+                shell_quote(flag)
+            })
+            .collect()
+    }
+    /// Returns the number of macros defined in a context.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// assert_eq!(minipre::Context::new().len(), 0);
+    /// assert_eq!(minipre::Context::new().define("foo", "1").define("bar", "2").len(), 2);
+    /// ```
+    pub fn len(&self) -> usize {
+        self.defs.len()
+    }
+    /// Returns true if no macros are defined in a context.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// assert!(minipre::Context::new().is_empty());
+    /// assert!(!minipre::Context::new().define("foo", "1").is_empty());
+    /// ```
+    pub fn is_empty(&self) -> bool {
+        self.defs.is_empty()
+    }
+    /// Sets whether the line terminator (`\n` or `\r\n`) at the very end of the output is
+    /// removed. Blank lines elsewhere in the output are unaffected. Defaults to false.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// let mut context = minipre::Context::new();
+    /// context.trim_trailing_newline(true);
+    /// assert_eq!(minipre::process_str("foo\n\nbar\n", &mut context).unwrap(), "foo\n\nbar");
+    /// ```
+    pub fn trim_trailing_newline(&mut self, trim: bool) -> &mut Self {
+        self.trim_trailing_newline = trim;
+        self
+    }
+    /// Sets whether identifiers in directives may contain any Unicode letters and digits, rather
+    /// than only ASCII ones. Defaults to false.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// let mut context = minipre::Context::new();
+    /// context.unicode_identifiers(true).define("café", "1");
+    /// assert_eq!(minipre::process_str("#if café\nopen\n#endif\n", &mut context).unwrap(), "open\n");
+    /// ```
+    pub fn unicode_identifiers(&mut self, unicode: bool) -> &mut Self {
+        self.unicode_identifiers = unicode;
+        self
+    }
+    /// Sets which characters may start an identifier, and which may continue one after its first
+    /// character, in place of letters, digits and underscores. Macros in content are then only
+    /// expanded where they aren't next to an identifier character, as with
+    /// `identifier_boundaries`. Clones of the context share the functions.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// let mut context = minipre::Context::new();
+    /// context.identifier_chars(
+    ///     |c| c == '$' || c == '_' || c.is_ascii_alphabetic(),
+    ///     |c| c == '_' || c.is_ascii_alphanumeric(),
+    /// );
+    /// let text = "#define $count 2\n#if $count == 2\n$count items\n#endif\n";
+    /// assert_eq!(minipre::process_str(text, &mut context).unwrap(), "2 items\n");
+    /// ```
+    pub fn identifier_chars<S, C>(&mut self, start: S, cont: C) -> &mut Self
+    where
+        S: Fn(char) -> bool + Send + Sync + RefUnwindSafe + 'static,
+        C: Fn(char) -> bool + Send + Sync + RefUnwindSafe + 'static,
+    {
+        self.identifier_chars = Some((Shared(Arc::new(start)), Shared(Arc::new(cont))));
+        self.regex = None;
+        self
+    }
+    /// Sets whether content within a conditional block is dedented to the level of the block's
+    /// directive. The indentation removed is whatever the first non-blank line of each branch
+    /// has beyond the directive's own indentation, and is only removed from lines which start
+    /// with exactly that whitespace. Defaults to false.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// let mut context = minipre::Context::new();
+    /// context.dedent_conditional_blocks(true);
+    /// assert_eq!(minipre::process_str("
+    ///     #if 1
+    ///         foo
+    ///           bar
+    ///     #endif", &mut context).unwrap(), "
+    ///     foo
+    ///       bar
+    /// ");
+    /// ```
+    pub fn dedent_conditional_blocks(&mut self, dedent: bool) -> &mut Self {
+        self.dedent_conditional_blocks = dedent;
+        self
+    }
+    /// Sets whether identifiers in conditions which aren't defined macros are treated as `0`,
+    /// as a C preprocessor would, rather than being an error. Defaults to false. This is a
+    /// shorthand for `undefined_mode` with `UndefinedMode::Zero` or `UndefinedMode::Error`.
+    ///
+    /// An undefined identifier is then indistinguishable from a macro defined as `0`: `FOO == 0`
+    /// and `!FOO` are true when `FOO` is undefined, while `FOO == 1` (or a comparison with any
+    /// other non-zero value) is false. Use `defined(FOO)` to tell the two apart.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// let mut context = minipre::Context::new();
+    /// context.treat_undefined_as_zero(true);
+    /// assert_eq!(minipre::process_str("#if FOO == 0\nzero\n#endif\n", &mut context).unwrap(), "zero\n");
+    /// ```
+    pub fn treat_undefined_as_zero(&mut self, zero: bool) -> &mut Self {
+        self.undefined_mode(if zero {
+            UndefinedMode::Zero
+        } else {
+            UndefinedMode::Error
+        })
+    }
+    /// Sets how identifiers in conditions which aren't defined macros are treated. Defaults to
+    /// `UndefinedMode::Error`.
+    ///
+    /// With `UndefinedMode::Passthrough`, an `#if` whose condition uses an undefined identifier
+    /// is written out as it is, along with all of its branches and their `#elif`, `#else` and
+    /// `#endif` directives. Directives within those branches are still processed. An `#elif`
+    /// which is reached this way, after the earlier branches weren't taken, is written out as an
+    /// `#if`, as the earlier branches are left out. A `#set` directive using an undefined
+    /// identifier is also written out as it is.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// let mut context = minipre::Context::new();
+    /// context.undefined_mode(minipre::UndefinedMode::Passthrough).define("FOO", "1");
+    /// assert_eq!(
+    ///     minipre::process_str("#if FOO && BAR\nbar\n#else\nbaz\n#endif\n", &mut context).unwrap(),
+    ///     "#if FOO && BAR\nbar\n#else\nbaz\n#endif\n",
+    /// );
+    /// ```
+    pub fn undefined_mode(&mut self, mode: UndefinedMode) -> &mut Self {
+        self.undefined_mode = mode;
+        self
+    }
+    /// Sets whether the conditions of `#if` and `#elif` directives which aren't evaluated,
+    /// because they're in a branch which isn't taken, are still checked for syntax errors.
+    /// Undefined identifiers are not an error in such conditions. Defaults to false.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// let mut context = minipre::Context::new();
+    /// assert!(minipre::process_str("#if 1\n#elif (1\n#endif\n", &mut context).is_ok());
+    /// context.validate_inactive_branches(true);
+    /// assert!(minipre::process_str("#if 1\n#elif (1\n#endif\n", &mut context).is_err());
+    /// assert!(minipre::process_str("#if 1\n#elif UNDEFINED\n#endif\n", &mut context).is_ok());
+    /// ```
+    pub fn validate_inactive_branches(&mut self, validate: bool) -> &mut Self {
+        self.validate_inactive_branches = validate;
+        self
+    }
+    /// Sets whether a line which only starts with `#` once its macros have been expanded is
+    /// emitted as text, rather than being treated as a directive. Defaults to false.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// let mut context = minipre::Context::new();
+    /// context.define("GUARD", "#endif");
+    /// assert!(minipre::process_str("GUARD\n", &mut context).is_err());
+    /// context.no_directive_injection(true);
+    /// assert_eq!(minipre::process_str("GUARD\n", &mut context).unwrap(), "#endif\n");
+    /// ```
+    pub fn no_directive_injection(&mut self, no_injection: bool) -> &mut Self {
+        self.no_directive_injection = no_injection;
+        self
+    }
+    /// Sets whether macros in content are only expanded when they aren't next to an identifier
+    /// character, as decided by `unicode_identifiers`, rather than at regex word boundaries. This
+    /// makes content split into identifiers the same way as conditions. Defaults to false.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// let mut context = minipre::Context::new();
+    /// context.define("FOO", "1");
+    /// assert_eq!(minipre::process_str("éFOO\n", &mut context).unwrap(), "éFOO\n");
+    /// context.identifier_boundaries(true);
+    /// assert_eq!(minipre::process_str("éFOO\n", &mut context).unwrap(), "é1\n");
+    /// ```
+    pub fn identifier_boundaries(&mut self, identifier_boundaries: bool) -> &mut Self {
+        self.identifier_boundaries = identifier_boundaries;
+        self.regex = None;
+        self
+    }
+    /// Sets the character which separates the elements of a list for the `contains` operator.
+    /// Defaults to `,`.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// let mut context = minipre::Context::new();
+    /// context.define("FEATURES", "a b c").list_delimiter(' ');
+    /// assert_eq!(minipre::process_str("#if FEATURES contains b\nb\n#endif\n", &mut context).unwrap(), "b\n");
+    /// ```
+    pub fn list_delimiter(&mut self, delimiter: char) -> &mut Self {
+        self.list_delimiter = delimiter;
+        self
+    }
+    /// Sets whether an identifier which isn't a defined macro is compared as a string when it's
+    /// an operand of `==` or `!=`, and the other operand is a string or another such identifier.
+    /// As macros are expanded first, `MODE == release` is then true when `MODE` is defined as
+    /// `release`. Defaults to false.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// let mut context = minipre::Context::new();
+    /// context.define("MODE", "release").bare_word_comparisons(true);
+    /// assert_eq!(minipre::process_str("#if MODE == release\nfast\n#endif\n", &mut context).unwrap(), "fast\n");
+    /// ```
+    pub fn bare_word_comparisons(&mut self, bare_words: bool) -> &mut Self {
+        self.bare_word_comparisons = bare_words;
+        self
+    }
+    /// Sets whether directives are written to the output as they are, including any comment, so
+    /// that it shows where they were. Directives within branches which aren't taken are left out
+    /// along with them. Defaults to false.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// let mut context = minipre::Context::new();
+    /// context.annotate_directives(true);
+    /// assert_eq!(
+    ///     minipre::process_str("#if 1 // always\nfoo\n#endif\n", &mut context).unwrap(),
+    ///     "#if 1 // always\nfoo\n#endif\n",
+    /// );
+    /// ```
+    pub fn annotate_directives(&mut self, annotate: bool) -> &mut Self {
+        self.annotate_directives = annotate;
+        self
+    }
+    /// Sets whether macros are expanded in the `//` comments of directives which are written to
+    /// the output, by `annotate_directives` or `UndefinedMode::Passthrough`. Otherwise the
+    /// comments are written as they are. Defaults to false.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// let mut context = minipre::Context::new();
+    /// context.annotate_directives(true).define("FOO", "1");
+    /// assert_eq!(minipre::process_str("#if 1 // FOO\n#endif\n", &mut context).unwrap(), "#if 1 // FOO\n#endif\n");
+    /// context.expand_directive_comments(true);
+    /// assert_eq!(minipre::process_str("#if 1 // FOO\n#endif\n", &mut context).unwrap(), "#if 1 // 1\n#endif\n");
+    /// ```
+    pub fn expand_directive_comments(&mut self, expand: bool) -> &mut Self {
+        self.expand_directive_comments = expand;
+        self
+    }
+    /// Sets whether `//` starts a comment in `#define` directives. Otherwise the rest of the line
+    /// is part of the macro's value, so that values such as URLs can be defined. Defaults to true.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// let mut context = minipre::Context::new();
+    /// context.define_comments(false);
+    /// minipre::process_str("#define URL http://example.com\n", &mut context).unwrap();
+    /// assert_eq!(context.get_macro("URL").unwrap(), "http://example.com");
+    /// ```
+    pub fn define_comments(&mut self, comments: bool) -> &mut Self {
+        self.define_comments = comments;
+        self
+    }
+    /// Sets how whitespace around the values of macros defined by `#define` directives is
+    /// trimmed. Defaults to `TrimPolicy::Trailing`, so that indentation at the start of a value is
+    /// kept.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// let mut context = minipre::Context::new();
+    /// context.define_trim(minipre::TrimPolicy::Both);
+    /// minipre::process_str("#define FOO    indented\n", &mut context).unwrap();
+    /// assert_eq!(context.get_macro("FOO").unwrap(), "indented");
+    /// ```
+    pub fn define_trim(&mut self, policy: TrimPolicy) -> &mut Self {
+        self.define_trim = policy;
+        self
+    }
+    /// Sets whether C-style escape sequences such as `\n` are interpreted in the values of macros
+    /// defined by `#define` directives. Otherwise backslashes are kept as they are. Defaults to
+    /// false.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// let mut context = minipre::Context::new();
+    /// context.interpret_escapes_in_defines(true);
+    /// minipre::process_str("#define TAB a\\tb\n", &mut context).unwrap();
+    /// assert_eq!(context.get_macro("TAB").unwrap(), "a\tb");
+    /// ```
+    pub fn interpret_escapes_in_defines(&mut self, interpret: bool) -> &mut Self {
+        self.interpret_escapes_in_defines = interpret;
+        self
+    }
+    // The value of a macro defined by `#define`, with any escape sequences interpreted
+    fn define_value<'t>(&self, text: &'t str, line: u32) -> Result<Cow<'t, str>, Error> {
+        if !self.interpret_escapes_in_defines {
+            return Ok(Cow::Borrowed(text));
+        }
+        let mut value = String::new();
+        let mut chars = text.chars();
+        while let Some(c) = chars.next() {
+            value.push(if c == '\\' {
+                chars.next().and_then(unescape).ok_or(Error::Syntax {
+                    line,
+                    msg: "Invalid escape sequence in macro value",
+                })?
+            } else {
+                c
+            });
+        }
+        Ok(Cow::Owned(value))
+    }
+    /// Sets whether a `#switch` block which is evaluated must take one of its branches. When
+    /// set, a block where no `#case` matches and there's no `#default` fails, rather than
+    /// writing nothing. Defaults to false.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// let mut context = minipre::Context::new();
+    /// context.require_switch_default(true);
+    /// assert!(minipre::process_str("#switch 3\n#case 1\none\n#endswitch\n", &mut context).is_err());
+    /// ```
+    pub fn require_switch_default(&mut self, require: bool) -> &mut Self {
+        self.require_switch_default = require;
+        self
+    }
+    /// Limits the number of macros which may be expanded on a single line, including those within
+    /// the arguments of function-like macros and those expanded while evaluating a condition.
+    /// Lines which need more fail, which bounds the work done for adversarial input. By default
+    /// there is no limit.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// let mut context = minipre::Context::new();
+    /// context.define("A", "a").max_expansions_per_line(2);
+    /// assert_eq!(minipre::process_str("A A\n", &mut context).unwrap(), "a a\n");
+    /// assert!(minipre::process_str("A A A\n", &mut context).is_err());
+    /// ```
+    pub fn max_expansions_per_line(&mut self, max: usize) -> &mut Self {
+        self.max_expansions_per_line = Some(max);
+        self
+    }
+    /// Limits the total number of bytes which may be written to the output. Preprocessing fails
+    /// on the line which would go over the limit, and nothing from that line is written, which
+    /// bounds the output of adversarial input. By default there is no limit.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// let mut context = minipre::Context::new();
+    /// context.max_output_size(8);
+    /// assert_eq!(minipre::process_str("abc\ndef\n", &mut context).unwrap(), "abc\ndef\n");
+    /// assert!(minipre::process_str("abc\ndef\nghi\n", &mut context).is_err());
+    /// ```
+    pub fn max_output_size(&mut self, max: usize) -> &mut Self {
+        self.max_output_size = Some(max);
+        self
+    }
+    /// Sets whether the macros defined, redefined or undefined within an `#if` block, including
+    /// by `#set`, are restored to how they were before it once the block ends with `#endif`, as
+    /// if it were surrounded by `#push_defs` and `#pop_defs`. Defaults to false, where a macro
+    /// defined in a block stays defined after it, as with a C preprocessor.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// let mut context = minipre::Context::new();
+    /// context.block_scoped_defines(true);
+    /// let text = "#if 1\n#define FOO 1\nFOO\n#endif\nFOO\n";
+    /// assert_eq!(minipre::process_str(text, &mut context).unwrap(), "1\nFOO\n");
+    /// ```
+    pub fn block_scoped_defines(&mut self, scoped: bool) -> &mut Self {
+        self.block_scoped_defines = scoped;
+        self
+    }
+    // Counts an expansion of a macro, failing if there have been too many on the line
+    fn count_expansion(&self, name: &str, expansions: &mut usize, line: u32) -> Result<(), Error> {
+        if let Some(ref used) = self.used_macros {
+            let mut used = used.0.lock().unwrap_or_else(|e| e.into_inner());
+            if !used.contains(name) {
+                used.insert(name.into());
+            }
+        }
+        *expansions += 1;
+        if self
+            .max_expansions_per_line
+            .is_some_and(|max| *expansions > max)
+        {
+            return Err(Error::Syntax {
+                line,
+                msg: "Too many expansions on line",
+            });
+        }
+        Ok(())
+    }
+    /// Forbids macros with any of the given names from being defined, such as the keywords of the
+    /// language being preprocessed, which would otherwise be replaced throughout. `#define`,
+    /// and `#set` directives using these names fail, and so do `define_from_str` and the
+    /// `try_define` methods, while `define` and the like ignore them. `#undef` of these names does
+    /// nothing, as they can't be defined, and any macros already defined with these names are
+    /// removed. By default no names are forbidden.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// let mut context = minipre::Context::new();
+    /// context.forbid_names(&["if", "for"]);
+    /// assert!(minipre::process_str("#define if 1\n", &mut context).is_err());
+    /// ```
+    pub fn forbid_names(&mut self, names: &[&str]) -> &mut Self {
+        self.forbidden_names = Arc::new(names.iter().map(|&name| name.into()).collect());
+        for name in names {
+            self.undefine(name);
+        }
+        self
+    }
+    /// Sets whether strings compared with `==` and `!=` in conditions, or with the value of a
+    /// `#switch` by `#case`, ignore case. Ordering comparisons and integers are unaffected.
+    /// Defaults to false.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// let mut context = minipre::Context::new();
+    /// context
+    ///     .define("MODE", "\"release\"")
+    ///     .case_insensitive_string_compare(true);
+    /// let text = "#if MODE == \"Release\"\noptimized\n#endif\n";
+    /// assert_eq!(minipre::process_str(text, &mut context).unwrap(), "optimized\n");
+    /// ```
+    pub fn case_insensitive_string_compare(&mut self, ignore_case: bool) -> &mut Self {
+        self.case_insensitive_string_compare = ignore_case;
+        self
+    }
+    /// Sets whether identifiers in `#if` and `#elif` conditions which aren't defined macros are
+    /// treated as `0`, as a C preprocessor would, when they would otherwise be an error. Macros
+    /// which are empty are `0` there too, so `N + 1` is `1` when `N` is empty, where otherwise
+    /// it's an error. Unlike `treat_undefined_as_zero`, other directives, such as `#set` and
+    /// `#assert`, are unaffected. Defaults to false.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// let mut context = minipre::Context::new();
+    /// context.cpp_compat(true);
+    /// assert_eq!(minipre::process_str("#if FOO\nfoo\n#else\nbar\n#endif\n", &mut context).unwrap(), "bar\n");
+    /// assert!(minipre::process_str("#set X = FOO\n", &mut context).is_err());
+    /// ```
+    pub fn cpp_compat(&mut self, cpp_compat: bool) -> &mut Self {
+        self.cpp_compat = cpp_compat;
+        self
+    }
+    /// Sets a comment token, such as `//`, which may come before a directive, so that directives
+    /// can be written within comments in the host language. A line starting with the token is
+    /// only treated as a directive if one follows the token, and other such lines are content as
+    /// usual. Defaults to `None`.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// let mut context = minipre::Context::new();
+    /// context.leading_comment_prefix(Some("//".into())).define("FOO", "1");
+    /// let text = "// #if FOO\nfoo();\n// #endif\n// a comment\n";
+    /// assert_eq!(minipre::process_str(text, &mut context).unwrap(), "foo();\n// a comment\n");
+    /// ```
+    pub fn leading_comment_prefix(&mut self, prefix: Option<String>) -> &mut Self {
+        self.leading_comment_prefix = prefix;
+        self
+    }
+    // Removes the `leading_comment_prefix` from the start of a line, if a directive follows it
+    fn strip_comment_prefix<'t>(&self, line: &'t str) -> &'t str {
+        match self.leading_comment_prefix {
+            Some(ref prefix) => match line.trim_start().strip_prefix(prefix.as_str()) {
+                Some(rest) if self.directive_names.is_directive(rest) => rest,
+                _ => line,
+            },
+            None => line,
+        }
+    }
+    /// Sets whether a byte order mark at the start of the input is removed from the output. A
+    /// byte order mark never stops the first line being recognized as a directive, and one at the
+    /// start of an included file is always removed. Defaults to true.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// let mut context = minipre::Context::new();
+    /// let text = "\u{feff}#if 1\ntext\n#endif\n";
+    /// assert_eq!(minipre::process_str(text, &mut context).unwrap(), "text\n");
+    /// context.strip_bom(false);
+    /// assert_eq!(minipre::process_str(text, &mut context).unwrap(), "\u{feff}text\n");
+    /// ```
+    pub fn strip_bom(&mut self, strip: bool) -> &mut Self {
+        self.strip_bom = strip;
+        self
+    }
+    /// Sets lines which turn preprocessing on and off, so that it only applies to part of the
+    /// input. Lines before the `on` marker, and after an `off` marker until the next `on` marker,
+    /// are written as they are, without expanding macros or interpreting directives. The marker
+    /// lines themselves aren't written. Included files are preprocessed throughout.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// let mut context = minipre::Context::new();
+    /// context.define("FOO", "1").gated_by_markers("#minipre on", "#minipre off");
+    /// let text = "#pragma once\nFOO\n#minipre on\nFOO\n#minipre off\nFOO\n";
+    /// assert_eq!(minipre::process_str(text, &mut context).unwrap(), "#pragma once\nFOO\n1\nFOO\n");
+    /// ```
+    pub fn gated_by_markers(&mut self, on: &str, off: &str) -> &mut Self {
+        self.markers = Some((on.into(), off.into()));
+        self
+    }
+    /// Sets whether macros are expanded in content. Otherwise content is written as it is, and
+    /// macros are only used by the conditions of directives. Defaults to true.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// let mut context = minipre::Context::new();
+    /// context.define("DEBUG", "1").expand_content(false);
+    /// let text = "#if DEBUG\nDEBUG build\n#endif\n";
+    /// assert_eq!(minipre::process_str(text, &mut context).unwrap(), "DEBUG build\n");
+    /// ```
+    pub fn expand_content(&mut self, expand: bool) -> &mut Self {
+        self.expand_content = expand;
+        self
+    }
+    /// Sets the keywords which start each directive, so that files with other conventions can be
+    /// processed.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// let mut context = minipre::Context::new();
+    /// context.directive_names(minipre::DirectiveNames {
+    ///     endif: "#fi".into(),
+    ///     ..Default::default()
+    /// });
+    /// assert_eq!(minipre::process_str("#if 0\nfoo\n#fi\nbar\n", &mut context).unwrap(), "bar\n");
+    /// ```
+    pub fn directive_names(&mut self, names: DirectiveNames) -> &mut Self {
+        self.directive_names = names;
+        self
+    }
+    /// Limits the directives which are interpreted to those named, including the `#`. Any other
+    /// line starting with `#` is treated as text, and written out as it is when active. By
+    /// default, every directive is interpreted.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// let mut context = minipre::Context::new();
+    /// context.recognized_directives(&["#if", "#else", "#endif"]);
+    /// assert_eq!(minipre::process_str("#heading\n#if 0\nfoo\n#endif\n", &mut context).unwrap(), "#heading\n");
+    /// ```
+    pub fn recognized_directives(&mut self, names: &[&str]) -> &mut Self {
+        self.recognized_directives = Some(names.iter().map(|&name| name.into()).collect());
+        self
+    }
+    fn is_recognized(&self, name: &str) -> bool {
+        self.recognized_directives
+            .as_ref()
+            .is_none_or(|names| names.iter().any(|n| n == name))
+    }
+    /// Sets the line terminator written after each line of output. A final line without one is
+    /// still written without one. Defaults to `Newline::Preserve`.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// let mut context = minipre::Context::new();
+    /// context.output_newline(minipre::Newline::Lf);
+    /// assert_eq!(minipre::process_str("foo\r\nbar\r\n", &mut context).unwrap(), "foo\nbar\n");
+    /// ```
+    pub fn output_newline(&mut self, newline: Newline) -> &mut Self {
+        self.output_newline = newline;
+        self
+    }
+    /// Sets the name of the input, which `__FILE__` expands to as a string literal. Within an
+    /// included file, `__FILE__` is the path given by the include resolver instead. Defaults to
+    /// the empty string.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// let mut context = minipre::Context::new();
+    /// context.file_name("main.c");
+    /// assert_eq!(minipre::process_str("__FILE__:__LINE__\n", &mut context).unwrap(), "\"main.c\":1\n");
+    /// ```
+    pub fn file_name<S: Into<String>>(&mut self, name: S) -> &mut Self {
+        self.file_name = name.into();
+        self
+    }
+    // Gets the value of a built-in macro on the given line
+    fn builtin(&self, name: &str, line: u32) -> Option<String> {
+        match name {
+            "__LINE__" => Some(line.to_string()),
+            "__FILE__" => Some(quote(&self.file_name)),
+            _ => None,
+        }
+    }
+    // Lowercases two strings which are to be tested for equality, if the context ignores case
+    fn fold_case(&self, lhs: Value, rhs: Value) -> (Value, Value) {
+        match (lhs, rhs) {
+            (Value::Str(lhs), Value::Str(rhs)) if self.case_insensitive_string_compare => (
+                Value::Str(lhs.to_lowercase()),
+                Value::Str(rhs.to_lowercase()),
+            ),
+            operands => operands,
+        }
+    }
+    // Checks that a directive may define or undefine a macro with the given name
+    fn check_unfrozen(&self, line: u32) -> Result<(), Error> {
+        if self.frozen {
+            return Err(Error::Syntax {
+                line,
+                msg: "Cannot change the macros of a frozen context",
+            });
+        }
+        Ok(())
+    }
+    // Checks that a macro can be defined with a name, or undefined with `undefine`, which allows
+    // forbidden names as they're never defined
+    fn check_macro_name(&self, name: &str, line: u32, undefine: bool) -> Result<(), Error> {
+        if !self.is_identifier(name) {
+            return Err(Error::Syntax {
+                line,
+                msg: "Invalid macro name",
+            });
+        }
+        if BUILTIN_MACROS.contains(&name) {
+            return Err(Error::Syntax {
+                line,
+                msg: "Cannot redefine built-in macro",
+            });
+        }
+        if !undefine && self.forbidden_names.iter().any(|n| n == name) {
+            return Err(Error::Syntax {
+                line,
+                msg: "Cannot define forbidden macro name",
+            });
+        }
+        Ok(())
+    }
+    // Whether a character may be part of an identifier
+    fn is_identifier_char(&self, c: char) -> bool {
+        match self.identifier_chars {
+            Some((ref start, ref cont)) => (start.0)(c) || (cont.0)(c),
+            None => {
+                c == '_'
+                    || if self.unicode_identifiers {
+                        c.is_alphanumeric()
+                    } else {
+                        c.is_ascii_alphanumeric()
+                    }
+            }
+        }
+    }
+    fn is_identifier_start(&self, c: char) -> bool {
+        match self.identifier_chars {
+            Some((ref start, _)) => (start.0)(c),
+            None => self.is_identifier_char(c) && !c.is_numeric(),
+        }
+    }
+    fn is_identifier(&self, name: &str) -> bool {
+        let mut chars = name.chars();
+        match self.identifier_chars {
+            Some((ref start, ref cont)) => {
+                chars.next().is_some_and(|c| (start.0)(c)) && chars.all(|c| (cont.0)(c))
+            }
+            None => {
+                chars.next().is_some_and(|c| self.is_identifier_start(c))
+                    && chars.all(|c| self.is_identifier_char(c))
+            }
+        }
+    }
+    // Adds the names in a condition to `names`, other than those of the operators
+    fn condition_names(&self, mut expr: &str, names: &mut BTreeSet<String>) {
+        while let Some(start) = expr.find(|c| self.is_identifier_start(c) || c == '"' || c == '\'')
+        {
+            expr = &expr[start..];
+            if expr.starts_with(['"', '\'']) {
+                // Quoted literals are skipped over
+                expr = &expr[parse_quoted(expr).map_or(1, |(_, len)| len)..];
+                continue;
+            }
+            let len = expr
+                .find(|c| !self.is_identifier_char(c))
+                .unwrap_or(expr.len());
+            let name = &expr[..len];
+            if !["defined", "__has_include", "contains", "min", "max"].contains(&name) {
+                names.insert(name.into());
+            }
+            expr = &expr[len..];
+        }
+    }
+    // Whether macros are only expanded where they aren't next to identifier characters, rather
+    // than at regex word boundaries
+    fn checks_boundaries(&self) -> bool {
+        self.identifier_boundaries || self.identifier_chars.is_some()
+    }
+    /// Sets the function used to find files for `#include` directives.
+    ///
+    /// The resolver is given the file name and the form it was written in, and returns the path
+    /// and contents of the file, or `None` if it can't be found. The file name may also be given
+    /// by a macro, as in `#include HEADER`. Included files are preprocessed with the same
+    /// context, so macros they define remain defined afterwards.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use std::path::PathBuf;
+    ///
+    /// let mut context = minipre::Context::new();
+    /// context.include_resolver(|name, _kind| {
+    ///     Ok(if name == "greeting.h" {
+    ///         Some((PathBuf::from(name), "hello\n".to_string()))
+    ///     } else {
+    ///         None
+    ///     })
+    /// });
+    /// assert_eq!(minipre::process_str("#include \"greeting.h\"\nworld\n", &mut context).unwrap(), "hello\nworld\n");
+    /// ```
+    pub fn include_resolver<F>(&mut self, resolver: F) -> &mut Self
+    where
+        F: Fn(&str, IncludeKind) -> io::Result<Option<(PathBuf, String)>>
+            + Send
+            + Sync
+            + RefUnwindSafe
+            + 'static,
+    {
+        self.include_resolver = Some(Shared(Arc::new(resolver)));
+        self
+    }
+    /// Sets a stream to which a log of the decisions made during preprocessing is written, such
+    /// as the result of each condition and each macro expanded. This is intended for debugging,
+    /// and the format of the log may change. Clones of the context share the same stream.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// let mut context = minipre::Context::new();
+    /// context.define("FOO", "1").set_trace(Box::new(std::io::stderr()));
+    /// minipre::process_str("#if FOO\nFOO\n#endif\n", &mut context).unwrap();
+    /// ```
+    pub fn set_trace(&mut self, trace: Box<dyn Write + Send>) -> &mut Self {
+        self.trace = Some(Shared(Arc::new(Mutex::new(trace))));
+        self
+    }
+    fn trace(&self, line: u32, args: fmt::Arguments) -> io::Result<()> {
+        match self.trace {
+            Some(ref trace) => {
+                let mut trace = trace.0.lock().unwrap_or_else(|e| e.into_inner());
+                writeln!(trace, "line {}: {}", line, args)
+            }
+            None => Ok(()),
+        }
+    }
+    fn build_regex(&self) -> Regex {
+        // Longer names are tried first, so that a name isn't hidden by a prefix of it when the
+        // boundaries are checked separately
+        let mut names: Vec<&str> = self.defs.keys().map(String::as_str).collect();
+        names.extend(BUILTIN_MACROS);
+        names.sort_by(|a, b| b.len().cmp(&a.len()).then(a.cmp(b)));
+        names.dedup();
+        let pat: String = names
+            .into_iter()
+            .flat_map(|k| vec!["|".into(), regex::escape(k)])
+            .skip(1)
+            .collect();
+        if self.checks_boundaries() {
+            Regex::new(&format!("(?:{})", pat)).expect("Regex should be valid")
+        } else {
+            Regex::new(&format!("\\b(?:{})\\b", pat)).expect("Regex should be valid")
+        }
+    }
+    fn expand<'t>(&mut self, text: &'t str, line: u32) -> Result<Cow<'t, str>, Error> {
+        if self.regex.is_none() {
+            self.regex = Some(self.build_regex());
+        }
+        let mut result = self.expand_text(text, line, &mut 0)?;
+        for (pattern, template) in self.patterns.iter() {
+            if let Cow::Owned(replaced) = pattern.replace_all(&result, template.as_str()) {
+                result = Cow::Owned(replaced);
+            }
+        }
+        Ok(result)
+    }
+    // Expands the macros in a line of content, unless the context says not to
+    fn expand_line<'t>(&mut self, text: &'t str, line: u32) -> Result<Cow<'t, str>, Error> {
+        if self.expand_content {
+            self.expand(text, line)
+        } else {
+            Ok(Cow::Borrowed(text))
+        }
+    }
+    // Expands each macro in `text` once, counting the expansions. The regex must already have
+    // been built.
+    fn expand_text<'t>(
+        &self,
+        text: &'t str,
+        line: u32,
+        expansions: &mut usize,
+    ) -> Result<Cow<'t, str>, Error> {
+        let regex = self.regex.as_ref().expect("Regex was built");
+        let mut result = String::new();
+        let mut copied = 0;
+        let mut pos = 0;
+        while let Some(m) = regex.find_at(text, pos) {
+            if self.checks_boundaries()
+                && (text[..m.start()]
+                    .chars()
+                    .next_back()
+                    .is_some_and(|c| self.is_identifier_char(c))
+                    || text[m.end()..]
+                        .chars()
+                        .next()
+                        .is_some_and(|c| self.is_identifier_char(c)))
+            {
+                // Look for another match starting within this one
+                pos = m.start() + text[m.start()..].chars().next().map_or(1, char::len_utf8);
+                continue;
+            }
+            let value = match self.builtin(m.as_str(), line) {
+                Some(value) => {
+                    pos = m.end();
+                    value
+                }
+                None => {
+                    let def = &self.defs.get(m.as_str()).expect("Found def for match").def;
+                    match def {
+                        Def::Function { params, body } => match parse_args(&text[m.end()..]) {
+                            Some((args, len)) => {
+                                // Arguments are expanded before being substituted
+                                let args = check_args(params, args)
+                                    .ok_or(Error::Syntax {
+                                        line,
+                                        msg: "Wrong number of arguments to macro",
+                                    })?
+                                    .into_iter()
+                                    .map(|arg| {
+                                        self.expand_text(&arg, line, expansions)
+                                            .map(Cow::into_owned)
+                                    })
+                                    .collect::<Result<Vec<_>, _>>()?;
+                                pos = m.end() + len;
+                                self.substitute(params, &args, body)
+                            }
+                            // Without arguments, the name of a function-like macro is left alone
+                            None => {
+                                pos = m.end();
+                                continue;
+                            }
+                        },
+                        _ => {
+                            pos = m.end();
+                            def.value().into_owned()
+                        }
+                    }
+                }
+            };
+            self.count_expansion(m.as_str(), expansions, line)?;
+            self.trace(
+                line,
+                format_args!("expanded '{}' -> '{}'", &text[m.start()..pos], value),
+            )?;
+            result.push_str(&text[copied..m.start()]);
+            result.push_str(&value);
+            copied = pos;
+        }
+        if copied == 0 {
+            return Ok(Cow::Borrowed(text));
+        }
+        result.push_str(&text[copied..]);
+        Ok(Cow::Owned(result))
+    }
+    // Expands the macros in `text` repeatedly, except for those being expanded already
+    fn expand_recursive<'s>(&'s self, text: &str, hidden: &mut Vec<&'s str>) -> String {
+        let mut result = String::new();
+        let mut rest = text;
+        while let Some(start) = rest.find(|c| self.is_identifier_char(c)) {
+            let len = rest[start..]
+                .find(|c| !self.is_identifier_char(c))
+                .unwrap_or(rest.len() - start);
+            let word = &rest[start..start + len];
+            result.push_str(&rest[..start]);
+            rest = &rest[start + len..];
+            let (name, def) = match self.defs.get_key_value(word) {
+                Some((name, m)) if !hidden.contains(&name.as_str()) => (name, &m.def),
+                _ => {
+                    result.push_str(word);
+                    continue;
+                }
+            };
+            let value = match def {
+                Def::Function { params, body } => {
+                    let found = parse_args(rest)
+                        .and_then(|(args, len)| check_args(params, args).map(|args| (args, len)));
+                    match found {
+                        Some((args, len)) => {
+                            rest = &rest[len..];
+                            self.substitute(params, &args, body)
+                        }
+                        // Without arguments, the name of a function-like macro is left alone
+                        None => {
+                            result.push_str(word);
+                            continue;
+                        }
+                    }
+                }
+                _ => def.value().into_owned(),
+            };
+            hidden.push(name);
+            result.push_str(&self.expand_recursive(&value, hidden));
+            hidden.pop();
+        }
+        result.push_str(rest);
+        result
+    }
+    // Replaces each parameter of a function-like macro in its body with the matching argument
+    fn substitute<S: AsRef<str>>(&self, params: &[String], args: &[S], body: &str) -> String {
+        let mut result = String::new();
+        let mut rest = body;
+        while let Some(start) = rest.find(|c| self.is_identifier_char(c)) {
+            let len = rest[start..]
+                .find(|c| !self.is_identifier_char(c))
+                .unwrap_or(rest.len() - start);
+            let word = &rest[start..start + len];
+            result.push_str(&rest[..start]);
+            let found = params
+                .iter()
+                .position(|param| param == word || param == VARIADIC && word == VA_ARGS);
+            match found {
+                Some(index) => {
+                    let arg = args[index].as_ref();
+                    // `, ## __VA_ARGS__` drops the comma when there are no variadic arguments
+                    if word == VA_ARGS {
+                        if let Some(before) = result.trim_end().strip_suffix("##") {
+                            let before = before.trim_end();
+                            if let Some(start) = before.strip_suffix(',') {
+                                let len = if arg.is_empty() {
+                                    start.trim_end().len()
+                                } else {
+                                    before.len()
+                                };
+                                result.truncate(len);
+                            }
+                        }
+                    }
+                    result.push_str(arg);
+                }
+                None => result.push_str(word),
+            }
+            rest = &rest[start + len..];
+        }
+        result.push_str(rest);
+        result
+    }
+    fn resolve_include(&mut self, expr: &str, line: u32) -> Result<(PathBuf, String), Error> {
+        // A file name which isn't quoted or bracketed must come from a macro
+        let expanded = if expr.starts_with('"') || expr.starts_with('<') {
+            Cow::Borrowed(expr)
+        } else {
+            self.expand(expr, line)?
+        };
+        let (name, kind) = parse_include_name(&expanded).ok_or(Error::Syntax {
+            line,
+            msg: "Expected \"file\" or <file> after `#include`",
+        })?;
+
+        let resolver = self.include_resolver.as_ref().ok_or(Error::Syntax {
+            line,
+            msg: "No include resolver is set",
+        })?;
+        (resolver.0)(name, kind)?.ok_or(Error::Syntax {
+            line,
+            msg: "Included file not found",
+        })
+    }
+    // Evaluates a condition, giving `None` if it's to be passed through because of an undefined
+    // identifier. With `zero_undefined`, undefined identifiers are `0` rather than an error.
+    fn condition(
+        &self,
+        directive: &str,
+        expr: &str,
+        line: u32,
+        zero_undefined: bool,
+    ) -> Result<Option<bool>, Error> {
+        let result = self.evaluate(expr, line, zero_undefined)?;
+        match result {
+            Some(result) => {
+                self.trace(line, format_args!("{} {} -> {}", directive, expr, result))?
+            }
+            None => self.trace(
+                line,
+                format_args!("{} {} -> passed through", directive, expr),
+            )?,
+        }
+        Ok(result)
+    }
+    fn evaluate(&self, expr: &str, line: u32, zero_undefined: bool) -> Result<Option<bool>, Error> {
+        self.parse_with(expr, line, true, zero_undefined)
+            .map(|value| value.map(|value| value.is_true()))
+    }
+    // Checks that an expression is well-formed, without evaluating it
+    fn validate(&self, expr: &str, line: u32) -> Result<(), Error> {
+        self.parse(expr, line, false).map(|_| ())
+    }
+    // Parses an expression, evaluating it if `active` is set. Gives `None` if an undefined
+    // identifier was found with `UndefinedMode::Passthrough`.
+    fn parse<'a>(&'a self, expr: &'a str, line: u32, active: bool) -> Result<Option<Value>, Error> {
+        self.parse_with(expr, line, active, false)
+    }
+    fn parse_with<'a>(
+        &'a self,
+        expr: &'a str,
+        line: u32,
+        active: bool,
+        zero_undefined: bool,
+    ) -> Result<Option<Value>, Error> {
+        let mut evaluator = Evaluator::new(self, expr, line);
+        evaluator.zero_undefined = zero_undefined;
+        // Macros which expand to nothing can leave nothing to evaluate
+        if *evaluator.peek()? == Token::End {
+            return Err(evaluator.error("Expression is empty after macro expansion"));
+        }
+        let result = evaluator.eval_ternary(active)?;
+        if evaluator.next_token(true)? != Token::End {
+            return Err(evaluator.error("Expected end-of-line"));
+        }
+        Ok(if evaluator.undefined {
+            None
+        } else {
+            Some(result)
+        })
+    }
+}
+
+// Operators recognised in expressions, longest first so that prefixes don't shadow them
+const OPERATORS: &[&str] = &[
+    "##", "||", "&&", "==", "!=", "<=", ">=", "<", ">", "!", "(", ")", "?", ":", "+", "-", "*",
+    "/", "%", ",",
+];
+
+#[derive(Eq, PartialEq, Debug, Clone)]
+enum Token {
+    Int(i64),
+    Str(String),
+    Ident(String),
+    Op(&'static str),
+    End,
+}
+
+// The value of an expression
+#[derive(Eq, PartialEq, Debug, Clone)]
+enum Value {
+    Int(i64),
+    Str(String),
+    // An identifier which isn't a macro, which is compared as a string by `==` and `!=` with
+    // `bare_word_comparisons`, and otherwise resolved as the undefined identifier it is
+    Word(String),
+}
+
+// An integer operator, which fails on overflow
+type IntOp = fn(i64, i64) -> Option<i64>;
+
+impl Value {
+    // Zero and the empty string are false, and everything else is true
+    fn is_true(&self) -> bool {
+        match *self {
+            Value::Int(value) => value != 0,
+            Value::Str(ref value) => !value.is_empty(),
+            Value::Word(_) => false,
+        }
+    }
+}
+
+impl From<bool> for Value {
+    fn from(value: bool) -> Self {
+        Value::Int(value as i64)
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Value::Int(value) => value.fmt(f),
+            Value::Str(ref value) | Value::Word(ref value) => f.write_str(value),
+        }
+    }
+}
+
+// Text still to be tokenized, along with the macro it was expanded from (if any)
+struct Source<'a> {
+    text: Cow<'a, str>,
+    pos: usize,
+    name: Option<&'a str>,
+}
+
+// Evaluates an expression, expanding macros as they are encountered. The right-hand side of
+// `&&` and `||` is only parsed (not evaluated) once the result is known, so that it may refer
+// to macros which aren't defined.
+struct Evaluator<'a> {
+    context: &'a Context,
+    line: u32,
+    sources: Vec<Source<'a>>,
+    peeked: Option<Token>,
+    // Whether an undefined identifier was evaluated with `UndefinedMode::Passthrough`
+    undefined: bool,
+    // Whether undefined identifiers, and macros which are empty, are `0` rather than an error,
+    // with `UndefinedMode::Error`
+    zero_undefined: bool,
+    // Whether a macro which is empty was expanded just before the last token
+    after_empty: bool,
+    // The number of macros expanded so far
+    expansions: usize,
+}
+
+impl<'a> Evaluator<'a> {
+    fn new(context: &'a Context, expr: &'a str, line: u32) -> Self {
+        Evaluator {
+            context,
+            line,
+            sources: vec![Source {
+                text: Cow::Borrowed(expr),
+                pos: 0,
+                name: None,
+            }],
+            peeked: None,
+            undefined: false,
+            zero_undefined: false,
+            after_empty: false,
+            expansions: 0,
+        }
+    }
+    fn error(&self, msg: &'static str) -> Error {
+        Error::Syntax {
+            line: self.line,
+            msg,
+        }
+    }
+    // Reads a single token from the start of `text`, returning it along with the number of
+    // bytes consumed, or `None` at the end of the text.
+    fn lex(&self, text: &str) -> Result<(Option<Token>, usize), Error> {
+        let trimmed = text.trim_start();
+        let skipped = text.len() - trimmed.len();
+        let first = match trimmed.chars().next() {
+            Some(first) => first,
+            None => return Ok((None, text.len())),
+        };
+
+        let (token, len) = if first.is_ascii_digit() || self.context.is_identifier_start(first) {
+            // A literal runs on through any letters, which make it invalid
+            let literal = !self.context.is_identifier_start(first);
+            let len = trimmed
+                .find(|c: char| {
+                    !(self.context.is_identifier_char(c) || literal && c.is_ascii_alphanumeric())
+                })
+                .unwrap_or(trimmed.len());
+            let word = &trimmed[..len];
+            if literal {
+                // A leading zero makes the literal octal, as in C
+                let value = if word.len() > 1 && word.starts_with('0') {
+                    i64::from_str_radix(&word[1..], 8)
+                } else {
+                    word.parse()
+                };
+                let value = value.map_err(|_| self.error("Invalid integer literal"))?;
+                (Token::Int(value), len)
+            } else {
+                (Token::Ident(word.into()), len)
+            }
+        } else if first == '\'' {
+            let (value, len) = match parse_quoted(trimmed) {
+                Some((ref value, len)) if value.chars().count() == 1 => (
+                    value.chars().next().expect("Literal has one character"),
+                    len,
+                ),
+                _ => return Err(self.error("Invalid character literal")),
+            };
+            (Token::Int(value as i64), len)
+        } else if first == '"' {
+            let (value, len) =
+                parse_quoted(trimmed).ok_or_else(|| self.error("Invalid string literal"))?;
+            (Token::Str(value), len)
+        } else {
+            let op = OPERATORS
+                .iter()
+                .find(|op| trimmed.starts_with(*op))
+                .ok_or_else(|| self.error("Unexpected character in expression"))?;
+            (Token::Op(op), op.len())
+        };
+        Ok((Some(token), skipped + len))
+    }
+    fn next_token(&mut self, expand: bool) -> Result<Token, Error> {
+        if let Some(token) = self.peeked.take() {
+            return Ok(token);
+        }
+        self.after_empty = false;
+        loop {
+            let (token, len) = match self.sources.last() {
+                Some(source) => self.lex(&source.text[source.pos..])?,
+                None => return Ok(Token::End),
+            };
+            self.sources.last_mut().expect("Source exists").pos += len;
+
+            let token = match token {
+                Some(token) => token,
+                None => {
+                    self.sources.pop();
+                    continue;
+                }
+            };
+            if let Token::Ident(ref word) = token {
+                // A macro is not expanded again within its own expansion
+                let hidden = word == "defined"
+                    || word == "__has_include"
+                    || self.sources.iter().any(|s| s.name == Some(word.as_str()));
+                if expand && !hidden {
+                    if let Some(value) = self.context.builtin(word, self.line) {
+                        self.context
+                            .count_expansion(word, &mut self.expansions, self.line)?;
+                        let name = BUILTIN_MACROS.iter().find(|b| *b == word);
+                        self.sources.push(Source {
+                            text: Cow::Owned(value),
+                            pos: 0,
+                            name: name.cloned(),
+                        });
+                        continue;
+                    }
+                }
+                match self
+                    .context
+                    .defs
+                    .get_key_value(word.as_str())
+                    .map(|(name, m)| (name, &m.def))
+                {
+                    Some((name, def)) if expand && !hidden => {
+                        let text = match def {
+                            Def::Function { params, body } => {
+                                let source = self.sources.last().expect("Source exists");
+                                let (args, len) = match parse_args(&source.text[source.pos..]) {
+                                    Some(found) => found,
+                                    // Without arguments, the name is left alone
+                                    None => return Ok(token),
+                                };
+                                let args = check_args(params, args).ok_or_else(|| {
+                                    self.error("Wrong number of arguments to macro")
+                                })?;
+                                let body = self.context.substitute(params, &args, body);
+                                self.sources.last_mut().expect("Source exists").pos += len;
+                                Cow::Owned(body)
+                            }
+                            _ => {
+                                // The left operand of `contains` is a macro's text, rather than
+                                // its tokens
+                                let source = self.sources.last().expect("Source exists");
+                                if let (Some(Token::Ident(next)), _) =
+                                    self.lex(&source.text[source.pos..])?
+                                {
+                                    if next == "contains" {
+                                        return Ok(Token::Str(self.resolve_list(name, def)));
+                                    }
+                                }
+                                let value = def.value();
+                                if value.trim().is_empty() {
+                                    if self.zero_undefined {
+                                        return Ok(Token::Int(0));
+                                    }
+                                    self.after_empty = true;
+                                }
+                                value
+                            }
+                        };
+                        self.context
+                            .count_expansion(name, &mut self.expansions, self.line)?;
+                        self.sources.push(Source {
+                            text,
+                            pos: 0,
+                            name: Some(name),
+                        });
+                        continue;
+                    }
+                    _ => {}
+                }
+            }
+            return Ok(token);
+        }
+    }
+    // Follows a list macro which is defined as the name of another macro, so that the list is
+    // the text at the end of the chain
+    fn resolve_list(&self, name: &'a str, def: &'a Def) -> String {
+        let mut seen: Vec<&str> = self.sources.iter().filter_map(|s| s.name).collect();
+        seen.push(name);
+        let mut value = def.value();
+        loop {
+            let next = value.trim();
+            if !self.context.is_identifier(next) || seen.contains(&next) {
+                return value.into_owned();
+            }
+            match self.context.defs.get_key_value(next) {
+                Some((next, m)) if !matches!(m.def, Def::Function { .. }) => {
+                    seen.push(next);
+                    value = m.def.value();
+                }
+                _ => return value.into_owned(),
+            }
+        }
+    }
+    fn peek(&mut self) -> Result<&Token, Error> {
+        if self.peeked.is_none() {
+            self.peeked = Some(self.next_token(true)?);
+        }
+        Ok(self.peeked.as_ref().expect("Token was peeked"))
+    }
+    fn eat(&mut self, op: &str) -> Result<bool, Error> {
+        match *self.peek()? {
+            Token::Op(o) if o == op => {
+                self.peeked = None;
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+    fn expect(&mut self, op: &str, msg: &'static str) -> Result<(), Error> {
+        if self.eat(op)? {
+            Ok(())
+        } else {
+            Err(self.error(msg))
+        }
+    }
+    fn eval_defined(&mut self) -> Result<Value, Error> {
+        // The operand of `defined` must not be expanded
+        let mut token = self.next_token(false)?;
+        let paren = token == Token::Op("(");
+        if paren {
+            token = self.next_token(false)?;
+        }
+        let mut name = match token {
+            Token::Ident(name) => name,
+            _ => return Err(self.error("Expected identifier after `defined`")),
+        };
+        // Names joined with `##` are each expanded, and then pasted together to form the name
+        if self.eat("##")? {
+            name = self.context.expand_macro(&name).unwrap_or(name);
+            loop {
+                let part = match self.next_token(false)? {
+                    Token::Ident(part) => self.context.expand_macro(&part).unwrap_or(part),
+                    Token::Int(part) => part.to_string(),
+                    _ => return Err(self.error("Expected identifier after `##`")),
+                };
+                name.push_str(part.trim());
+                if !self.eat("##")? {
+                    break;
+                }
+            }
+            name = name.trim().into();
+        }
+        if paren && self.next_token(false)? != Token::Op(")") {
+            return Err(self.error("Expected `)` after `defined`"));
+        }
+        Ok(Value::from(
+            self.context.defs.contains_key(&name) || BUILTIN_MACROS.contains(&name.as_str()),
+        ))
+    }
+    fn eval_has_include(&mut self, active: bool) -> Result<Value, Error> {
+        // The file name is taken from the text as it is, as it isn't made of tokens
+        let source = self.sources.last_mut().expect("Source exists");
+        let (name, kind, len) = match parse_args(&source.text[source.pos..]) {
+            Some((ref args, len)) if args.len() == 1 => match parse_include_name(args[0]) {
+                Some((name, kind)) => (name.to_string(), kind, len),
+                None => return Err(self.error("Expected \"file\" or <file> in `__has_include`")),
+            },
+            _ => return Err(self.error("Expected `(` and a file name after `__has_include`")),
+        };
+        source.pos += len;
+        if !active {
+            return Ok(Value::Int(0));
+        }
+        Ok(Value::from(match self.context.include_resolver {
+            Some(ref resolver) => (resolver.0)(&name, kind)?.is_some(),
+            None => false,
+        }))
+    }
+    fn eval_term(&mut self, active: bool) -> Result<Value, Error> {
+        match self.next_token(true)? {
+            Token::Int(value) => Ok(Value::Int(value)),
+            Token::Str(value) => Ok(Value::Str(value)),
+            Token::Ident(ref name) if name == "defined" => self.eval_defined(),
+            Token::Ident(ref name) if name == "__has_include" => self.eval_has_include(active),
+            Token::Ident(ref name) if (name == "min" || name == "max") && self.eat("(")? => {
+                let op: IntOp = if name == "min" {
+                    |lhs, rhs| Some(lhs.min(rhs))
+                } else {
+                    |lhs, rhs| Some(lhs.max(rhs))
+                };
+                let lhs = self.eval_ternary(active)?;
+                self.expect(",", "Expected `,` between arguments")?;
+                let rhs = self.eval_ternary(active)?;
+                self.expect(")", "Expected `)` after arguments")?;
+                self.arithmetic(active, lhs, rhs, op)
+            }
+            Token::Ident(name) => {
+                let word = Value::Word(name);
+                if self.context.bare_word_comparisons {
+                    Ok(word)
+                } else {
+                    self.resolve(active, word)
+                }
+            }
+            Token::Op("(") => {
+                let result = self.eval_ternary(active)?;
+                self.expect(")", "Expected `)`")?;
+                Ok(result)
+            }
+            Token::Op(_) | Token::End if self.after_empty => {
+                Err(self.error("Expected term, found a macro which is empty"))
+            }
+            Token::Op(_) => Err(self.error("Expected term, found operator")),
+            Token::End => Err(self.error("Expected term, found nothing")),
+        }
+    }
+    // Gives the value of an undefined identifier, which is an error unless the context says
+    // otherwise
+    fn resolve(&mut self, active: bool, value: Value) -> Result<Value, Error> {
+        match value {
+            Value::Word(name) if active && self.context.undefined_names.is_some() => {
+                let names = self
+                    .context
+                    .undefined_names
+                    .as_ref()
+                    .expect("Names are collected");
+                names
+                    .0
+                    .lock()
+                    .unwrap_or_else(|e| e.into_inner())
+                    .insert(name);
+                Ok(Value::Int(0))
+            }
+            Value::Word(name) if active => match self.context.undefined_mode {
+                UndefinedMode::Error if self.zero_undefined => Ok(Value::Int(0)),
+                UndefinedMode::Error => Err(Error::UndefinedIdentifier {
+                    line: self.line,
+                    name,
+                }),
+                UndefinedMode::Zero => Ok(Value::Int(0)),
+                UndefinedMode::Passthrough => {
+                    self.undefined = true;
+                    Ok(Value::Int(0))
+                }
+            },
+            Value::Word(_) => Ok(Value::Int(0)),
+            value => Ok(value),
+        }
+    }
+    fn eval_unary(&mut self, active: bool) -> Result<Value, Error> {
+        if self.eat("!")? {
+            let value = self.eval_unary(active)?;
+            Ok(Value::from(!self.resolve(active, value)?.is_true()))
+        } else if self.eat("-")? {
+            let value = self.eval_unary(active)?;
+            self.arithmetic(active, Value::Int(0), value, i64::checked_sub)
+        } else {
+            self.eval_term(active)
+        }
+    }
+    // Applies an integer operator, unless this part of the expression isn't being evaluated
+    fn arithmetic(
+        &mut self,
+        active: bool,
+        lhs: Value,
+        rhs: Value,
+        op: IntOp,
+    ) -> Result<Value, Error> {
+        if !active {
+            return Ok(Value::Int(0));
+        }
+        match (self.resolve(active, lhs)?, self.resolve(active, rhs)?) {
+            (Value::Int(lhs), Value::Int(rhs)) => op(lhs, rhs)
+                .map(Value::Int)
+                .ok_or_else(|| self.error("Integer overflow")),
+            _ => Err(self.error("Expected integer operand")),
+        }
+    }
+    fn eval_mul(&mut self, active: bool) -> Result<Value, Error> {
+        let mut result = self.eval_unary(active)?;
+        loop {
+            let (op, divides): (IntOp, bool) = if self.eat("*")? {
+                (i64::checked_mul, false)
+            } else if self.eat("/")? {
+                (i64::checked_div, true)
+            } else if self.eat("%")? {
+                (i64::checked_rem, true)
+            } else {
+                return Ok(result);
+            };
+            let rhs = self.eval_unary(active)?;
+            let rhs = self.resolve(active, rhs)?;
+            if divides && active && rhs == Value::Int(0) {
+                return Err(self.error("Division by zero"));
+            }
+            result = self.arithmetic(active, result, rhs, op)?;
+        }
+    }
+    fn eval_add(&mut self, active: bool) -> Result<Value, Error> {
+        let mut result = self.eval_mul(active)?;
+        loop {
+            result = if self.eat("+")? {
+                match (result, self.eval_mul(active)?) {
+                    // Adding strings joins them
+                    (Value::Str(lhs), Value::Str(rhs)) => Value::Str(lhs + &rhs),
+                    (lhs, rhs) => self.arithmetic(active, lhs, rhs, i64::checked_add)?,
+                }
+            } else if self.eat("-")? {
+                let rhs = self.eval_mul(active)?;
+                self.arithmetic(active, result, rhs, i64::checked_sub)?
+            } else {
+                return Ok(result);
+            };
+        }
+    }
+    // Compares two values of the same type, unless this part of the expression isn't being
+    // evaluated
+    fn compare(&mut self, active: bool, lhs: Value, rhs: Value) -> Result<Ordering, Error> {
+        if !active {
+            return Ok(Ordering::Equal);
+        }
+        match (&self.resolve(active, lhs)?, &self.resolve(active, rhs)?) {
+            (Value::Int(lhs), Value::Int(rhs)) => Ok(lhs.cmp(rhs)),
+            (Value::Str(lhs), Value::Str(rhs)) => Ok(lhs.cmp(rhs)),
+            _ => Err(self.error("Cannot compare a string with an integer")),
+        }
+    }
+    fn eat_word(&mut self, word: &str) -> Result<bool, Error> {
+        match *self.peek()? {
+            Token::Ident(ref w) if w == word => {}
+            _ => return Ok(false),
+        }
+        self.peeked = None;
+        Ok(true)
+    }
+    fn eval_rel(&mut self, active: bool) -> Result<Value, Error> {
+        let mut result = self.eval_add(active)?;
+        loop {
+            if self.eat_word("contains")? {
+                // The element is taken as it is, without being expanded
+                let element = match self.next_token(false)? {
+                    Token::Ident(element) | Token::Str(element) => element,
+                    Token::Int(element) => element.to_string(),
+                    _ => return Err(self.error("Expected list element after `contains`")),
+                };
+                let list = self.resolve(active, result)?.to_string();
+                let delimiter = self.context.list_delimiter;
+                result = Value::from(
+                    active
+                        && list
+                            .split(delimiter)
+                            .map(str::trim)
+                            .any(|e| !e.is_empty() && e == element),
+                );
+                continue;
+            }
+            let matches: fn(Ordering) -> bool = if self.eat("<=")? {
+                Ordering::is_le
+            } else if self.eat(">=")? {
+                Ordering::is_ge
+            } else if self.eat("<")? {
+                Ordering::is_lt
+            } else if self.eat(">")? {
+                Ordering::is_gt
+            } else {
+                return Ok(result);
+            };
+            let rhs = self.eval_add(active)?;
+            result = Value::from(matches(self.compare(active, result, rhs)?));
+        }
+    }
+    fn eval_eq(&mut self, active: bool) -> Result<Value, Error> {
+        let mut result = self.eval_rel(active)?;
+        loop {
+            let matches: fn(Ordering) -> bool = if self.eat("==")? {
+                Ordering::is_eq
+            } else if self.eat("!=")? {
+                Ordering::is_ne
+            } else {
+                return self.resolve(active, result);
+            };
+            let rhs = self.eval_rel(active)?;
+            // A bare word is compared as a string with another, or with a string
+            let (lhs, rhs) = match (result, rhs) {
+                (Value::Word(lhs), Value::Word(rhs)) => (Value::Str(lhs), Value::Str(rhs)),
+                (Value::Word(lhs), rhs @ Value::Str(_)) => (Value::Str(lhs), rhs),
+                (lhs @ Value::Str(_), Value::Word(rhs)) => (lhs, Value::Str(rhs)),
+                operands => operands,
+            };
+            let (lhs, rhs) = self.context.fold_case(lhs, rhs);
+            result = Value::from(matches(self.compare(active, lhs, rhs)?));
+        }
+    }
+    fn eval_and(&mut self, active: bool) -> Result<Value, Error> {
+        let mut result = self.eval_eq(active)?;
+        while self.eat("&&")? {
+            let lhs = result.is_true();
+            let rhs = self.eval_eq(active && lhs)?;
+            result = Value::from(lhs && rhs.is_true());
+        }
+        Ok(result)
+    }
+    fn eval_or(&mut self, active: bool) -> Result<Value, Error> {
+        let mut result = self.eval_and(active)?;
+        while self.eat("||")? {
+            let lhs = result.is_true();
+            let rhs = self.eval_and(active && !lhs)?;
+            result = Value::from(lhs || rhs.is_true());
+        }
+        Ok(result)
+    }
+    fn eval_ternary(&mut self, active: bool) -> Result<Value, Error> {
+        let condition = self.eval_or(active)?;
+        if !self.eat("?")? {
+            return Ok(condition);
+        }
+        // Only the branch which is picked is evaluated
+        let picked = condition.is_true();
+        let then = self.eval_ternary(active && picked)?;
+        self.expect(":", "Expected `:` in conditional expression")?;
+        let otherwise = self.eval_ternary(active && !picked)?;
+        Ok(if picked { then } else { otherwise })
+    }
+}
+
+#[derive(Eq, PartialEq, Debug, Copy, Clone)]
+enum State {
+    // A condition already matched, skip remaining clauses
     Skip,
     // Condition has not yet matched, evaluate remaining clauses
     Inactive,
@@ -218,539 +2921,5982 @@ enum State {
     Active,
 }
 
-/// Preprocesses a string.
-///
-/// This function takes a context and a string, and preprocesses it.
-///
-/// # Errors
-///
-/// This function returns a result and can fail with Err(minipre::Error).
-///
-/// # Examples
-///
-/// ```
-/// assert_eq!(minipre::process_str("
-///     #if FOO
-///     foo text
-///     #endif
-///     bar text", minipre::Context::new().define("FOO", "1")).unwrap(), "
-///     foo text
-///     bar text");
-/// assert_eq!(minipre::process_str("
-///     #if FOO
-///     foo text
-///     #endif
-///     bar text", minipre::Context::new().define("FOO", "0")).unwrap(), "
-///     bar text");
-/// ```
-pub fn process_str(input: &str, context: &mut Context) -> Result<String, Error> {
-    let mut output = Vec::new();
-    process(input.as_bytes(), &mut output, context)?;
-    Ok(String::from_utf8(output).expect("Input was utf8, so output should be too..."))
-}
+fn leading_whitespace(text: &str) -> &str {
+    let rest = text.trim_start_matches([' ', '\t']);
+    &text[..text.len() - rest.len()]
+}
+
+// Quotes an argument for a POSIX shell, if it contains any characters which need quoting
+fn shell_quote(arg: String) -> String {
+    let plain = |c: char| c.is_ascii_alphanumeric() || "-_=+.,/:@%".contains(c);
+    if arg.chars().all(plain) {
+        arg
+    } else {
+        format!("'{}'", arg.replace('\'', "'\\''"))
+    }
+}
+
+// Splits a quoted or bracketed file name into the name and the kind of include
+fn parse_include_name(text: &str) -> Option<(&str, IncludeKind)> {
+    let text = text.trim();
+    let len = text.len();
+    if len >= 2 && text.starts_with('"') && text.ends_with('"') {
+        Some((&text[1..len - 1], IncludeKind::Quoted))
+    } else if len >= 2 && text.starts_with('<') && text.ends_with('>') {
+        Some((&text[1..len - 1], IncludeKind::Angled))
+    } else {
+        None
+    }
+}
+
+// Parses the quoted literal at the start of `text`, which is closed by the same quote character
+// it starts with, returning its contents along with the length of the literal
+fn parse_quoted(text: &str) -> Option<(String, usize)> {
+    let mut chars = text.char_indices();
+    let quote = chars.next()?.1;
+    let mut value = String::new();
+    loop {
+        let c = match chars.next()? {
+            (index, c) if c == quote => return Some((value, index + 1)),
+            (_, '\\') => unescape(chars.next()?.1)?,
+            (_, c) => c,
+        };
+        value.push(c);
+    }
+}
+
+// Writes a string as a string literal, escaping any characters which `unescape` gives
+fn quote(text: &str) -> String {
+    let mut quoted = String::from("\"");
+    for c in text.chars() {
+        match c {
+            '\n' => quoted.push_str("\\n"),
+            '\t' => quoted.push_str("\\t"),
+            '\r' => quoted.push_str("\\r"),
+            '\0' => quoted.push_str("\\0"),
+            '\\' | '"' => {
+                quoted.push('\\');
+                quoted.push(c);
+            }
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+// Gives the character which an escape sequence stands for, from the character after the `\`
+fn unescape(c: char) -> Option<char> {
+    match c {
+        'n' => Some('\n'),
+        't' => Some('\t'),
+        'r' => Some('\r'),
+        '0' => Some('\0'),
+        '\\' | '\'' | '"' => Some(c),
+        _ => None,
+    }
+}
+
+// Splits the parenthesised, comma-separated arguments at the start of `text`, returning them
+// along with the length of the text they took up. Commas within nested parentheses or string and
+// character literals don't separate arguments.
+fn parse_args(text: &str) -> Option<(Vec<&str>, usize)> {
+    let trimmed = text.trim_start();
+    if !trimmed.starts_with('(') {
+        return None;
+    }
+    let offset = text.len() - trimmed.len();
+    let mut args = Vec::new();
+    let mut depth = 0;
+    let mut start = 1;
+    let mut index = 1;
+    while let Some(c) = trimmed[index..].chars().next() {
+        match c {
+            '(' => depth += 1,
+            ')' if depth == 0 => {
+                args.push(trimmed[start..index].trim());
+                return Some((args, offset + index + 1));
+            }
+            ')' => depth -= 1,
+            ',' if depth == 0 => {
+                args.push(trimmed[start..index].trim());
+                start = index + 1;
+            }
+            '"' | '\'' => {
+                if let Some(len) = literal_len(&trimmed[index..]) {
+                    index += len;
+                    continue;
+                }
+            }
+            _ => {}
+        }
+        index += c.len_utf8();
+    }
+    None
+}
+
+// Gives the length of the string or character literal at the start of `text`, if it's closed
+fn literal_len(text: &str) -> Option<usize> {
+    let mut chars = text.char_indices();
+    let quote = chars.next()?.1;
+    while let Some((index, c)) = chars.next() {
+        if c == '\\' {
+            chars.next();
+        } else if c == quote {
+            return Some(index + 1);
+        }
+    }
+    None
+}
+
+// Checks that there's an argument for each parameter of a function-like macro, joining any
+// variadic arguments into one
+fn check_args<'t>(params: &[String], mut args: Vec<&'t str>) -> Option<Vec<Cow<'t, str>>> {
+    // `()` is no arguments, rather than a single empty one, if that's what the macro expects
+    if params.is_empty() && args == [""] {
+        args.clear();
+    }
+    let variadic = if params.last().is_some_and(|param| param == VARIADIC) {
+        let fixed = params.len() - 1;
+        if args.len() < fixed {
+            return None;
+        }
+        Some(args.split_off(fixed).join(", "))
+    } else if args.len() == params.len() {
+        None
+    } else {
+        return None;
+    };
+    let mut args: Vec<_> = args.into_iter().map(Cow::Borrowed).collect();
+    args.extend(variadic.map(Cow::Owned));
+    Some(args)
+}
+
+type SelectOutput<O> = fn(&mut O, Option<&str>, &'static str) -> Option<&'static str>;
+
+// Writes emitted lines to the output, applying the context's output options
+struct Output<'c, O> {
+    inner: O,
+    trim_trailing_newline: bool,
+    // Line terminator held back in case it turns out to be the last one
+    pending_newline: &'static str,
+    // Whether content is written when it's skipped, rather than when it isn't
+    inverse: bool,
+    annotate_directives: bool,
+    newline: Newline,
+    // Checked before each line is processed
+    cancel: Option<&'c AtomicBool>,
+    // The files loaded by `#include` directives, if they're being collected
+    deps: Option<Vec<PathBuf>>,
+    lines_written: usize,
+    bytes_written: usize,
+    max_size: Option<usize>,
+    // Set once writing something would have gone over `max_size`, so it was left out
+    exceeded: bool,
+    // Whether the last line written had no line terminator
+    unterminated: bool,
+    // The input line being processed, outside of any included file
+    line: u32,
+    // The input line which each line written came from, if they're being recorded
+    line_numbers: Option<Vec<u32>>,
+    // Switches the writer which `inner` forwards to, when it can forward to named outputs. Takes
+    // the pending line terminator of the current writer, and gives that of the new one, or `None`
+    // if there's no output with the name.
+    select: Option<SelectOutput<O>>,
+}
+
+impl<'c, O: Write> Output<'c, O> {
+    fn new(inner: O, context: &Context) -> Self {
+        Output {
+            inner,
+            trim_trailing_newline: context.trim_trailing_newline,
+            pending_newline: "",
+            inverse: false,
+            annotate_directives: context.annotate_directives,
+            newline: context.output_newline,
+            cancel: None,
+            deps: None,
+            lines_written: 0,
+            bytes_written: 0,
+            max_size: context.max_output_size,
+            exceeded: false,
+            unterminated: false,
+            line: 0,
+            line_numbers: None,
+            select: None,
+        }
+    }
+    // Gives the line terminator written in place of the one at the end of a line
+    fn terminator(&self, newline: &'static str) -> &'static str {
+        match self.newline {
+            _ if newline.is_empty() => "",
+            Newline::Preserve => newline,
+            Newline::Lf => "\n",
+            Newline::Crlf => "\r\n",
+        }
+    }
+    fn write_line(&mut self, text: &str) -> io::Result<()> {
+        let newline = if text.ends_with("\r\n") {
+            "\r\n"
+        } else if text.ends_with('\n') {
+            "\n"
+        } else {
+            ""
+        };
+        let text = &text[..text.len() - newline.len()];
+        self.lines_written += 1;
+        if !text.is_empty() || !newline.is_empty() {
+            self.unterminated = newline.is_empty();
+            if let Some(ref mut numbers) = self.line_numbers {
+                numbers.push(self.line);
+            }
+        }
+        let newline = self.terminator(newline);
+        if self.trim_trailing_newline {
+            let pending = self.pending_newline;
+            self.write_str(pending)?;
+            self.write_str(text)?;
+            self.pending_newline = newline;
+            Ok(())
+        } else {
+            self.write_str(text)?;
+            self.write_str(newline)
+        }
+    }
+    // Writes part of a line, which is continued by the next text written
+    fn write_piece(&mut self, text: &str) -> io::Result<()> {
+        let pending = self.pending_newline;
+        self.write_str(pending)?;
+        self.pending_newline = "";
+        if !text.is_empty() {
+            self.unterminated = true;
+        }
+        self.write_str(text)
+    }
+    // Ends the last line written if it had no line terminator, as at the end of an included file
+    // without one, so that it isn't joined to the next line. `line` is the line, such as the
+    // directive, whose terminator is used.
+    fn end_line(&mut self, line: &str) -> io::Result<()> {
+        if !self.unterminated {
+            return Ok(());
+        }
+        self.unterminated = false;
+        let newline = if line.ends_with("\r\n") {
+            "\r\n"
+        } else if line.ends_with('\n') {
+            "\n"
+        } else {
+            ""
+        };
+        let newline = self.terminator(newline);
+        if self.trim_trailing_newline {
+            self.pending_newline = newline;
+            Ok(())
+        } else {
+            self.write_str(newline)
+        }
+    }
+    fn write_str(&mut self, text: &str) -> io::Result<()> {
+        let total = self.bytes_written + text.len();
+        if self.exceeded || self.max_size.is_some_and(|max| total > max) {
+            self.exceeded = true;
+            return Ok(());
+        }
+        self.bytes_written = total;
+        self.inner.write_all(text.as_bytes())
+    }
+    // Fails if the output has gone over its size limit
+    fn check_size(&self, line: u32) -> Result<(), Error> {
+        if self.exceeded {
+            Err(Error::Syntax {
+                line,
+                msg: "Output size limit exceeded",
+            })
+        } else {
+            Ok(())
+        }
+    }
+    // Writes a directive which is being passed through, unless only skipped content is written,
+    // or it has already been written as an annotation
+    fn write_directive(&mut self, text: &str) -> io::Result<()> {
+        if self.inverse || self.annotate_directives {
+            Ok(())
+        } else {
+            self.write_line(text)
+        }
+    }
+}
+
+/// Splits a directive line into the name of the directive, including the `#`, and its
+/// expression, as preprocessing does. Any `//` comment is removed first, but not a `//` within a
+/// string or character literal. Returns `None` if the line isn't a directive.
+///
+/// # Examples
+///
+/// ```
+/// assert_eq!(minipre::parse_directive("  #if FOO // comment"), Some(("#if", Some("FOO"))));
+/// assert_eq!(minipre::parse_directive("#endif"), Some(("#endif", None)));
+/// assert_eq!(minipre::parse_directive("some text"), None);
+/// ```
+pub fn parse_directive(line: &str) -> Option<(&str, Option<&str>)> {
+    let line = line.trim();
+    if !line.starts_with('#') {
+        return None;
+    }
+    Some(split_directive(line))
+}
+
+// Finds the `//` starting a comment on a directive line, skipping over any quoted literals. A
+// quote without a matching closing quote is taken as it is.
+fn comment_start(line: &str) -> Option<usize> {
+    let mut index = 0;
+    while let Some(offset) = line[index..].find(['/', '"', '\'']) {
+        index += offset;
+        let rest = &line[index..];
+        if rest.starts_with("//") {
+            return Some(index);
+        } else if rest.starts_with('/') {
+            index += 1;
+            continue;
+        }
+        index += literal_len(rest).unwrap_or(1);
+    }
+    None
+}
+
+// Removes the whitespace character separating a macro's name from its value
+fn value_after_separator(text: &str) -> &str {
+    match text.chars().next() {
+        Some(c) if c.is_whitespace() => &text[c.len_utf8()..],
+        _ => text,
+    }
+}
+
+// Splits a trimmed line which is known to be a directive into its name and expression
+fn split_directive(line: &str) -> (&str, Option<&str>) {
+    let line = &line[..comment_start(line).unwrap_or(line.len())];
+    let mut parts = line.splitn(2, ' ');
+    let name = parts.next().unwrap().trim_end();
+    let expr = parts.next().map(str::trim).filter(|s| !s.is_empty());
+    (name, expr)
+}
+
+/// Preprocesses a string.
+///
+/// This function takes a context and a string, and preprocesses it.
+///
+/// # Errors
+///
+/// This function returns a result and can fail with Err(minipre::Error).
+///
+/// # Examples
+///
+/// ```
+/// assert_eq!(minipre::process_str("
+///     #if FOO
+///     foo text
+///     #endif
+///     bar text", minipre::Context::new().define("FOO", "1")).unwrap(), "
+///     foo text
+///     bar text");
+/// assert_eq!(minipre::process_str("
+///     #if FOO
+///     foo text
+///     #endif
+///     bar text", minipre::Context::new().define("FOO", "0")).unwrap(), "
+///     bar text");
+/// ```
+pub fn process_str(input: &str, context: &mut Context) -> Result<String, Error> {
+    let mut output = Vec::new();
+    process(input.as_bytes(), &mut output, context)?;
+    Ok(String::from_utf8(output).expect("Input was utf8, so output should be too..."))
+}
+
+/// Preprocesses a byte slice, returning the output as bytes.
+///
+/// # Errors
+///
+/// Lines are read as text, so input which isn't valid UTF-8 fails with an `Error::Io` of kind
+/// `InvalidData`, like any other reader passed to `process`.
+///
+/// # Example
+///
+/// ```
+/// let mut context = minipre::Context::new();
+/// context.define("FOO", "1");
+///
+/// assert_eq!(minipre::process_bytes(b"FOO\n", &mut context).unwrap(), b"1\n");
+/// ```
+pub fn process_bytes(input: &[u8], context: &mut Context) -> Result<Vec<u8>, Error> {
+    let mut output = Vec::new();
+    process(input, &mut output, context)?;
+    Ok(output)
+}
+
+/// Preprocesses a string with two contexts, and compares the outputs line by line.
+///
+/// The result lists every line of both outputs in order, with the lines only found in the first
+/// output marked as removed, and those only found in the second marked as added. Line terminators
+/// are not included.
+///
+/// # Example
+///
+/// ```
+/// use minipre::DiffLine;
+///
+/// let diff = minipre::process_diff(
+///     "#if FOO\nfoo\n#endif\nbar\n",
+///     minipre::Context::new().define("FOO", "1"),
+///     minipre::Context::new().define("FOO", "0"),
+/// ).unwrap();
+/// assert_eq!(diff, [DiffLine::Removed("foo".into()), DiffLine::Unchanged("bar".into())]);
+/// ```
+pub fn process_diff(
+    input: &str,
+    context_a: &mut Context,
+    context_b: &mut Context,
+) -> Result<Vec<DiffLine>, Error> {
+    let a = process_str(input, context_a)?;
+    let b = process_str(input, context_b)?;
+    let a: Vec<&str> = a.lines().collect();
+    let b: Vec<&str> = b.lines().collect();
+
+    // The length of the longest common subsequence of the lines from each position onwards
+    let mut lengths = vec![vec![0usize; b.len() + 1]; a.len() + 1];
+    for i in (0..a.len()).rev() {
+        for j in (0..b.len()).rev() {
+            lengths[i][j] = if a[i] == b[j] {
+                lengths[i + 1][j + 1] + 1
+            } else {
+                lengths[i + 1][j].max(lengths[i][j + 1])
+            };
+        }
+    }
+
+    let mut diff = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    while i < a.len() || j < b.len() {
+        if i < a.len() && j < b.len() && a[i] == b[j] {
+            diff.push(DiffLine::Unchanged(a[i].into()));
+            i += 1;
+            j += 1;
+        } else if j == b.len() || (i < a.len() && lengths[i + 1][j] >= lengths[i][j + 1]) {
+            diff.push(DiffLine::Removed(a[i].into()));
+            i += 1;
+        } else {
+            diff.push(DiffLine::Added(b[j].into()));
+            j += 1;
+        }
+    }
+    Ok(diff)
+}
+
+/// Preprocesses a string without changing the context.
+///
+/// The input is processed with a copy of the context, so any `#define`, `#undef` or other
+/// directives which change the macros only affect the rest of this input. As the context is only
+/// borrowed immutably, it can be shared between threads.
+///
+/// # Example
+///
+/// ```
+/// let mut context = minipre::Context::new();
+/// context.define("FOO", "1");
+///
+/// assert_eq!(minipre::process_str_ro("#define FOO 2\nFOO\n", &context).unwrap(), "2\n");
+/// assert_eq!(context.get_macro("FOO").unwrap(), "1");
+/// ```
+pub fn process_str_ro(input: &str, context: &Context) -> Result<String, Error> {
+    process_str(input, &mut context.clone())
+}
+
+/// Preprocesses a generic buffer with a frozen context.
+///
+/// Directives which would change the macros, such as `#define` and `#undef`, fail when they're
+/// reached, and other directives work as they do with `process`.
+///
+/// Each call works with its own copy of the context, so that the state of processing is kept
+/// apart. The macros, forbidden names and patterns are shared with the frozen context rather
+/// than copied, unless a `#repeat` block defines its index, which copies the macros, and only
+/// the context's other options are copied.
+///
+/// # Example
+///
+/// ```
+/// let frozen = minipre::Context::new().define("FOO", "1").freeze();
+///
+/// let mut output = Vec::new();
+/// minipre::process_frozen("#if FOO\nfoo\n#endif\n".as_bytes(), &mut output, &frozen).unwrap();
+/// assert_eq!(output, b"foo\n");
+/// assert!(minipre::process_frozen("#define FOO 2\n".as_bytes(), &mut output, &frozen).is_err());
+/// ```
+pub fn process_frozen<I: BufRead, O: Write>(
+    input: I,
+    output: &mut O,
+    context: &FrozenContext,
+) -> Result<(), Error> {
+    process(input, output, &mut context.context.clone())
+}
+
+/// Preprocesses a generic buffer.
+///
+/// This function takes any generic BufRead input and Write output and preprocesses it.
+///
+/// Very long lines of ordinary text are written out in pieces as they are read, rather than
+/// being held in memory as a whole. Directives, and lines which could become directives or use
+/// function-like macros, are still read in full.
+///
+/// # Example
+///
+/// ```
+/// let mut output = Vec::new();
+/// minipre::process("
+///     foo text
+///     #if !FOO
+///     more text
+///     #endif
+///     bar text".as_bytes(), &mut output, minipre::Context::new().define("FOO", "0"));
+///
+/// assert_eq!(String::from_utf8(output).unwrap(), "
+///     foo text
+///     more text
+///     bar text");
+/// ```
+pub fn process<I: BufRead, O: Write>(
+    input: I,
+    output: O,
+    context: &mut Context,
+) -> Result<(), Error> {
+    let mut output = Output::new(output, context);
+    process_file(input, &mut output, context, 0, None)
+}
+
+/// Preprocesses the standard input, writing the result to the standard output.
+///
+/// Both streams are locked while processing. Requires the `std` feature, which is enabled by
+/// default.
+///
+/// # Example
+///
+/// ```no_run
+/// let mut context = minipre::Context::new();
+/// context.define("FOO", "1");
+/// minipre::process_stdin(&mut context).unwrap();
+/// ```
+#[cfg(feature = "std")]
+pub fn process_stdin(context: &mut Context) -> Result<(), Error> {
+    let stdin = io::stdin();
+    let stdout = io::stdout();
+    process_streams(stdin.lock(), stdout.lock(), context)
+}
+
+// Preprocesses one stream into another, flushing the output once done
+#[cfg(feature = "std")]
+fn process_streams<I: BufRead, O: Write>(
+    input: I,
+    mut output: O,
+    context: &mut Context,
+) -> Result<(), Error> {
+    process(input, &mut output, context)?;
+    output.flush()?;
+    Ok(())
+}
+
+/// Preprocesses a generic buffer, stopping early if a flag is set.
+///
+/// The flag is checked before each line is processed, including the lines of included files, so
+/// it can be set from another thread to stop processing. Processing then fails with
+/// `Error::Cancelled`, and the output has everything written before that point.
+///
+/// # Example
+///
+/// ```
+/// use std::sync::atomic::AtomicBool;
+///
+/// let cancel = AtomicBool::new(true);
+/// let mut output = Vec::new();
+/// let result = minipre::process_cancellable("foo\n".as_bytes(), &mut output, &mut minipre::Context::new(), &cancel);
+/// assert!(matches!(result, Err(minipre::Error::Cancelled)));
+/// ```
+pub fn process_cancellable<I: BufRead, O: Write>(
+    input: I,
+    output: O,
+    context: &mut Context,
+    cancel: &AtomicBool,
+) -> Result<(), Error> {
+    let mut output = Output::new(output, context);
+    output.cancel = Some(cancel);
+    process_file(input, &mut output, context, 0, None)
+}
+
+/// Preprocesses a generic buffer, writing only the content which is skipped.
+///
+/// This is the opposite of `process`: lines which would be written are left out, and lines
+/// within branches which aren't taken are written instead. Directives are still left out, and
+/// still change the context as they normally would.
+///
+/// # Example
+///
+/// ```
+/// let mut output = Vec::new();
+/// minipre::process_inverse("
+///     foo text
+///     #if 0
+///     more text
+///     #endif
+///     bar text".as_bytes(), &mut output, &mut minipre::Context::new()).unwrap();
+///
+/// assert_eq!(String::from_utf8(output).unwrap(), "    more text\n");
+/// ```
+pub fn process_inverse<I: BufRead, O: Write>(
+    input: I,
+    output: O,
+    context: &mut Context,
+) -> Result<(), Error> {
+    let mut output = Output::new(output, context);
+    output.inverse = true;
+    process_file(input, &mut output, context, 0, None)
+}
+
+/// Checks whether preprocessing a generic buffer would write anything, stopping as soon as it
+/// would. The context is changed by the input which is processed, as with `process`.
+///
+/// # Example
+///
+/// ```
+/// let mut context = minipre::Context::new();
+/// assert!(!minipre::produces_output("#if 0\nfoo\n#endif\n".as_bytes(), &mut context).unwrap());
+/// assert!(minipre::produces_output("#if 1\nfoo\n#endif\n".as_bytes(), &mut context).unwrap());
+/// ```
+pub fn produces_output<I: BufRead>(input: I, context: &mut Context) -> Result<bool, Error> {
+    let mut output = Output::new(Detector { produced: false }, context);
+    let result = process_file(input, &mut output, context, 0, None);
+    if output.inner.produced {
+        Ok(true)
+    } else {
+        result.map(|()| false)
+    }
+}
+
+/// Preprocesses a generic buffer, returning the length of the output in bytes without keeping
+/// the output itself.
+///
+/// # Example
+///
+/// ```
+/// let mut context = minipre::Context::new();
+/// context.define("FOO", "1234");
+/// assert_eq!(minipre::process_size("FOO\n".as_bytes(), &mut context).unwrap(), 5);
+/// ```
+pub fn process_size<I: BufRead>(input: I, context: &mut Context) -> Result<usize, Error> {
+    let mut output = Output::new(Counter(0), context);
+    process_file(input, &mut output, context, 0, None)?;
+    Ok(output.inner.0)
+}
+
+// Counts the bytes written to it, and discards them
+struct Counter(usize);
+
+impl Write for Counter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0 += buf.len();
+        Ok(buf.len())
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// Records whether anything is written to it, and fails the first write to stop processing
+struct Detector {
+    produced: bool,
+}
+
+impl Write for Detector {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        self.produced = true;
+        Err(io::Error::other("Output was produced"))
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Preprocesses a generic buffer, returning the result of each `#if` and `#elif` condition which
+/// was evaluated, by line number.
+///
+/// Conditions which aren't evaluated, because they're in a branch which isn't taken or an earlier
+/// branch was, are left out, as are the conditions in included files.
+///
+/// # Example
+///
+/// ```
+/// let mut output = Vec::new();
+/// let conditions = minipre::process_collect_conditions("#if 0
+/// #elif 1
+/// #elif 1
+/// #endif
+/// ".as_bytes(), &mut output, &mut minipre::Context::new()).unwrap();
+///
+/// assert_eq!(conditions.into_iter().collect::<Vec<_>>(), [(1, false), (2, true)]);
+/// ```
+pub fn process_collect_conditions<I: BufRead, O: Write>(
+    input: I,
+    output: O,
+    context: &mut Context,
+) -> Result<BTreeMap<u32, bool>, Error> {
+    let mut output = Output::new(output, context);
+    let mut processor = Processor::new(0);
+    processor.conditions = Some(BTreeMap::new());
+    process_file_with(input, &mut processor, &mut output, context, None)?;
+    Ok(processor.conditions.unwrap_or_default())
+}
+
+/// Preprocesses a generic buffer, returning where each directive is in the input, as byte offsets.
+/// Only directives in the input itself are included, not those in included files.
+///
+/// # Example
+///
+/// ```
+/// let mut output = Vec::new();
+/// let spans = minipre::process_with_spans("#if 1\nfoo\n  #endif\n".as_bytes(), &mut output, &mut minipre::Context::new()).unwrap();
+///
+/// assert_eq!((spans[1].name.as_str(), spans[1].start, spans[1].end), ("#endif", 12, 18));
+/// ```
+pub fn process_with_spans<I: BufRead, O: Write>(
+    input: I,
+    output: O,
+    context: &mut Context,
+) -> Result<Vec<DirectiveSpan>, Error> {
+    let mut output = Output::new(output, context);
+    let mut processor = Processor::new(0);
+    processor.spans = Some(Vec::new());
+    process_file_with(input, &mut processor, &mut output, context, None)?;
+    Ok(processor.spans.unwrap_or_default())
+}
+
+/// Preprocesses a generic buffer, returning the output along with the files loaded by
+/// `#include` directives, including those within included files. Each file is listed once, in the
+/// order it was first included.
+///
+/// # Example
+///
+/// ```
+/// use std::path::PathBuf;
+///
+/// let mut context = minipre::Context::new();
+/// context.include_resolver(|name, _| Ok(Some((PathBuf::from(name), format!("{}\n", name)))));
+///
+/// let (output, deps) = minipre::process_with_deps("#include \"a.h\"\n#include \"a.h\"\n".as_bytes(), &mut context).unwrap();
+/// assert_eq!(output, "a.h\na.h\n");
+/// assert_eq!(deps, [PathBuf::from("a.h")]);
+/// ```
+pub fn process_with_deps<I: BufRead>(
+    input: I,
+    context: &mut Context,
+) -> Result<(String, Vec<PathBuf>), Error> {
+    let mut output = Output::new(Vec::new(), context);
+    output.deps = Some(Vec::new());
+    process_file(input, &mut output, context, 0, None)?;
+    Ok((
+        String::from_utf8(output.inner).expect("Input was utf8, so output should be too..."),
+        output.deps.unwrap_or_default(),
+    ))
+}
+
+/// Preprocesses a generic buffer, returning the output along with the name of each undefined
+/// identifier evaluated in an expression, to help find macros which are missing. Rather than being
+/// an error, such identifiers are `0`, whatever the context's `undefined_mode`.
+///
+/// # Example
+///
+/// ```
+/// let mut context = minipre::Context::new();
+/// context.define("FOO", "1");
+///
+/// let text = "#if FOO && BAR\nbar\n#elif BAZ || BAR\nbaz\n#endif\n";
+/// let (output, names) = minipre::process_collect_undefined(text.as_bytes(), &mut context).unwrap();
+/// assert_eq!(output, "");
+/// assert_eq!(names.into_iter().collect::<Vec<_>>(), ["BAR", "BAZ"]);
+/// ```
+pub fn process_collect_undefined<I: BufRead>(
+    input: I,
+    context: &mut Context,
+) -> Result<(String, BTreeSet<String>), Error> {
+    let mut output = Output::new(Vec::new(), context);
+    let names = Arc::new(Mutex::new(BTreeSet::new()));
+    context.undefined_names = Some(Shared(names.clone()));
+    let result = process_file(input, &mut output, context, 0, None);
+    context.undefined_names = None;
+    result?;
+
+    let names = mem::take(&mut *names.lock().unwrap_or_else(|e| e.into_inner()));
+    Ok((
+        String::from_utf8(output.inner).expect("Input was utf8, so output should be too..."),
+        names,
+    ))
+}
+
+/// Preprocesses a generic buffer, writing to one of several named outputs.
+///
+/// An `#output "name"` directive sends the content after it to the output with that name, and
+/// `#output` on its own goes back to `output`, which is where content is written before any
+/// `#output` directive. Naming an output which isn't in `outputs` is an error. When the context
+/// trims the trailing newline, it's trimmed from each output.
+///
+/// # Example
+///
+/// ```
+/// use std::collections::BTreeMap;
+///
+/// let mut outputs = BTreeMap::new();
+/// outputs.insert("a".to_string(), Vec::new());
+/// let mut output = Vec::new();
+/// minipre::process_with_outputs(
+///     "main\n#output \"a\"\nfor a\n#output\nmain again\n".as_bytes(),
+///     &mut output,
+///     &mut outputs,
+///     &mut minipre::Context::new(),
+/// ).unwrap();
+/// assert_eq!(output, b"main\nmain again\n");
+/// assert_eq!(outputs["a"], b"for a\n");
+/// ```
+pub fn process_with_outputs<I: BufRead, O: Write, S: Write>(
+    input: I,
+    output: &mut O,
+    outputs: &mut BTreeMap<String, S>,
+    context: &mut Context,
+) -> Result<(), Error> {
+    let router = Router {
+        default: output,
+        outputs,
+        current: None,
+        pending: BTreeMap::new(),
+    };
+    let mut output = Output::new(router, context);
+    output.select = Some(Router::select);
+    process_file(input, &mut output, context, 0, None)
+}
+
+// Forwards writes to the default output, or to the named output selected by `#output`
+struct Router<'a, O, S> {
+    default: &'a mut O,
+    outputs: &'a mut BTreeMap<String, S>,
+    current: Option<String>,
+    // The line terminator held back for each output other than the current one
+    pending: BTreeMap<Option<String>, &'static str>,
+}
+
+impl<'a, O: Write, S: Write> Router<'a, O, S> {
+    fn select(&mut self, name: Option<&str>, pending: &'static str) -> Option<&'static str> {
+        if name.is_some_and(|name| !self.outputs.contains_key(name)) {
+            return None;
+        }
+        self.pending.insert(self.current.take(), pending);
+        self.current = name.map(Into::into);
+        Some(self.pending.remove(&self.current).unwrap_or(""))
+    }
+    fn target(&mut self) -> &mut dyn Write {
+        match self.current {
+            Some(ref name) => self.outputs.get_mut(name).expect("Output exists"),
+            None => &mut *self.default,
+        }
+    }
+}
+
+impl<'a, O: Write, S: Write> Write for Router<'a, O, S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.target().write(buf)
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        for output in self.outputs.values_mut() {
+            output.flush()?;
+        }
+        self.default.flush()
+    }
+}
+
+/// Preprocesses a generic buffer, returning a summary of what was done.
+///
+/// # Example
+///
+/// ```
+/// let mut context = minipre::Context::new();
+/// context.define("FOO", "1").define("BAR", "2");
+///
+/// let mut output = Vec::new();
+/// let report = minipre::process_with_report("#if FOO\nBAR\n#endif\n".as_bytes(), &mut output, &mut context).unwrap();
+/// assert_eq!(report.lines_in, 3);
+/// assert_eq!(report.lines_out, 1);
+/// assert_eq!(report.conditions.into_iter().collect::<Vec<_>>(), [(1, true)]);
+/// assert_eq!(report.macros_used.into_iter().collect::<Vec<_>>(), ["BAR", "FOO"]);
+/// ```
+pub fn process_with_report<I: BufRead, O: Write>(
+    input: I,
+    output: O,
+    context: &mut Context,
+) -> Result<ProcessReport, Error> {
+    let mut output = Output::new(output, context);
+    output.deps = Some(Vec::new());
+    let mut processor = Processor::new(0);
+    processor.conditions = Some(BTreeMap::new());
+    let used_macros = Arc::new(Mutex::new(BTreeSet::new()));
+    context.used_macros = Some(Shared(used_macros.clone()));
+    let result = process_file_with(input, &mut processor, &mut output, context, None);
+    context.used_macros = None;
+    result?;
+
+    let macros_used = mem::take(&mut *used_macros.lock().unwrap_or_else(|e| e.into_inner()));
+    Ok(ProcessReport {
+        lines_in: processor.line,
+        lines_out: output.lines_written,
+        conditions: processor.conditions.unwrap_or_default(),
+        macros_used,
+        files_included: output.deps.unwrap_or_default(),
+    })
+}
+
+/// Counts the conditional blocks in a generic buffer, without preprocessing it.
+///
+/// Every directive is counted, whether or not its block would be skipped, and macros in the
+/// input aren't expanded. Included files aren't read.
+///
+/// # Errors
+///
+/// Fails with a syntax error if there's an `#endif` with no matching `#if`.
+///
+/// # Example
+///
+/// ```
+/// let analysis = minipre::analyze("#if A
+/// #ifdef B
+/// #endif
+/// #elif A && C
+/// #else
+/// #endif
+/// ".as_bytes(), &minipre::Context::new()).unwrap();
+///
+/// assert_eq!((analysis.ifs, analysis.elifs, analysis.elses, analysis.endifs), (2, 1, 1, 2));
+/// assert_eq!(analysis.max_depth, 2);
+/// assert_eq!(analysis.macros.len(), 3);
+/// ```
+pub fn analyze<I: BufRead>(mut input: I, context: &Context) -> Result<Analysis, Error> {
+    let mut analysis = Analysis::default();
+    let mut depth = 0;
+    let mut buf = String::new();
+    let mut line = 0;
+    while input.read_line(&mut buf)? > 0 {
+        line += 1;
+        let text = context
+            .strip_comment_prefix(buf.trim_start_matches(BOM))
+            .trim();
+        if context.directive_names.is_directive(text) {
+            let (keyword, expr) = split_directive(text);
+            let counter = match context.directive_names.canonical(keyword) {
+                Some("#if") | Some("#ifdef") | Some("#ifndef") => {
+                    depth += 1;
+                    analysis.max_depth = analysis.max_depth.max(depth);
+                    Some(&mut analysis.ifs)
+                }
+                Some("#elif") | Some("#elifdef") | Some("#elifndef") => Some(&mut analysis.elifs),
+                Some("#else") => Some(&mut analysis.elses),
+                Some("#endif") => {
+                    depth = depth.checked_sub(1).ok_or(Error::Syntax {
+                        line,
+                        msg: "Unexpected `#endif` with no matching `#if`",
+                    })?;
+                    Some(&mut analysis.endifs)
+                }
+                _ => None,
+            };
+            if let Some(counter) = counter {
+                *counter += 1;
+                context.condition_names(expr.unwrap_or(""), &mut analysis.macros);
+            }
+        }
+        buf.clear();
+    }
+    Ok(analysis)
+}
+
+/// Parses the conditional blocks in a generic buffer into a tree, without preprocessing it.
+///
+/// Every block is included, whether or not it would be skipped, and conditions are neither
+/// evaluated nor expanded. Directives are recognized by their default names.
+///
+/// # Errors
+///
+/// Fails with a syntax error if there's an `#elif`, `#else` or `#endif` with no matching `#if`,
+/// or an `#if` with no matching `#endif`.
+///
+/// # Example
+///
+/// ```
+/// let blocks = minipre::parse_structure("#if A
+/// #ifdef B
+/// #endif
+/// #else
+/// #endif
+/// ".as_bytes()).unwrap();
+///
+/// assert_eq!((blocks[0].start, blocks[0].end), (1, 5));
+/// assert_eq!(blocks[0].branches[0].condition.as_deref(), Some("A"));
+/// assert_eq!(blocks[0].branches[0].blocks[0].branches[0].directive, "#ifdef");
+/// assert_eq!(blocks[0].branches[1].line, 4);
+/// ```
+pub fn parse_structure<I: BufRead>(mut input: I) -> Result<Vec<ConditionalBlock>, Error> {
+    let names = DirectiveNames::default();
+    let mut blocks = Vec::new();
+    // The blocks which are open, innermost last
+    let mut open: Vec<ConditionalBlock> = Vec::new();
+    let mut buf = String::new();
+    let mut line = 0;
+    while input.read_line(&mut buf)? > 0 {
+        line += 1;
+        let text = buf.trim_start_matches(BOM).trim();
+        let name = if names.is_directive(text) {
+            names.canonical(split_directive(text).0)
+        } else {
+            None
+        };
+        if let Some(name) = name {
+            let branch = ConditionalBranch {
+                directive: name.into(),
+                condition: split_directive(text).1.map(Into::into),
+                line,
+                blocks: Vec::new(),
+            };
+            match name {
+                "#if" | "#ifdef" | "#ifndef" => open.push(ConditionalBlock {
+                    start: line,
+                    end: line,
+                    branches: vec![branch],
+                }),
+                "#elif" | "#elifdef" | "#elifndef" | "#else" => {
+                    let block = open.last_mut().ok_or(Error::Syntax {
+                        line,
+                        msg: "Unexpected `#elif` or `#else` with no matching `#if`",
+                    })?;
+                    block.branches.push(branch);
+                }
+                "#endif" => {
+                    let mut block = open.pop().ok_or(Error::Syntax {
+                        line,
+                        msg: "Unexpected `#endif` with no matching `#if`",
+                    })?;
+                    block.end = line;
+                    match open.last_mut() {
+                        Some(outer) => outer
+                            .branches
+                            .last_mut()
+                            .expect("A block has a branch")
+                            .blocks
+                            .push(block),
+                        None => blocks.push(block),
+                    }
+                }
+                _ => {}
+            }
+        }
+        buf.clear();
+    }
+    match open.last() {
+        Some(block) => Err(Error::Syntax {
+            line: block.start,
+            msg: "Expected `#endif` before the end of the input",
+        }),
+        None => Ok(blocks),
+    }
+}
+
+/// Preprocesses a generic buffer, recording which conditional blocks were open if it fails.
+///
+/// On error, `open_lines` is set to the line numbers of the `#if` directives starting each
+/// conditional block which was open at the time, outermost first. Only blocks in the input itself
+/// are recorded, and not those in included files.
+///
+/// # Example
+///
+/// ```
+/// let mut open_lines = Vec::new();
+/// let result = minipre::process_with_open_lines("#if 1
+/// #endif
+/// #if 1
+/// #if 1
+/// #bad
+/// #endif
+/// #endif
+/// ".as_bytes(), Vec::new(), &mut minipre::Context::new(), &mut open_lines);
+///
+/// assert!(result.is_err());
+/// assert_eq!(open_lines, [3, 4]);
+/// ```
+pub fn process_with_open_lines<I: BufRead, O: Write>(
+    input: I,
+    output: O,
+    context: &mut Context,
+    open_lines: &mut Vec<u32>,
+) -> Result<(), Error> {
+    let mut output = Output::new(output, context);
+    let mut processor = Processor::new(0);
+    let result = process_file_with(input, &mut processor, &mut output, context, None);
+    if result.is_err() {
+        *open_lines = processor.open_lines;
+    }
+    result
+}
+
+/// Preprocesses a generic buffer, carrying on past syntax errors.
+///
+/// Rather than stopping at the first syntax error, the offending line is skipped and the error
+/// collected. A condition which fails to evaluate is treated as false. I/O errors still stop
+/// processing, and are returned as the last error. The output is returned along with every error
+/// encountered.
+///
+/// # Example
+///
+/// ```
+/// let (output, errors) = minipre::process_collect_errors("
+///     #if FOO
+///     foo
+///     #endif
+///     bar
+///     #bad".as_bytes(), Vec::new(), &mut minipre::Context::new());
+///
+/// assert_eq!(String::from_utf8(output).unwrap(), "
+///     bar
+/// ");
+/// assert_eq!(errors.len(), 2);
+/// ```
+pub fn process_collect_errors<I: BufRead, O: Write>(
+    input: I,
+    output: O,
+    context: &mut Context,
+) -> (O, Vec<Error>) {
+    let mut errors = Vec::new();
+    let mut output = Output::new(output, context);
+    if let Err(e) = process_file(input, &mut output, context, 0, Some(&mut errors)) {
+        errors.push(e);
+    }
+    (output.inner, errors)
+}
+
+/// Preprocesses text as it is written, forwarding the result to another writer.
+///
+/// Input is buffered until a whole line has been written, and then processed with the held
+/// context. `flush` flushes the underlying writer, but leaves an incomplete line buffered, so
+/// that it can still be continued: the final line is processed by `finish`, or when the
+/// `LineProcessor` is dropped, in which case any error is ignored.
+///
+/// # Example
+///
+/// ```
+/// use std::io::Write;
+///
+/// let mut context = minipre::Context::new();
+/// context.define("FOO", "1");
+///
+/// let mut processor = minipre::LineProcessor::new(Vec::new(), context);
+/// processor.write_all(b"#if FO").unwrap();
+/// processor.write_all(b"O\nfoo\n#endif\nbar").unwrap();
+/// let output = processor.finish().unwrap();
+/// assert_eq!(String::from_utf8(output).unwrap(), "foo\nbar");
+/// ```
+pub struct LineProcessor<W: Write> {
+    // Taken once processing is finished
+    output: Option<Output<'static, W>>,
+    context: Context,
+    processor: Processor,
+    buf: Vec<u8>,
+}
+
+impl<W: Write> LineProcessor<W> {
+    /// Creates a `LineProcessor` which preprocesses with the given context, and writes the
+    /// result to `output`.
+    pub fn new(output: W, context: Context) -> Self {
+        LineProcessor {
+            output: Some(Output::new(output, &context)),
+            context,
+            processor: Processor::new(0),
+            buf: Vec::new(),
+        }
+    }
+    /// Gets the context, including any changes made to it by the input so far.
+    pub fn context(&self) -> &Context {
+        &self.context
+    }
+    /// Processes any incomplete final line, and returns the underlying writer.
+    pub fn finish(mut self) -> Result<W, Error> {
+        self.process_buffered(true)?;
+        self.processor.finish()?;
+        let output = self.output.take().expect("Not yet finished");
+        Ok(output.inner)
+    }
+    // Processes each complete line in the buffer, or everything if this is the end of the input
+    fn process_buffered(&mut self, end: bool) -> Result<(), Error> {
+        let output = self.output.as_mut().expect("Not yet finished");
+        let mut start = 0;
+        while start < self.buf.len() {
+            let len = match self.buf[start..].iter().position(|&b| b == b'\n') {
+                Some(index) => index + 1,
+                None if end => self.buf.len() - start,
+                None => break,
+            };
+            let line = std::str::from_utf8(&self.buf[start..start + len])
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            start += len;
+            let processor = &mut self.processor;
+            let result = processor
+                .process_line(line, output, &mut self.context, &mut None)
+                .and_then(|()| output.check_size(processor.line));
+            if result.is_err() {
+                self.buf.drain(..start);
+                return result;
+            }
+        }
+        self.buf.drain(..start);
+        Ok(())
+    }
+}
+
+impl<W: Write> Write for LineProcessor<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buf.extend_from_slice(buf);
+        self.process_buffered(false).map_err(|e| match e {
+            Error::Io(e) => e,
+            e => io::Error::new(io::ErrorKind::InvalidData, e),
+        })?;
+        Ok(buf.len())
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        self.output
+            .as_mut()
+            .expect("Not yet finished")
+            .inner
+            .flush()
+    }
+}
+
+impl<W: Write> Drop for LineProcessor<W> {
+    fn drop(&mut self) {
+        if self.output.is_some() {
+            let _ = self
+                .process_buffered(true)
+                .and_then(|()| self.processor.finish());
+        }
+    }
+}
+
+/// Preprocesses a generic buffer lazily, yielding each output line along with the number of the
+/// input line it came from.
+///
+/// Line terminators are removed from the yielded lines. Lines which are skipped, and directives,
+/// produce nothing, and lines from an `#include`d file are numbered with the line of the
+/// `#include` directive. Each repetition of a `#repeat` block is numbered with the lines of the
+/// block. Iteration stops after the first error.
+///
+/// # Example
+///
+/// ```
+/// let lines: Vec<_> = minipre::process_lines("
+///     #if FOO
+///     foo
+///     #endif
+///     bar".as_bytes(), minipre::Context::new().define("FOO", "0"))
+///     .collect::<Result<_, _>>()
+///     .unwrap();
+///
+/// assert_eq!(lines, [(1, String::new()), (5, "    bar".to_string())]);
+/// ```
+pub fn process_lines<I: BufRead>(input: I, context: &mut Context) -> ProcessLines<'_, I> {
+    let output = Output {
+        trim_trailing_newline: false,
+        newline: Newline::Preserve,
+        line_numbers: Some(Vec::new()),
+        ..Output::new(Vec::new(), context)
+    };
+    ProcessLines {
+        input: Some(input),
+        context,
+        processor: Processor::new(0),
+        output,
+        lines: VecDeque::new(),
+    }
+}
+
+/// Preprocesses a generic buffer, passing each line of the output to `sink` as it's produced,
+/// without its line terminator, like `process_lines`. An error from `sink` stops the
+/// preprocessing, and is returned.
+///
+/// # Example
+///
+/// ```
+/// let mut lines = Vec::new();
+/// minipre::process_with_sink("#if 1\nfoo\n#else\nbar\n#endif\nbaz\n".as_bytes(), |line| {
+///     lines.push(line.to_uppercase());
+///     Ok(())
+/// }, &mut minipre::Context::new()).unwrap();
+///
+/// assert_eq!(lines, ["FOO", "BAZ"]);
+/// ```
+pub fn process_with_sink<I: BufRead, F: FnMut(&str) -> Result<(), Error>>(
+    input: I,
+    mut sink: F,
+    context: &mut Context,
+) -> Result<(), Error> {
+    for line in process_lines(input, context) {
+        sink(&line?.1)?;
+    }
+    Ok(())
+}
+
+/// An iterator over the preprocessed lines of some input, created by `process_lines`.
+pub struct ProcessLines<'a, I> {
+    // Cleared at the end of the input, or after an error
+    input: Option<I>,
+    context: &'a mut Context,
+    processor: Processor,
+    output: Output<'a, Vec<u8>>,
+    lines: VecDeque<(u32, String)>,
+}
+
+impl<'a, I: BufRead> ProcessLines<'a, I> {
+    // Processes the next input line, queueing any output lines it produces
+    fn process_next(&mut self, input: &mut I) -> Result<bool, Error> {
+        let mut buf = String::new();
+        if input.read_line(&mut buf)? == 0 {
+            return self.processor.finish().map(|()| false);
+        }
+        self.processor
+            .process_line(&buf, &mut self.output, self.context, &mut None)?;
+        self.output.check_size(self.processor.line)?;
+        let output = String::from_utf8(self.output.inner.split_off(0))
+            .expect("Input was utf8, so output should be too...");
+        // Lines written within a `#repeat` block come from the lines of the block, rather than
+        // the line ending it
+        let mut numbers = self
+            .output
+            .line_numbers
+            .as_mut()
+            .expect("Line numbers are recorded")
+            .drain(..);
+        for text in output.lines() {
+            let line = numbers.next().unwrap_or(self.processor.line);
+            self.lines.push_back((line, text.to_string()));
+        }
+        Ok(true)
+    }
+}
+
+impl<'a, I: BufRead> Iterator for ProcessLines<'a, I> {
+    type Item = Result<(u32, String), Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.lines.is_empty() {
+            let mut input = self.input.take()?;
+            match self.process_next(&mut input) {
+                Ok(true) => self.input = Some(input),
+                Ok(false) => {}
+                Err(e) => return Some(Err(e)),
+            }
+        }
+        self.lines.pop_front().map(Ok)
+    }
+}
+
+// Preprocesses a single file, recursing into any files it includes. When `errors` is given,
+// syntax errors are collected there and the offending lines skipped.
+fn process_file<I: BufRead, O: Write>(
+    input: I,
+    output: &mut Output<O>,
+    context: &mut Context,
+    depth: usize,
+    errors: Option<&mut Vec<Error>>,
+) -> Result<(), Error> {
+    process_file_with(input, &mut Processor::new(depth), output, context, errors)
+}
+
+fn process_file_with<I: BufRead, O: Write>(
+    input: I,
+    processor: &mut Processor,
+    output: &mut Output<O>,
+    context: &mut Context,
+    mut errors: Option<&mut Vec<Error>>,
+) -> Result<(), Error> {
+    let mut input = Counted {
+        inner: input,
+        count: 0,
+    };
+    let mut buf = Vec::new();
+
+    loop {
+        processor.offset = input.count;
+        let complete = read_line_limited(&mut input, &mut buf)?;
+        if buf.is_empty() {
+            break;
+        }
+        if output
+            .cancel
+            .is_some_and(|cancel| cancel.load(atomic::Ordering::Relaxed))
+        {
+            return Err(Error::Cancelled);
+        }
+        let result = if complete || !processor.can_stream(&buf, context) {
+            if !complete {
+                input.read_until(b'\n', &mut buf)?;
+            }
+            let line = std::str::from_utf8(&buf).map_err(invalid_utf8)?;
+            processor.process_line(line, output, context, &mut errors)
+        } else {
+            processor.stream_line(&mut input, &mut buf, output, context)
+        };
+        match (result, errors.as_mut()) {
+            (Err(e), Some(errors)) if e.is_recoverable() => errors.push(e),
+            (result, _) => result?,
+        }
+        output.check_size(processor.line)?;
+        buf.clear();
+    }
+    processor.finish()
+}
+
+// A reader which counts the bytes consumed from it
+struct Counted<I> {
+    inner: I,
+    count: usize,
+}
+
+impl<I: Read> Read for Counted<I> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.inner.read(buf)?;
+        self.count += len;
+        Ok(len)
+    }
+}
+
+impl<I: BufRead> BufRead for Counted<I> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        self.inner.fill_buf()
+    }
+    fn consume(&mut self, amt: usize) {
+        self.count += amt;
+        self.inner.consume(amt);
+    }
+}
+
+// Reads up to the end of the next line, unless `buf` reaches `LONG_LINE` bytes first. Returns
+// whether the whole line was read.
+fn read_line_limited<I: BufRead>(input: &mut I, buf: &mut Vec<u8>) -> io::Result<bool> {
+    loop {
+        let available = input.fill_buf()?;
+        if available.is_empty() {
+            return Ok(true);
+        }
+        let (len, complete) = match available.iter().position(|&b| b == b'\n') {
+            Some(index) => (index + 1, true),
+            None => (available.len(), false),
+        };
+        buf.extend_from_slice(&available[..len]);
+        input.consume(len);
+        if complete {
+            return Ok(true);
+        }
+        if buf.len() >= LONG_LINE {
+            return Ok(false);
+        }
+    }
+}
+
+fn invalid_utf8(e: std::str::Utf8Error) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
+
+// The conditional state of a single file being preprocessed
+struct Processor {
+    stack: Vec<State>,
+    state: State,
+    // Macros saved by `#push_defs`
+    def_stack: Vec<Arc<BTreeMap<String, Macro>>>,
+    // For each open `#if` block, the macros to restore at its `#endif`, with
+    // `block_scoped_defines`
+    block_defs: Vec<Option<Arc<BTreeMap<String, Macro>>>>,
+    // For each open conditional block, the indentation of its directive, and the extra
+    // indentation to remove from its content once that's known
+    dedent: Vec<(String, Option<String>)>,
+    line: u32,
+    depth: usize,
+    // The result of each condition which is evaluated, by line, if they're being collected
+    conditions: Option<BTreeMap<u32, bool>>,
+    // The nesting depths of the conditional blocks being passed through to the output
+    passthrough: Vec<usize>,
+    // The line of the `#if` directive starting each open conditional block
+    open_lines: Vec<u32>,
+    // Whether each open conditional block has reached its `#else` or `#default` branch
+    else_seen: Vec<bool>,
+    // The `#repeat` block whose lines are being collected
+    repeat: Option<Repeat>,
+    // The open `#switch` blocks
+    switches: Vec<Switch>,
+    // Whether the input is outside the markers set by `gated_by_markers`
+    outside_markers: bool,
+    // Where each directive is in the input, if they're being collected
+    spans: Option<Vec<DirectiveSpan>>,
+    // The byte offset of the current line in the input
+    offset: usize,
+}
+
+struct Switch {
+    // The nesting depth of the block
+    depth: usize,
+    // The value compared against each case, if the block is active
+    value: Option<Value>,
+}
+
+struct Repeat {
+    // The line of the `#repeat` directive
+    line: u32,
+    count: u64,
+    // The number of nested `#repeat` blocks open within the lines collected so far
+    nested: usize,
+    lines: Vec<String>,
+}
+
+impl Processor {
+    fn new(depth: usize) -> Self {
+        Processor {
+            stack: Vec::new(),
+            state: State::Active,
+            def_stack: Vec::new(),
+            block_defs: Vec::new(),
+            dedent: Vec::new(),
+            line: 0,
+            depth,
+            conditions: None,
+            passthrough: Vec::new(),
+            open_lines: Vec::new(),
+            else_seen: Vec::new(),
+            repeat: None,
+            switches: Vec::new(),
+            outside_markers: false,
+            spans: None,
+            offset: 0,
+        }
+    }
+    // Checks that the input didn't end part way through a block
+    fn finish(&mut self) -> Result<(), Error> {
+        match self.repeat.take() {
+            Some(repeat) => Err(Error::Syntax {
+                line: repeat.line,
+                msg: "Expected `#endrepeat` before the end of the input",
+            }),
+            None => Ok(()),
+        }
+    }
+    // Collects a line within a `#repeat` block, and writes the block out once it's complete
+    fn collect_repeat<O: Write>(
+        &mut self,
+        buf: &str,
+        output: &mut Output<O>,
+        context: &mut Context,
+        errors: &mut Option<&mut Vec<Error>>,
+    ) -> Result<(), Error> {
+        {
+            let repeat = self.repeat.as_mut().expect("A `#repeat` block is open");
+            let names = &context.directive_names;
+            let name = if names.is_directive(buf) {
+                names.canonical(split_directive(buf.trim()).0)
+            } else {
+                None
+            };
+            let complete = match name {
+                Some("#repeat") => {
+                    repeat.nested += 1;
+                    false
+                }
+                Some("#endrepeat") if repeat.nested == 0 => true,
+                Some("#endrepeat") => {
+                    repeat.nested -= 1;
+                    false
+                }
+                _ => false,
+            };
+            if !complete {
+                repeat.lines.push(buf.into());
+                return Ok(());
+            }
+        }
+        let repeat = self.repeat.take().expect("A `#repeat` block is open");
+        if output.annotate_directives && self.state == State::Active && !output.inverse {
+            output.write_line(buf)?;
+        }
+        let end = self.line;
+        // Replayed lines are already recorded where they are in the input
+        let spans = self.spans.take();
+        let outer = context.defs.get(REPEAT_INDEX).cloned();
+        let depth = self.stack.len();
+        let mut result = Ok(());
+        'iterations: for index in 0..repeat.count {
+            if output.exceeded {
+                break;
+            }
+            // The index is defined even when its name is forbidden, as it's built in
+            let index = Macro {
+                def: Def::Text(index.to_string()),
+                source: None,
+            };
+            Arc::make_mut(&mut context.defs).insert(REPEAT_INDEX.into(), index);
+            context.regex = None;
+            self.line = repeat.line;
+            for text in &repeat.lines {
+                match (
+                    self.process_line(text, output, context, errors),
+                    errors.as_mut(),
+                ) {
+                    (Err(e), Some(errors)) if e.is_recoverable() => errors.push(e),
+                    (Err(e), _) => {
+                        result = Err(e);
+                        break 'iterations;
+                    }
+                    (Ok(()), _) => {}
+                }
+            }
+            if self.stack.len() != depth {
+                result = Err(Error::Syntax {
+                    line: repeat.line,
+                    msg: "Conditional blocks must end within the `#repeat` block",
+                });
+                break;
+            }
+        }
+        match outer {
+            Some(def) => {
+                Arc::make_mut(&mut context.defs).insert(REPEAT_INDEX.into(), def);
+            }
+            None => {
+                Arc::make_mut(&mut context.defs).remove(REPEAT_INDEX);
+            }
+        }
+        context.regex = None;
+        self.line = end;
+        self.spans = spans;
+        result
+    }
+    // Removes the extra indentation of content within each open conditional block, which is
+    // found from the first non-blank line of the block's current branch
+    fn dedent<'t>(&mut self, text: &'t str) -> Cow<'t, str> {
+        let mut text = Cow::Borrowed(text);
+        for &mut (ref indent, ref mut extra) in &mut self.dedent {
+            if !text.starts_with(indent.as_str()) {
+                break;
+            }
+            let dedented = {
+                let rest = &text[indent.len()..];
+                if extra.is_none() {
+                    if rest.trim().is_empty() {
+                        break;
+                    }
+                    *extra = Some(leading_whitespace(rest).to_string());
+                }
+                let extra = extra.as_ref().expect("Extra indentation is known");
+                if extra.is_empty() || !rest.starts_with(extra.as_str()) {
+                    continue;
+                }
+                format!("{}{}", indent, &rest[extra.len()..])
+            };
+            text = Cow::Owned(dedented);
+        }
+        text
+    }
+    fn condition(
+        &mut self,
+        context: &Context,
+        directive: &str,
+        expr: &str,
+        line: u32,
+    ) -> Result<Option<bool>, Error> {
+        let result = context.condition(directive, expr, line, context.cpp_compat)?;
+        if let (Some(ref mut conditions), Some(result)) = (self.conditions.as_mut(), result) {
+            conditions.insert(line, result);
+        }
+        Ok(result)
+    }
+    // Whether the start of a long line can be written out before the rest has been read. The line
+    // mustn't be a directive, or become one when macros are expanded, and there mustn't be any
+    // function-like macros, whose arguments could be split between pieces, or macros expanding
+    // to several lines, which could be directives, or pattern macros, which could match across
+    // pieces.
+    fn can_stream(&self, start: &[u8], context: &Context) -> bool {
+        let start = String::from_utf8_lossy(start);
+        let start = start.trim_start();
+        let word = match start.find(|c| !context.is_identifier_char(c)) {
+            Some(index) => &start[..index],
+            None => start,
+        };
+        // A byte order mark is left to `process_line`
+        self.repeat.is_none()
+            && context.patterns.is_empty()
+            && (self.line > 0 || !start.starts_with(BOM))
+            && (context.markers.is_none() || self.line > 0 && !self.outside_markers)
+            && !context
+                .directive_names
+                .is_directive(context.strip_comment_prefix(start))
+            && (context.no_directive_injection
+                || context.expand_macro(word).is_none_or(|value| {
+                    let value = value.trim_start();
+                    !value.is_empty() && !context.directive_names.is_directive(value)
+                }))
+            && !context
+                .defs
+                .values()
+                .any(|m| matches!(m.def, Def::Function { .. }) || m.def.value().contains('\n'))
+    }
+    // Processes a long content line in pieces, splitting it between macro names, so that only
+    // part of it is held in memory at once. `buf` holds the start of the line.
+    fn stream_line<I: BufRead, O: Write>(
+        &mut self,
+        input: &mut I,
+        buf: &mut Vec<u8>,
+        output: &mut Output<O>,
+        context: &mut Context,
+    ) -> Result<(), Error> {
+        let line = self.line + 1;
+        let write = (self.state == State::Active) != output.inverse;
+        let mut first = true;
+        while !read_line_limited(input, buf)? {
+            let valid = match std::str::from_utf8(buf) {
+                Ok(text) => text.len(),
+                // A character may be split at the end of the buffer
+                Err(e) if e.error_len().is_none() => e.valid_up_to(),
+                Err(e) => return Err(invalid_utf8(e).into()),
+            };
+            let text = std::str::from_utf8(&buf[..valid]).expect("Text is valid");
+            let split = match text.rfind(|c| !context.is_identifier_char(c)) {
+                Some(index) => index + text[index..].chars().next().map_or(1, char::len_utf8),
+                None => continue,
+            };
+            if write {
+                let mut piece = context.expand_line(&text[..split], line)?;
+                if first && context.dedent_conditional_blocks {
+                    piece = Cow::Owned(self.dedent(&piece).into_owned());
+                }
+                output.write_piece(&piece)?;
+            }
+            first = false;
+            buf.drain(..split);
+        }
+        self.line = line;
+        if write {
+            let rest = std::str::from_utf8(buf).map_err(invalid_utf8)?;
+            output.write_line(&context.expand_line(rest, line)?)?;
+        }
+        Ok(())
+    }
+    // Whether the innermost conditional block has reached its `#else` or `#default` branch
+    fn after_else(&self) -> bool {
+        self.else_seen.last() == Some(&true)
+    }
+    // Whether the innermost conditional block is a `#switch` block
+    fn in_switch(&self) -> bool {
+        self.switches
+            .last()
+            .is_some_and(|switch| switch.depth == self.stack.len())
+    }
+    // Whether the innermost conditional block is being passed through to the output
+    fn passing_through(&self) -> bool {
+        self.passthrough.last() == Some(&self.stack.len())
+    }
+    fn process_line<O: Write>(
+        &mut self,
+        buf: &str,
+        output: &mut Output<O>,
+        context: &mut Context,
+        errors: &mut Option<&mut Vec<Error>>,
+    ) -> Result<(), Error> {
+        self.line += 1;
+        let line = self.line;
+        if self.depth == 0 {
+            output.line = line;
+        }
+        let len = buf.len();
+        let buf = match buf.strip_prefix(BOM) {
+            Some(rest) if line == 1 => {
+                if !context.strip_bom && self.depth == 0 {
+                    output.write_piece(BOM)?;
+                }
+                rest
+            }
+            _ => buf,
+        };
+        if let Some((ref on, ref off)) = context.markers {
+            if line == 1 && self.depth == 0 {
+                self.outside_markers = true;
+            }
+            if self.outside_markers {
+                if buf.trim() == on {
+                    self.outside_markers = false;
+                } else if !output.inverse {
+                    output.write_line(buf)?;
+                }
+                return Ok(());
+            } else if buf.trim() == off && self.depth == 0 {
+                self.outside_markers = true;
+                return Ok(());
+            }
+        }
+        let buf = context.strip_comment_prefix(buf);
+        if let Some(ref mut spans) = self.spans {
+            let names = &context.directive_names;
+            let keyword = split_directive(buf.trim()).0;
+            if names.is_directive(buf) && context.is_recognized(keyword) {
+                if let Some(name) = names.canonical(keyword) {
+                    let start = self.offset + len - buf.trim_start().len();
+                    spans.push(DirectiveSpan {
+                        line,
+                        name: name.into(),
+                        start,
+                        end: start + buf.trim().len(),
+                    });
+                }
+            }
+        }
+        if self.repeat.is_some() {
+            return self.collect_repeat(buf, output, context, errors);
+        }
+
+        // Directive expressions are expanded as they are evaluated. Other lines are expanded
+        // first, and are treated as directives if the result starts like one, unless the context
+        // prevents that.
+        let directive = context.directive_names.is_directive(buf);
+        let new_line = if directive {
+            Cow::Borrowed(buf)
+        } else {
+            context.expand_line(buf, line)?
+        };
+        // Each line of a multi-line expansion is processed in turn, as if it were in the input
+        if !directive && new_line.trim_end_matches(['\r', '\n']).contains('\n') {
+            for text in new_line.split_inclusive('\n') {
+                if self.repeat.is_some() {
+                    self.collect_repeat(text, output, context, errors)?;
+                } else {
+                    self.process_expanded(text, false, output, context, errors)?;
+                }
+            }
+            return Ok(());
+        }
+        self.process_expanded(&new_line, directive, output, context, errors)
+    }
+    // Processes a line after any macros in it have been expanded. `directive` is whether the line
+    // was a directive before expansion.
+    fn process_expanded<O: Write>(
+        &mut self,
+        new_line: &str,
+        directive: bool,
+        output: &mut Output<O>,
+        context: &mut Context,
+        errors: &mut Option<&mut Vec<Error>>,
+    ) -> Result<(), Error> {
+        let line = self.line;
+        let substr = new_line.trim();
+        let is_directive = context.directive_names.is_directive(substr);
+        let unrecognized = is_directive && !context.is_recognized(split_directive(substr).0);
+        if !is_directive || (!directive && context.no_directive_injection) || unrecognized {
+            if (self.state == State::Active) != output.inverse {
+                if context.dedent_conditional_blocks {
+                    output.write_line(&self.dedent(new_line))?;
+                } else {
+                    output.write_line(new_line)?;
+                }
+            }
+            return Ok(());
+        }
+
+        let (keyword, maybe_expr) = split_directive(substr);
+        // Directives are matched by their default names, and anything else is reported as
+        // unrecognised below
+        let name = context.directive_names.canonical(keyword).unwrap_or("");
+        // `#ifdef NAME` and the like are shorthand for conditions using `defined`. An operand
+        // which isn't a single name is reported once the condition would be evaluated.
+        let defined_condition;
+        let (name, maybe_expr, operand_error) = match name {
+            "#ifdef" | "#ifndef" | "#elifdef" | "#elifndef" => {
+                let negate = if name.ends_with("ndef") { "!" } else { "" };
+                defined_condition = maybe_expr.map(|expr| format!("{}defined({})", negate, expr));
+                let operand_error = match maybe_expr {
+                    Some(expr) if !context.is_identifier(expr) => Some(match name {
+                        "#ifdef" => "Expected a single macro name after `#ifdef`",
+                        "#ifndef" => "Expected a single macro name after `#ifndef`",
+                        "#elifdef" => "Expected a single macro name after `#elifdef`",
+                        _ => "Expected a single macro name after `#elifndef`",
+                    }),
+                    _ => None,
+                };
+                let name = if name.starts_with("#if") {
+                    "#if"
+                } else {
+                    "#elif"
+                };
+                (name, defined_condition.as_deref(), operand_error)
+            }
+            _ => (name, maybe_expr, None),
+        };
+
+        // A conditional directive is part of the block containing its own block
+        let enclosing = match name {
+            "#elif" | "#else" | "#endif" | "#case" | "#default" | "#endswitch" => {
+                self.stack.last().cloned().unwrap_or(State::Active)
+            }
+            _ => self.state,
+        };
+        // Directives are written out with their comments as they are, unless the context says
+        // to expand them
+        let written = match comment_start(new_line) {
+            Some(index)
+                if directive
+                    && context.expand_directive_comments
+                    && (output.annotate_directives
+                        || context.undefined_mode == UndefinedMode::Passthrough) =>
+            {
+                Cow::Owned(format!(
+                    "{}{}",
+                    &new_line[..index],
+                    context.expand(&new_line[index..], line)?
+                ))
+            }
+            _ => Cow::Borrowed(new_line),
+        };
+        if output.annotate_directives && enclosing == State::Active && !output.inverse {
+            output.write_line(&written)?;
+        }
+
+        if self.in_switch() && (name == "#elif" || name == "#else" || name == "#endif") {
+            return Err(Error::Syntax {
+                line,
+                msg: "Expected `#case`, `#default` or `#endswitch` within `#switch`",
+            });
+        }
+
+        // State changes are made before anything which can fail, so that the nesting of
+        // conditional blocks is still tracked when errors are skipped over. A condition which
+        // fails to evaluate skips the rest of its block.
+        match name {
+            "#if" => {
+                if context.dedent_conditional_blocks {
+                    let indent = leading_whitespace(&self.dedent(new_line)).to_string();
+                    self.dedent.push((indent, None));
+                }
+                let outer = self.state;
+                self.stack.push(outer);
+                self.open_lines.push(line);
+                self.else_seen.push(false);
+                let scoped = context.block_scoped_defines && outer == State::Active;
+                self.block_defs.push(scoped.then(|| context.defs.clone()));
+                self.state = State::Skip;
+                let expr = maybe_expr.ok_or(Error::Syntax {
+                    line,
+                    msg: "Expected expression after `#if`",
+                })?;
+                if let Some(msg) = operand_error {
+                    if outer == State::Active || context.validate_inactive_branches {
+                        return Err(Error::Syntax { line, msg });
+                    }
+                }
+                if outer == State::Active {
+                    self.state = match self.condition(context, name, expr, line)? {
+                        Some(true) => State::Active,
+                        Some(false) => State::Inactive,
+                        None => {
+                            self.passthrough.push(self.stack.len());
+                            output.write_directive(&written)?;
+                            State::Active
+                        }
+                    };
+                } else if context.validate_inactive_branches {
+                    context.validate(expr, line)?;
+                }
+            }
+            "#elif" => {
+                if self.after_else() {
+                    return Err(Error::Syntax {
+                        line,
+                        msg: "Unexpected `#elif` after `#else`",
+                    });
+                }
+                if let Some(top) = self.dedent.last_mut() {
+                    top.1 = None;
+                }
+                if self.passing_through() {
+                    output.write_directive(&written)?;
+                    return Ok(());
+                }
+                let inactive = self.state == State::Inactive;
+                self.state = State::Skip;
+                let expr = maybe_expr.ok_or(Error::Syntax {
+                    line,
+                    msg: "Expected expression after `#elif`",
+                })?;
+                if let Some(msg) = operand_error {
+                    if inactive || context.validate_inactive_branches {
+                        return Err(Error::Syntax { line, msg });
+                    }
+                }
+                if inactive {
+                    self.state = match self.condition(context, name, expr, line)? {
+                        Some(true) => State::Active,
+                        Some(false) => State::Inactive,
+                        None => {
+                            // The earlier branches are left out, so this one starts the block
+                            self.passthrough.push(self.stack.len());
+                            output.write_directive(&written.replacen(
+                                keyword,
+                                &context.directive_names.if_,
+                                1,
+                            ))?;
+                            State::Active
+                        }
+                    };
+                } else if context.validate_inactive_branches {
+                    context.validate(expr, line)?;
+                }
+            }
+            "#else" => {
+                if self.after_else() {
+                    return Err(Error::Syntax {
+                        line,
+                        msg: "Unexpected `#else` after `#else`",
+                    });
+                }
+                if let Some(seen) = self.else_seen.last_mut() {
+                    *seen = true;
+                }
+                if let Some(top) = self.dedent.last_mut() {
+                    top.1 = None;
+                }
+                if self.passing_through() {
+                    output.write_directive(&written)?;
+                    return Ok(());
+                }
+                self.state = if self.state == State::Inactive {
+                    State::Active
+                } else {
+                    State::Skip
+                };
+                if maybe_expr.is_some() {
+                    return Err(Error::Syntax {
+                        line,
+                        msg: "Unexpected expression after `#else`",
+                    });
+                }
+            }
+            "#endif" => {
+                if self.passing_through() {
+                    self.passthrough.pop();
+                    output.write_directive(&written)?;
+                }
+                self.state = self.stack.pop().ok_or(Error::Syntax {
+                    line,
+                    msg: "Unexpected `#endif` with no matching `#if`",
+                })?;
+                self.open_lines.pop();
+                self.else_seen.pop();
+                self.dedent.pop();
+                if let Some(defs) = self.block_defs.pop().flatten() {
+                    context.defs = defs;
+                    context.regex = None;
+                }
+                if maybe_expr.is_some() {
+                    return Err(Error::Syntax {
+                        line,
+                        msg: "Unexpected expression after `#endif`",
+                    });
+                }
+            }
+            "#switch" => {
+                if context.dedent_conditional_blocks {
+                    let indent = leading_whitespace(&self.dedent(new_line)).to_string();
+                    self.dedent.push((indent, None));
+                }
+                let outer = self.state;
+                self.stack.push(outer);
+                self.open_lines.push(line);
+                self.else_seen.push(false);
+                self.switches.push(Switch {
+                    depth: self.stack.len(),
+                    value: None,
+                });
+                self.state = State::Skip;
+                let expr = maybe_expr.ok_or(Error::Syntax {
+                    line,
+                    msg: "Expected expression after `#switch`",
+                })?;
+                if outer == State::Active {
+                    let value = context.parse(expr, line, true)?.ok_or(Error::Syntax {
+                        line,
+                        msg: "Undefined identifier in `#switch` which can't be passed through",
+                    })?;
+                    context.trace(line, format_args!("{} {} -> {}", name, expr, value))?;
+                    self.switches
+                        .last_mut()
+                        .expect("Block was just opened")
+                        .value = Some(value);
+                    self.state = State::Inactive;
+                } else if context.validate_inactive_branches {
+                    context.validate(expr, line)?;
+                }
+            }
+            "#case" => {
+                if !self.in_switch() {
+                    return Err(Error::Syntax {
+                        line,
+                        msg: "Unexpected `#case` with no matching `#switch`",
+                    });
+                }
+                if self.after_else() {
+                    return Err(Error::Syntax {
+                        line,
+                        msg: "Unexpected `#case` after `#default`",
+                    });
+                }
+                if let Some(top) = self.dedent.last_mut() {
+                    top.1 = None;
+                }
+                let inactive = self.state == State::Inactive;
+                self.state = State::Skip;
+                let expr = maybe_expr.ok_or(Error::Syntax {
+                    line,
+                    msg: "Expected value after `#case`",
+                })?;
+                if inactive {
+                    let value = context.parse(expr, line, true)?.ok_or(Error::Syntax {
+                        line,
+                        msg: "Undefined identifier in `#case` which can't be passed through",
+                    })?;
+                    let switch = self.switches.last().and_then(|s| s.value.clone());
+                    let operands = context.fold_case(switch.expect("Switch is active"), value);
+                    let matched = match (&operands.0, &operands.1) {
+                        (Value::Int(lhs), Value::Int(rhs)) => lhs == rhs,
+                        (Value::Str(lhs), Value::Str(rhs)) => lhs == rhs,
+                        _ => {
+                            return Err(Error::Syntax {
+                                line,
+                                msg: "Cannot compare a string with an integer",
+                            })
+                        }
+                    };
+                    context.trace(line, format_args!("{} {} -> {}", name, expr, matched))?;
+                    if let Some(ref mut conditions) = self.conditions {
+                        conditions.insert(line, matched);
+                    }
+                    self.state = if matched {
+                        State::Active
+                    } else {
+                        State::Inactive
+                    };
+                } else if context.validate_inactive_branches {
+                    context.validate(expr, line)?;
+                }
+            }
+            "#default" => {
+                if !self.in_switch() {
+                    return Err(Error::Syntax {
+                        line,
+                        msg: "Unexpected `#default` with no matching `#switch`",
+                    });
+                }
+                if self.after_else() {
+                    return Err(Error::Syntax {
+                        line,
+                        msg: "Unexpected `#default` after `#default`",
+                    });
+                }
+                *self.else_seen.last_mut().expect("Switch is open") = true;
+                if let Some(top) = self.dedent.last_mut() {
+                    top.1 = None;
+                }
+                self.state = if self.state == State::Inactive {
+                    State::Active
+                } else {
+                    State::Skip
+                };
+                if maybe_expr.is_some() {
+                    return Err(Error::Syntax {
+                        line,
+                        msg: "Unexpected expression after `#default`",
+                    });
+                }
+            }
+            "#endswitch" => {
+                if !self.in_switch() {
+                    return Err(Error::Syntax {
+                        line,
+                        msg: "Unexpected `#endswitch` with no matching `#switch`",
+                    });
+                }
+                let switch = self.switches.pop().expect("Switch is open");
+                let unmatched = switch.value.is_some() && self.state == State::Inactive;
+                self.state = self.stack.pop().expect("Switch is open");
+                let start = self.open_lines.pop().expect("Switch is open");
+                self.else_seen.pop();
+                self.dedent.pop();
+                if unmatched && context.require_switch_default {
+                    return Err(Error::Syntax {
+                        line: start,
+                        msg: "Unmatched #switch with no default",
+                    });
+                }
+                if maybe_expr.is_some() {
+                    return Err(Error::Syntax {
+                        line,
+                        msg: "Unexpected expression after `#endswitch`",
+                    });
+                }
+            }
+            "#define" => {
+                // The value is taken from the line itself, as the expression has been trimmed
+                let rest = new_line.trim_start().trim_end_matches(['\r', '\n']);
+                let rest = &rest[keyword.len()..];
+                let expr = if context.define_comments {
+                    &rest[..comment_start(rest).unwrap_or(rest.len())]
+                } else {
+                    rest
+                };
+                let expr = expr.trim_start();
+                if expr.trim_end().is_empty() {
+                    return Err(Error::Syntax {
+                        line,
+                        msg: "Expected macro name after `#define`",
+                    });
+                }
+                let len = expr
+                    .find(|c| !context.is_identifier_char(c))
+                    .unwrap_or(expr.len());
+                let (name, rest) = expr.split_at(len);
+                if rest.starts_with('(') {
+                    // A parenthesis straight after the name starts a function-like macro
+                    let close = rest.find(')').ok_or(Error::Syntax {
+                        line,
+                        msg: "Expected `)` after macro parameters",
+                    })?;
+                    let mut params: Vec<String> =
+                        rest[1..close].split(',').map(|p| p.trim().into()).collect();
+                    if params == [""] {
+                        params.clear();
+                    }
+                    let last = params.len().saturating_sub(1);
+                    let valid = params.iter().enumerate().all(|(index, param)| {
+                        context.is_identifier(param) || index == last && param == VARIADIC
+                    });
+                    if !valid {
+                        return Err(Error::Syntax {
+                            line,
+                            msg: "Invalid macro parameter",
+                        });
+                    }
+                    if self.state == State::Active {
+                        context.check_macro_name(name, line, false)?;
+                        context.check_unfrozen(line)?;
+                        let body = value_after_separator(&rest[close + 1..]);
+                        let body = context.define_value(context.define_trim.apply(body), line)?;
+                        context.define_fn(name, params, body);
+                    }
+                } else if rest.is_empty() || rest.starts_with(char::is_whitespace) {
+                    if self.state == State::Active {
+                        context.check_macro_name(name, line, false)?;
+                        context.check_unfrozen(line)?;
+                        let value = value_after_separator(rest);
+                        let value = context.define_value(context.define_trim.apply(value), line)?;
+                        context.define(name, value);
+                    }
+                } else {
+                    return Err(Error::Syntax {
+                        line,
+                        msg: "Invalid macro name",
+                    });
+                }
+            }
+            "#set" => {
+                let expr = maybe_expr.ok_or(Error::Syntax {
+                    line,
+                    msg: "Expected macro name after `#set`",
+                })?;
+                let (name, value) = expr.split_at(expr.find('=').ok_or(Error::Syntax {
+                    line,
+                    msg: "Expected `=` after macro name",
+                })?);
+                let name = name.trim();
+                if self.state == State::Active {
+                    context.check_macro_name(name, line, false)?;
+                    context.check_unfrozen(line)?;
+                    match context.parse(&value[1..], line, true)? {
+                        Some(value) => {
+                            let value = match value {
+                                // Strings are quoted, so that they're still strings when used
+                                Value::Str(value) => quote(&value),
+                                value => value.to_string(),
+                            };
+                            context.define(name, value);
+                        }
+                        None => output.write_directive(&written)?,
+                    }
+                }
+            }
+            "#undef" => {
+                let name = maybe_expr.ok_or(Error::Syntax {
+                    line,
+                    msg: "Expected macro name after `#undef`",
+                })?;
+                if self.state == State::Active {
+                    context.check_macro_name(name, line, true)?;
+                    context.check_unfrozen(line)?;
+                    context.undefine(name);
+                }
+            }
+            "#push_defs" => {
+                if maybe_expr.is_some() {
+                    return Err(Error::Syntax {
+                        line,
+                        msg: "Unexpected expression after `#push_defs`",
+                    });
+                }
+                if self.state == State::Active {
+                    self.def_stack.push(context.defs.clone());
+                }
+            }
+            "#pop_defs" => {
+                if maybe_expr.is_some() {
+                    return Err(Error::Syntax {
+                        line,
+                        msg: "Unexpected expression after `#pop_defs`",
+                    });
+                }
+                if self.state == State::Active {
+                    context.check_unfrozen(line)?;
+                    let defs = self.def_stack.pop().ok_or(Error::Syntax {
+                        line,
+                        msg: "Unexpected `#pop_defs` with no matching `#push_defs`",
+                    })?;
+                    context.defs = defs;
+                    context.regex = None;
+                }
+            }
+            "#repeat" => {
+                let expr = maybe_expr.ok_or(Error::Syntax {
+                    line,
+                    msg: "Expected count after `#repeat`",
+                })?;
+                // The block is still collected when it fails to evaluate, and then left out
+                self.repeat = Some(Repeat {
+                    line,
+                    count: 0,
+                    nested: 0,
+                    lines: Vec::new(),
+                });
+                if self.state == State::Active {
+                    let count = match context.parse(expr, line, true)? {
+                        Some(Value::Int(count)) => {
+                            u64::try_from(count).map_err(|_| Error::Syntax {
+                                line,
+                                msg: "Negative count after `#repeat`",
+                            })?
+                        }
+                        _ => {
+                            return Err(Error::Syntax {
+                                line,
+                                msg: "Expected integer count after `#repeat`",
+                            })
+                        }
+                    };
+                    self.repeat.as_mut().expect("Block was just opened").count = count;
+                } else if context.validate_inactive_branches {
+                    context.validate(expr, line)?;
+                }
+            }
+            "#endrepeat" => {
+                return Err(Error::Syntax {
+                    line,
+                    msg: "Unexpected `#endrepeat` with no matching `#repeat`",
+                });
+            }
+            "#include" => {
+                let expr = maybe_expr.ok_or(Error::Syntax {
+                    line,
+                    msg: "Expected file name after `#include`",
+                })?;
+                if self.state == State::Active {
+                    if self.depth >= MAX_INCLUDE_DEPTH {
+                        return Err(Error::Syntax {
+                            line,
+                            msg: "Too many nested `#include` directives",
+                        });
+                    }
+                    let (path, contents) = context.resolve_include(expr, line)?;
+                    if let Some(ref mut deps) = output.deps {
+                        if !deps.contains(&path) {
+                            deps.push(path.clone());
+                        }
+                    }
+                    let outer_name =
+                        mem::replace(&mut context.file_name, path.display().to_string());
+                    let result = process_file(
+                        contents.as_bytes(),
+                        output,
+                        context,
+                        self.depth + 1,
+                        errors.as_mut().map(|e| &mut **e),
+                    );
+                    context.file_name = outer_name;
+                    result?;
+                    output.end_line(new_line)?;
+                }
+            }
+            "#include_str" => {
+                let expr = maybe_expr.ok_or(Error::Syntax {
+                    line,
+                    msg: "Expected file name after `#include_str`",
+                })?;
+                if self.state == State::Active {
+                    let (path, contents) = context.resolve_include(expr, line)?;
+                    if let Some(ref mut deps) = output.deps {
+                        if !deps.contains(&path) {
+                            deps.push(path);
+                        }
+                    }
+                    if !output.inverse {
+                        for text in contents.split_inclusive('\n') {
+                            output.write_line(text)?;
+                        }
+                        output.end_line(new_line)?;
+                    }
+                }
+            }
+            "#assert" => {
+                let expr = maybe_expr.ok_or(Error::Syntax {
+                    line,
+                    msg: "Expected expression after `#assert`",
+                })?;
+                if self.state == State::Active {
+                    match context.condition(name, expr, line, false)? {
+                        Some(true) => {}
+                        Some(false) => {
+                            return Err(Error::Directive {
+                                line,
+                                msg: format!("Assertion failed: {}", expr),
+                            })
+                        }
+                        None => output.write_directive(&written)?,
+                    }
+                } else if context.validate_inactive_branches {
+                    context.validate(expr, line)?;
+                }
+            }
+            "#output" => {
+                let name = match maybe_expr {
+                    Some(expr) => match parse_quoted(expr) {
+                        Some((name, len)) if len == expr.len() => Some(name),
+                        _ => {
+                            return Err(Error::Syntax {
+                                line,
+                                msg: "Expected \"name\" after `#output`",
+                            })
+                        }
+                    },
+                    None => None,
+                };
+                if self.state == State::Active {
+                    let select = output.select.ok_or(Error::Syntax {
+                        line,
+                        msg: "No named outputs are set",
+                    })?;
+                    // Each output has its own last line
+                    let pending = output.pending_newline;
+                    output.unterminated = false;
+                    output.pending_newline = select(&mut output.inner, name.as_deref(), pending)
+                        .ok_or(Error::Syntax {
+                            line,
+                            msg: "Unknown output",
+                        })?;
+                }
+            }
+            // A directive with no name does nothing, as in C
+            _ if maybe_expr.is_none() && keyword.chars().count() == 1 => {}
+            _ => {
+                return Err(Error::Syntax {
+                    line,
+                    msg: "Unrecognised preprocessor directive",
+                });
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn get_macro() {
+        let mut context = Context::new();
+        context.define("FOO", "1");
+
+        let name = String::from("FOO");
+        assert_eq!(context.get_macro("FOO").unwrap(), "1");
+        assert_eq!(context.get_macro(&name).unwrap(), "1");
+        assert_eq!(context.get_macro(name).unwrap(), "1");
+        assert!(context.get_macro("BAR").is_none());
+    }
+
+    #[test]
+    fn pass_through() {
+        assert_eq!(
+            &process_str(
+                "
+            some
+            multiline
+            text
+            with # symbols
+        ",
+                &mut Context::new()
+            )
+            .unwrap(),
+            "
+            some
+            multiline
+            text
+            with # symbols
+        "
+        );
+    }
+
+    #[test]
+    fn variable() {
+        assert_eq!(
+            &process_str(
+                "
+            some
+            #if FOO
+            multiline
+            text
+            #endif
+            with # symbols
+        ",
+                Context::new().define("FOO", "0")
+            )
+            .unwrap(),
+            "
+            some
+            with # symbols
+        "
+        );
+
+        assert_eq!(
+            &process_str(
+                "
+            some
+            #if FOO
+            multiline
+            text
+            #endif
+            with # symbols
+        ",
+                Context::new().define("FOO", "1")
+            )
+            .unwrap(),
+            "
+            some
+            multiline
+            text
+            with # symbols
+        "
+        );
+    }
+
+    #[test]
+    fn constant() {
+        assert_eq!(
+            &process_str(
+                "
+            some
+            #if 0
+            multiline
+            text
+            #endif
+            with # symbols
+        ",
+                &mut Context::new()
+            )
+            .unwrap(),
+            "
+            some
+            with # symbols
+        "
+        );
+
+        assert_eq!(
+            &process_str(
+                "
+            some
+            #if 1
+            multiline
+            text
+            #endif
+            with # symbols
+        ",
+                &mut Context::new()
+            )
+            .unwrap(),
+            "
+            some
+            multiline
+            text
+            with # symbols
+        "
+        );
+    }
+
+    #[test]
+    fn negation() {
+        assert_eq!(
+            &process_str(
+                "
+            some
+            #if !FOO
+            multiline
+            text
+            #endif
+            with # symbols
+        ",
+                Context::new().define("FOO", "1")
+            )
+            .unwrap(),
+            "
+            some
+            with # symbols
+        "
+        );
+
+        assert_eq!(
+            &process_str(
+                "
+            some
+            #if !FOO
+            multiline
+            text
+            #endif
+            with # symbols
+        ",
+                Context::new().define("FOO", "0")
+            )
+            .unwrap(),
+            "
+            some
+            multiline
+            text
+            with # symbols
+        "
+        );
+    }
+
+    #[test]
+    fn else_() {
+        assert_eq!(
+            &process_str(
+                "
+            some
+            #if FOO
+            multiline
+            #else
+            text
+            #endif
+            with # symbols
+        ",
+                Context::new().define("FOO", "0")
+            )
+            .unwrap(),
+            "
+            some
+            text
+            with # symbols
+        "
+        );
+
+        assert_eq!(
+            &process_str(
+                "
+            some
+            #if FOO
+            multiline
+            #else
+            text
+            #endif
+            with # symbols
+        ",
+                Context::new().define("FOO", "1")
+            )
+            .unwrap(),
+            "
+            some
+            multiline
+            with # symbols
+        "
+        );
+    }
+
+    #[test]
+    fn elif() {
+        assert_eq!(
+            &process_str(
+                "
+            some
+            #if FOO
+            multiline
+            #elif 1
+            text
+            #endif
+            with # symbols
+        ",
+                Context::new().define("FOO", "0")
+            )
+            .unwrap(),
+            "
+            some
+            text
+            with # symbols
+        "
+        );
+
+        assert_eq!(
+            &process_str(
+                "
+            some
+            #if FOO
+            multiline
+            #elif 1
+            text
+            #endif
+            with # symbols
+        ",
+                Context::new().define("FOO", "1")
+            )
+            .unwrap(),
+            "
+            some
+            multiline
+            with # symbols
+        "
+        );
+
+        assert_eq!(
+            &process_str(
+                "
+            some
+            #if FOO
+            multiline
+            #elif 0
+            text
+            #endif
+            with # symbols
+        ",
+                Context::new().define("FOO", "0")
+            )
+            .unwrap(),
+            "
+            some
+            with # symbols
+        "
+        );
+
+        assert_eq!(
+            &process_str(
+                "
+            some
+            #if FOO
+            multiline
+            #elif 0
+            text
+            #else
+            with # symbols
+            #endif
+        ",
+                Context::new().define("FOO", "0")
+            )
+            .unwrap(),
+            "
+            some
+            with # symbols
+        "
+        );
+
+        assert_eq!(
+            &process_str(
+                "
+            some
+            #if FOO
+            multiline
+            #elif 1
+            text
+            #else
+            with # symbols
+            #endif
+        ",
+                Context::new().define("FOO", "0")
+            )
+            .unwrap(),
+            "
+            some
+            text
+        "
+        );
+    }
+
+    #[test]
+    fn equality() {
+        assert_eq!(
+            &process_str(
+                "
+            some
+            #if FOO == 1
+            multiline
+            text
+            #endif
+            with # symbols
+        ",
+                Context::new().define("FOO", "0")
+            )
+            .unwrap(),
+            "
+            some
+            with # symbols
+        "
+        );
+
+        assert_eq!(
+            &process_str(
+                "
+            some
+            #if FOO == 0
+            multiline
+            text
+            #endif
+            with # symbols
+        ",
+                Context::new().define("FOO", "0")
+            )
+            .unwrap(),
+            "
+            some
+            multiline
+            text
+            with # symbols
+        "
+        );
+    }
+
+    #[test]
+    fn expansion() {
+        assert_eq!(
+            &process_str(
+                "
+            some
+            FOO-BAR
+            multiline
+        ",
+                Context::new().define("FOO", "0")
+            )
+            .unwrap(),
+            "
+            some
+            0-BAR
+            multiline
+        "
+        );
+
+        assert_eq!(
+            &process_str(
+                "
+            some
+            FOO_BAR
+            multiline
+        ",
+                Context::new().define("FOO", "0")
+            )
+            .unwrap(),
+            "
+            some
+            FOO_BAR
+            multiline
+        "
+        );
+    }
+
+    #[test]
+    fn logical() {
+        assert_eq!(
+            &process_str(
+                "
+            #if FOO && !BAR
+            foo
+            #endif
+            #if BAR || FOO == 2
+            bar
+            #endif
+        ",
+                Context::new().define("FOO", "1").define("BAR", "0")
+            )
+            .unwrap(),
+            "
+            foo
+        "
+        );
+    }
+
+    #[test]
+    fn short_circuit() {
+        let text = "
+            #if defined(FOO) && FOO > 2
+            big
+            #endif
+            #if !defined FOO || FOO <= 2
+            small
+            #endif
+        ";
+
+        assert_eq!(
+            &process_str(text, &mut Context::new()).unwrap(),
+            "
+            small
+        "
+        );
+        assert_eq!(
+            &process_str(text, Context::new().define("FOO", "3")).unwrap(),
+            "
+            big
+        "
+        );
+        assert_eq!(
+            &process_str(text, Context::new().define("FOO", "1")).unwrap(),
+            "
+            small
+        "
+        );
+
+        // Without the guard, the undefined identifier is still an error
+        match process_str("#if 1 && FOO > 2\n#endif\n", &mut Context::new()) {
+            Err(Error::UndefinedIdentifier { line: 1, name }) => assert_eq!(name, "FOO"),
+            other => panic!("{:?}", other),
+        }
+
+        // The skipped operand must still be well-formed
+        match process_str("#if 0 && (FOO > \n#endif\n", &mut Context::new()) {
+            Err(Error::Syntax { line: 1, msg }) => assert_eq!(msg, "Expected term, found nothing"),
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn trim_trailing_newline() {
+        let mut context = Context::new();
+        context.trim_trailing_newline(true);
+
+        assert_eq!(
+            &process_str("foo\n\nbar\n", &mut context).unwrap(),
+            "foo\n\nbar"
+        );
+        assert_eq!(
+            &process_str("foo\n\nbar\n\n", &mut context).unwrap(),
+            "foo\n\nbar\n"
+        );
+        assert_eq!(
+            &process_str("foo\r\nbar\r\n", &mut context).unwrap(),
+            "foo\r\nbar"
+        );
+        assert_eq!(
+            &process_str("foo\n\nbar", &mut context).unwrap(),
+            "foo\n\nbar"
+        );
+        assert_eq!(
+            &process_str("foo\n#if 0\nbar\n#endif\n", &mut context).unwrap(),
+            "foo"
+        );
+        assert_eq!(&process_str("", &mut context).unwrap(), "");
+    }
+
+    #[test]
+    fn define() {
+        assert_eq!(
+            &process_str(
+                "
+            #define FOO 1
+            #define BAR FOO // the value of BAR is looked up when it's used
+            #if BAR
+            FOO BAR
+            #endif
+            #undef FOO
+            #if !defined(FOO)
+            FOO
+            #endif
+        ",
+                &mut Context::new()
+            )
+            .unwrap(),
+            "
+            1 FOO
+            FOO
+        "
+        );
+
+        match process_str("#define 1FOO\n", &mut Context::new()) {
+            Err(Error::Syntax { line: 1, msg }) => assert_eq!(msg, "Invalid macro name"),
+            other => panic!("{:?}", other),
+        }
+    }
+
+    fn test_resolver(context: &mut Context) -> &mut Context {
+        context.include_resolver(|name, kind| {
+            let contents = match (name, kind) {
+                ("config.h", IncludeKind::Quoted) => "#define HAVE_CONFIG 1\nconfig\n",
+                ("sys.h", IncludeKind::Angled) => "system\n",
+                ("sys.h", IncludeKind::Quoted) => "local\n",
+                ("both.h", IncludeKind::Quoted) => "#include <sys.h>\n#include \"config.h\"\n",
+                ("partial.h", IncludeKind::Quoted) => "hello",
+                ("guard.h", IncludeKind::Quoted) => "#ifndef GUARD\n#define GUARD\n#endif",
+                _ => return Ok(None),
+            };
+            Ok(Some((PathBuf::from(name), contents.to_string())))
+        })
+    }
+
+    #[test]
+    fn include() {
+        assert_eq!(
+            &process_str(
+                "#define HDR \"config.h\"\n#include HDR\n#if HAVE_CONFIG\nconfigured\n#endif\n",
+                test_resolver(&mut Context::new())
+            )
+            .unwrap(),
+            "config\nconfigured\n"
+        );
+        assert_eq!(
+            &process_str(
+                "#include \"config.h\"\n#include <sys.h>\n#include \"sys.h\"\n",
+                test_resolver(&mut Context::new())
+            )
+            .unwrap(),
+            "config\nsystem\nlocal\n"
+        );
+        assert_eq!(
+            &process_str(
+                "#define SYS <sys.h>\n#include SYS\n",
+                test_resolver(&mut Context::new())
+            )
+            .unwrap(),
+            "system\n"
+        );
+        // The last line of an included file is ended, even if the file doesn't end it
+        assert_eq!(
+            &process_str(
+                "#include \"partial.h\"\nworld\n#include \"guard.h\"\nend\n",
+                test_resolver(&mut Context::new())
+            )
+            .unwrap(),
+            "hello\nworld\nend\n"
+        );
+        assert_eq!(
+            &process_str(
+                "#include \"partial.h\"\r\nworld\r\n#include \"partial.h\"",
+                test_resolver(&mut Context::new())
+            )
+            .unwrap(),
+            "hello\r\nworld\r\nhello"
+        );
+
+        match process_str("#include <config.h>\n", test_resolver(&mut Context::new())) {
+            Err(Error::Syntax { line: 1, msg }) => assert_eq!(msg, "Included file not found"),
+            other => panic!("{:?}", other),
+        }
+        match process_str("#include config.h\n", test_resolver(&mut Context::new())) {
+            Err(Error::Syntax { line: 1, msg }) => {
+                assert_eq!(msg, "Expected \"file\" or <file> after `#include`")
+            }
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn empty_expression() {
+        let mut context = Context::new();
+        context.define("FOO", "").define("BAR", " ");
+
+        for text in &["#if FOO\n#endif\n", "#if FOO BAR\n#endif\n"] {
+            match process_str(text, &mut context) {
+                Err(Error::Syntax { line: 1, msg }) => {
+                    assert_eq!(msg, "Expression is empty after macro expansion")
+                }
+                other => panic!("{:?}", other),
+            }
+        }
+        match process_str("#if 1 && FOO\n#endif\n", &mut context) {
+            Err(Error::Syntax { line: 1, msg }) => {
+                assert_eq!(msg, "Expected term, found a macro which is empty")
+            }
+            other => panic!("{:?}", other),
+        }
+        match process_str("#if 1 &&\n#endif\n", &mut context) {
+            Err(Error::Syntax { line: 1, msg }) => assert_eq!(msg, "Expected term, found nothing"),
+            other => panic!("{:?}", other),
+        }
+        match process_str("#if\n#endif\n", &mut context) {
+            Err(Error::Syntax { line: 1, msg }) => {
+                assert_eq!(msg, "Expected expression after `#if`")
+            }
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[derive(Clone, Default)]
+    struct SharedBuf(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuf {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl SharedBuf {
+        fn contents(&self) -> String {
+            String::from_utf8(self.0.lock().unwrap().clone()).unwrap()
+        }
+    }
+
+    #[test]
+    fn trace() {
+        let trace = SharedBuf::default();
+        let mut context = Context::new();
+        context
+            .define("FOO", "0")
+            .set_trace(Box::new(trace.clone()));
+
+        assert_eq!(
+            &process_str("#if FOO == 1\none\n#elif !FOO\nFOO\n#endif\n", &mut context).unwrap(),
+            "0\n"
+        );
+        assert_eq!(
+            trace.contents(),
+            "line 1: #if FOO == 1 -> false\nline 3: #elif !FOO -> true\nline 4: expanded 'FOO' -> '0'\n"
+        );
+    }
+
+    #[test]
+    fn ternary() {
+        let text = "
+            #if FOO ? BAR == 2 : BAR == 3
+            match
+            #endif
+        ";
+
+        assert_eq!(
+            &process_str(text, Context::new().define("FOO", "1").define("BAR", "2")).unwrap(),
+            "
+            match
+        "
+        );
+        assert_eq!(
+            &process_str(text, Context::new().define("FOO", "0").define("BAR", "2")).unwrap(),
+            "
+        "
+        );
+        assert_eq!(
+            &process_str(text, Context::new().define("FOO", "0").define("BAR", "3")).unwrap(),
+            "
+            match
+        "
+        );
+
+        // The branch which isn't picked may refer to undefined macros
+        assert_eq!(
+            &process_str("#if 1 ? 1 : FOO\nyes\n#endif\n", &mut Context::new()).unwrap(),
+            "yes\n"
+        );
+        match process_str("#if 1 ? 1\n#endif\n", &mut Context::new()) {
+            Err(Error::Syntax { line: 1, msg }) => {
+                assert_eq!(msg, "Expected `:` in conditional expression")
+            }
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn collect_errors() {
+        let (output, errors) = process_collect_errors(
+            "
+            #if FOO
+            foo
+            #else
+            not foo
+            #endif
+            #if 1 ==
+            bad
+            #endif
+            good
+        "
+            .as_bytes(),
+            Vec::new(),
+            Context::new().define("BAR", "1"),
+        );
+
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "
+            good
+        "
+        );
+        let errors: Vec<_> = errors
+            .iter()
+            .map(|e| match *e {
+                Error::Syntax { line, msg } => (line, msg.to_string()),
+                Error::UndefinedIdentifier { line, ref name } => {
+                    (line, format!("Undefined identifier '{}'", name))
+                }
+                ref other => panic!("{:?}", other),
+            })
+            .collect();
+        assert_eq!(
+            errors,
+            vec![
+                (2, "Undefined identifier 'FOO'".to_string()),
+                (7, "Expected term, found nothing".to_string())
+            ]
+        );
+    }
+
+    #[test]
+    fn env_passthrough() {
+        let mut context = Context::new();
+        context.define_env_passthrough("VALUE", "MINIPRE_TEST_ENV_PASSTHROUGH");
+        assert!(context.get_macro("VALUE").is_none());
+
+        env::set_var("MINIPRE_TEST_ENV_PASSTHROUGH", "1");
+        assert_eq!(
+            &process_str("#if VALUE\nVALUE\n#endif\n", &mut context).unwrap(),
+            "1\n"
+        );
+
+        env::set_var("MINIPRE_TEST_ENV_PASSTHROUGH", "2");
+        assert_eq!(&process_str("VALUE\n", &mut context).unwrap(), "2\n");
+
+        env::remove_var("MINIPRE_TEST_ENV_PASSTHROUGH");
+        assert_eq!(
+            &process_str(
+                "[VALUE]\n#if defined(VALUE)\ndefined\n#endif\n",
+                &mut context
+            )
+            .unwrap(),
+            "[]\ndefined\n"
+        );
+    }
+
+    #[test]
+    fn unicode_identifiers() {
+        let text = "#define ñame 1\n#if ñame && défini\nñame défini\n#endif\n";
+
+        let mut context = Context::new();
+        context.define("défini", "2");
+        match process_str(text, &mut context) {
+            Err(Error::Syntax { line: 1, msg }) => assert_eq!(msg, "Invalid macro name"),
+            other => panic!("{:?}", other),
+        }
+        match process_str("#if défini\n#endif\n", &mut context) {
+            Err(Error::UndefinedIdentifier { line: 1, name }) => assert_eq!(name, "d"),
+            other => panic!("{:?}", other),
+        }
+
+        context.unicode_identifiers(true);
+        assert_eq!(&process_str(text, &mut context).unwrap(), "1 2\n");
+    }
+
+    #[test]
+    fn push_pop_defs() {
+        assert_eq!(
+            &process_str(
+                "
+            #push_defs
+            #define FOO 1
+            #undef BAR
+            FOO BAR
+            #pop_defs
+            FOO BAR
+        ",
+                Context::new().define("BAR", "2")
+            )
+            .unwrap(),
+            "
+            1 BAR
+            FOO 2
+        "
+        );
+
+        match process_str("#push_defs\n#pop_defs\n#pop_defs\n", &mut Context::new()) {
+            Err(Error::Syntax { line: 3, msg }) => {
+                assert_eq!(msg, "Unexpected `#pop_defs` with no matching `#push_defs`")
+            }
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn dedent_conditional_blocks() {
+        let text = "
+    fn main() {
+        #if FOO
+            foo();
+            #if BAR
+                bar();
+
+                baz();
+            #else
+              quux();
+            #endif
+        #endif
+        #if FOO
+\t\tfoo();
+    \t    mixed();
+        #endif
+    }
+";
+
+        let mut context = Context::new();
+        context.define("FOO", "1").define("BAR", "1");
+        assert_eq!(
+            &process_str(text, &mut context).unwrap(),
+            "
+    fn main() {
+            foo();
+                bar();
+
+                baz();
+\t\tfoo();
+    \t    mixed();
+    }
+"
+        );
+
+        context.dedent_conditional_blocks(true);
+        assert_eq!(
+            &process_str(text, &mut context).unwrap(),
+            "
+    fn main() {
+        foo();
+        bar();
+
+        baz();
+\t\tfoo();
+    \t    mixed();
+    }
+"
+        );
+
+        context.define("BAR", "0");
+        assert_eq!(
+            &process_str(text, &mut context).unwrap(),
+            "
+    fn main() {
+        foo();
+        quux();
+\t\tfoo();
+    \t    mixed();
+    }
+"
+        );
+    }
+
+    #[test]
+    fn treat_undefined_as_zero() {
+        let test = |expr: &str, context: &mut Context| {
+            process_str(&format!("#if {}\ntrue\n#endif\n", expr), context)
+                .map(|output| output == "true\n")
+        };
+
+        let mut context = Context::new();
+        match test("FOO == 1", &mut context) {
+            Err(Error::UndefinedIdentifier { line: 1, name }) => assert_eq!(name, "FOO"),
+            other => panic!("{:?}", other),
+        }
+
+        context.treat_undefined_as_zero(true);
+        assert!(!test("FOO", &mut context).unwrap());
+        assert!(test("!FOO", &mut context).unwrap());
+        assert!(!test("FOO == 1", &mut context).unwrap());
+        assert!(!test("FOO == 2", &mut context).unwrap());
+        assert!(test("FOO == 0", &mut context).unwrap());
+        assert!(test("FOO != 1", &mut context).unwrap());
+        assert!(test("FOO == BAR", &mut context).unwrap());
+        assert!(!test("defined(FOO) && FOO == 0", &mut context).unwrap());
+
+        context.define("FOO", "0");
+        assert!(test("defined(FOO) && FOO == 0", &mut context).unwrap());
+    }
+
+    #[test]
+    fn line_processor() {
+        let mut context = Context::new();
+        context.define("FOO", "1");
+
+        let mut processor = LineProcessor::new(Vec::new(), context);
+        for chunk in &[
+            "some\n#if FO",
+            "O\n",
+            "foo",
+            " FOO\n#end",
+            "if\nBAR\n#define BAR 2",
+        ] {
+            processor.write_all(chunk.as_bytes()).unwrap();
+        }
+        // Flushing leaves the incomplete final line to be continued
+        processor.flush().unwrap();
+        assert!(processor.context().get_macro("BAR").is_none());
+        processor.write_all(b"0\n").unwrap();
+        assert_eq!(processor.context().get_macro("BAR").unwrap(), "20");
+        assert_eq!(
+            String::from_utf8(processor.finish().unwrap()).unwrap(),
+            "some\nfoo 1\nBAR\n"
+        );
+
+        // A line and a character split by a flush are still processed whole
+        let mut context = Context::new();
+        context.define("FOO", "1");
+        let mut processor = LineProcessor::new(Vec::new(), context);
+        for chunk in &[&b"#if FO"[..], b"O\n\xc3", b"\xa9\n#endif\n"] {
+            processor.write_all(chunk).unwrap();
+            processor.flush().unwrap();
+        }
+        assert_eq!(
+            String::from_utf8(processor.finish().unwrap()).unwrap(),
+            "\u{e9}\n"
+        );
+
+        // The final line is also processed when the processor is dropped
+        let mut output = Vec::new();
+        {
+            let mut processor = LineProcessor::new(&mut output, Context::new());
+            io::copy(&mut "#if 1\none\n#endif\ntwo".as_bytes(), &mut processor).unwrap();
+        }
+        assert_eq!(String::from_utf8(output).unwrap(), "one\ntwo");
+
+        let mut processor = LineProcessor::new(Vec::new(), Context::new());
+        let err = processor.write_all(b"#bad\n").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn function_macros() {
+        let mut context = Context::new();
+        context
+            .define("FOO", "1")
+            .define_fn("SQUARE", vec!["x".into()], "((x)*(x))")
+            .define_fn("NOW", vec![], "now()");
+
+        assert_eq!(
+            &process_str(
+                "
+            #define ADD(a, b) a + b
+            #define IS_ONE(x) ((x) == 1)
+            SQUARE(4) SQUARE (FOO) SQUARE
+            ADD(SQUARE(2), (3, 4)) NOW()
+            #if IS_ONE(FOO) && !IS_ONE(2)
+            yes
+            #endif
+        ",
+                &mut context
+            )
+            .unwrap(),
+            "
+            ((4)*(4)) ((1)*(1)) SQUARE
+            ((2)*(2)) + (3, 4) now()
+            yes
+        "
+        );
+        assert!(context.get_macro("ADD").is_none());
+
+        match process_str("SQUARE(1, 2)\n", &mut context) {
+            Err(Error::Syntax { line: 1, msg }) => {
+                assert_eq!(msg, "Wrong number of arguments to macro")
+            }
+            other => panic!("{:?}", other),
+        }
+        match process_str("#if IS_ONE(1, 2)\n#endif\n", &mut context) {
+            Err(Error::Syntax { line: 1, msg }) => {
+                assert_eq!(msg, "Wrong number of arguments to macro")
+            }
+            other => panic!("{:?}", other),
+        }
+        match process_str("#define BAD(1) 1\n", &mut context) {
+            Err(Error::Syntax { line: 1, msg }) => assert_eq!(msg, "Invalid macro parameter"),
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn validate_inactive_branches() {
+        let text = "
+            #if 0
+            #if FOO && (BAR
+            #endif
+            #endif
+        ";
+
+        let mut context = Context::new();
+        assert_eq!(&process_str(text, &mut context).unwrap(), "\n        ");
+
+        context.validate_inactive_branches(true);
+        match process_str(text, &mut context) {
+            Err(Error::Syntax { line: 3, msg }) => assert_eq!(msg, "Expected `)`"),
+            other => panic!("{:?}", other),
+        }
+
+        // Undefined identifiers are fine, as the condition isn't evaluated
+        assert_eq!(
+            &process_str(
+                "#if 0\n#if FOO\n#endif\n#elif 1\n#elif BAR\n#endif\n",
+                &mut context
+            )
+            .unwrap(),
+            ""
+        );
+    }
+
+    #[test]
+    fn rename() {
+        let mut context = Context::new();
+        context.define("OLD", "1").define("OTHER", "2");
+        context.rename("OLD", "NEW").unwrap();
+
+        assert!(context.get_macro("OLD").is_none());
+        assert_eq!(context.get_macro("NEW").unwrap(), "1");
+        assert_eq!(&process_str("NEW OLD\n", &mut context).unwrap(), "1 OLD\n");
+
+        context.rename("OTHER", "NEW").unwrap();
+        assert_eq!(context.get_macro("NEW").unwrap(), "2");
+        assert_eq!(context.len(), 1);
+        assert!(context.rename("MISSING", "NEW").is_err());
+    }
+
+    #[test]
+    fn process_lines() {
+        let text = "a
+#if 0
+b
+#else
+c
+#endif
+d
+#include \"config.h\"
+e\r
+";
+        let mut context = Context::new();
+        let lines: Vec<_> = super::process_lines(text.as_bytes(), test_resolver(&mut context))
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(
+            lines,
+            [
+                (1, "a".to_string()),
+                (5, "c".to_string()),
+                (7, "d".to_string()),
+                (8, "config".to_string()),
+                (9, "e".to_string()),
+            ]
+        );
+
+        // Lines from a `#repeat` block are numbered with the lines of the block
+        let text = "#repeat 2\nx __REPEAT_INDEX__\n#if 1\ny\n#endif\n#endrepeat\nafter\n";
+        let lines: Vec<_> = super::process_lines(text.as_bytes(), &mut context)
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(
+            lines,
+            [
+                (2, "x 0".to_string()),
+                (4, "y".to_string()),
+                (2, "x 1".to_string()),
+                (4, "y".to_string()),
+                (7, "after".to_string()),
+            ]
+        );
+
+        let mut lines = super::process_lines("a\n#bad\nb\n".as_bytes(), &mut context);
+        assert_eq!(lines.next().unwrap().unwrap(), (1, "a".to_string()));
+        match lines.next() {
+            Some(Err(Error::Syntax { line: 2, .. })) => {}
+            other => panic!("Unexpected result: {:?}", other),
+        }
+        assert!(lines.next().is_none());
+    }
+
+    #[test]
+    fn directive_injection() {
+        let mut context = Context::new();
+        context
+            .define("GUARD", "#endif")
+            .define("HASH", "#")
+            .define("START", "#if 0");
+
+        // Expanded content is only a directive if it starts with `#` once trimmed
+        assert_eq!(
+            &process_str("prefix GUARD suffix\n", &mut context).unwrap(),
+            "prefix #endif suffix\n"
+        );
+        assert_eq!(&process_str("a HASH b\n", &mut context).unwrap(), "a # b\n");
+        assert_eq!(
+            &process_str("#if 1\n  GUARD\nfoo\n", &mut context).unwrap(),
+            "foo\n"
+        );
+        assert_eq!(
+            &process_str("START\nfoo\n#endif\nbar\n", &mut context).unwrap(),
+            "bar\n"
+        );
+        assert_eq!(
+            process_str("GUARD\n", &mut context)
+                .unwrap_err()
+                .to_string(),
+            "Unexpected `#endif` with no matching `#if` on line 1"
+        );
+
+        // Lines which were already directives are unaffected by the option
+        context.no_directive_injection(true);
+        assert_eq!(
+            &process_str("#if 1\n  GUARD\nfoo\n#endif\n", &mut context).unwrap(),
+            "  #endif\nfoo\n"
+        );
+        assert_eq!(
+            &process_str("START\nfoo\n", &mut context).unwrap(),
+            "#if 0\nfoo\n"
+        );
+        assert_eq!(
+            &process_str("HASH define X\nX\n", &mut context).unwrap(),
+            "# define X\nX\n"
+        );
+    }
+
+    #[test]
+    fn char_and_octal_literals() {
+        let mut context = Context::new();
+        context.define("C", "65").define("NL", "10");
+        let text = "#if C == 'A' && NL == '\\n'
+a
+#endif
+#if '\\0' == 0 && '\\\\' == 92 && '\\'' == 39 && 'é' == 233
+b
+#endif
+#if 0377 == 255 && 010 == 8 && 0 == 00
+c
+#endif
+";
+        assert_eq!(&process_str(text, &mut context).unwrap(), "a\nb\nc\n");
+
+        for expr in &["''", "'ab'", "'a", "'\\q'", "09"] {
+            let text = format!("#if {}\n#endif\n", expr);
+            assert!(process_str(&text, &mut context).is_err(), "{}", expr);
+        }
+    }
+
+    #[test]
+    fn process_str_ro() {
+        let mut context = Context::new();
+        context.define("FOO", "1");
+        let context = &context;
+
+        std::thread::scope(|scope| {
+            let threads: Vec<_> = (0..4)
+                .map(|i| {
+                    scope.spawn(move || {
+                        let text = format!("#define BAR {}\nFOO BAR\n", i);
+                        super::process_str_ro(&text, context).unwrap()
+                    })
+                })
+                .collect();
+            for (i, thread) in threads.into_iter().enumerate() {
+                assert_eq!(thread.join().unwrap(), format!("1 {}\n", i));
+            }
+        });
+        assert_eq!(context.len(), 1);
+    }
+
+    #[test]
+    fn has_include() {
+        let text = "#if __has_include(\"config.h\")
+config
+#endif
+#if __has_include(<config.h>) || __has_include( \"missing.h\" )
+missing
+#elif !__has_include(<sys.h>)
+no sys
+#else
+sys
+#endif
+";
+        assert_eq!(&process_str(text, &mut Context::new()).unwrap(), "no sys\n");
+        assert_eq!(
+            &process_str(text, test_resolver(&mut Context::new())).unwrap(),
+            "config\nsys\n"
+        );
+
+        // The file isn't included, so its macros aren't defined
+        let mut context = Context::new();
+        process_str(
+            "#if __has_include(\"config.h\")\n#endif\n",
+            test_resolver(&mut context),
+        )
+        .unwrap();
+        assert!(context.get_macro("HAVE_CONFIG").is_none());
+
+        for expr in &[
+            "__has_include",
+            "__has_include(config.h)",
+            "__has_include(\"a\", \"b\")",
+        ] {
+            let text = format!("#if {}\n#endif\n", expr);
+            assert!(process_str(&text, &mut Context::new()).is_err(), "{}", expr);
+        }
+    }
+
+    #[test]
+    fn process_inverse() {
+        let text = "a
+#if 0
+b
+#if 1
+c
+#endif
+#elif 1
+d
+#else
+e
+#endif
+#define F 1
+#if F
+f
+#else
+F
+#endif
+";
+        let mut output = Vec::new();
+        super::process_inverse(text.as_bytes(), &mut output, &mut Context::new()).unwrap();
+        assert_eq!(String::from_utf8(output).unwrap(), "b\nc\ne\n1\n");
+    }
+
+    #[test]
+    fn identifier_boundaries() {
+        let mut context = Context::new();
+        context
+            .define("V1", "one")
+            .define("V1X", "onex")
+            .define("X", "x")
+            .identifier_boundaries(true);
+
+        // Digits and letters are both identifier characters, in content as in conditions
+        assert_eq!(
+            &process_str("V1 V1X V1Y 2V1 X1 XX X\n", &mut context).unwrap(),
+            "one onex V1Y 2V1 X1 XX x\n"
+        );
+        assert!(process_str("#if V1Y\n#endif\n", &mut context).is_err());
+
+        // Characters which are only identifier characters with `unicode_identifiers` separate
+        // identifiers otherwise
+        assert_eq!(&process_str("éX\n", &mut context).unwrap(), "éx\n");
+        context.unicode_identifiers(true);
+        assert_eq!(&process_str("éX\n", &mut context).unwrap(), "éX\n");
+
+        // A combining mark is a word character to a regex, but not an identifier character
+        assert_eq!(
+            &process_str("X\u{301}\n", &mut context).unwrap(),
+            "x\u{301}\n"
+        );
+        context.identifier_boundaries(false);
+        assert_eq!(
+            &process_str("X\u{301}\n", &mut context).unwrap(),
+            "X\u{301}\n"
+        );
+    }
+
+    #[test]
+    fn set() {
+        let text = "#set N = 2 + 3
+#set M = N * 2 - 10 / 3 % 2
+#set NEG = -N + -(1)
+#set S = \"foo\" + \"bar\\n\"
+#set T = N > 4 ? \"big\" : \"small\"
+#if 0
+#set N = 0
+#endif
+N M NEG T
+#if \"a\" < \"b\" && \"x\" == \"x\" && \"\" != \"0\" && !\"\" && \"0\"
+strings
+#endif
+#if 0 && 1 / 0 || 1 ? 1 : \"a\" + 1
+short
+#endif
+";
+        let mut context = Context::new();
+        assert_eq!(
+            &process_str(text, &mut context).unwrap(),
+            "5 9 -6 \"big\"\nstrings\nshort\n"
+        );
+        assert_eq!(context.get_macro("S").unwrap(), "\"foobar\\n\"");
+
+        // String values can be used in conditions
+        let text = "#set S = \"a\" + \"b\"\n#set Q = \"\\\"\" + S + \"\\n\"\n#if S == \"ab\" && Q == \"\\\"ab\\n\"\nyes\n#endif\n";
+        assert_eq!(process_str(text, &mut context).unwrap(), "yes\n");
+
+        for expr in &[
+            "#if 1 / 0",
+            "#if 1 % 0",
+            "#if 9223372036854775807 + 1",
+            "#if \"a\" + 1",
+            "#if -\"a\"",
+            "#if \"a\" == 1",
+            "#if \"a",
+            "#set = 1",
+            "#set N 1",
+            "#set N =",
+            "#set 1 = 1",
+        ] {
+            let text = format!("{}\n#endif\n", expr);
+            assert!(process_str(&text, &mut Context::new()).is_err(), "{}", expr);
+        }
+    }
+
+    #[test]
+    fn try_from_str() {
+        let context = Context::try_from("FOO=1\r\n  BAR=a=b  \n\n").unwrap();
+        assert_eq!(context.len(), 2);
+        assert_eq!(context.get_macro("FOO").unwrap(), "1");
+        assert_eq!(context.get_macro("BAR").unwrap(), "a=b");
+
+        match Context::try_from("FOO=1\nBAD NAME=2") {
+            Err(Error::Syntax { line: 2, .. }) => {}
+            other => panic!("Unexpected result: {:?}", other),
+        }
+        assert!(Context::try_from("=1").is_err());
+    }
+
+    #[test]
+    fn collect_conditions() {
+        let text = "#if FOO == 1
+#if BAR
+#endif
+#elif FOO == 2
+#endif
+#if 0
+#if FOO
+#endif
+#endif
+#include \"config.h\"
+";
+        let mut context = Context::new();
+        context.define("FOO", "2").define("BAR", "1");
+        test_resolver(&mut context);
+        let mut output = Vec::new();
+        let conditions =
+            super::process_collect_conditions(text.as_bytes(), &mut output, &mut context).unwrap();
+
+        let expected: BTreeMap<u32, bool> = vec![(1, false), (4, true), (6, false)]
+            .into_iter()
+            .collect();
+        assert_eq!(conditions, expected);
+        assert_eq!(String::from_utf8(output).unwrap(), "config\n");
+    }
+
+    #[test]
+    fn undefined_mode() {
+        let text = "#if 0
+zero
+#elif LATER
+later
+#if DEFINED
+defined
+#else
+not defined
+#endif
+#else
+otherwise
+#endif
+#set N = LATER + 1
+N
+";
+        let mut context = Context::new();
+        context.define("DEFINED", "1");
+        assert_eq!(
+            process_str(text, &mut context).unwrap_err().to_string(),
+            "Undefined identifier 'LATER' on line 3"
+        );
+
+        context.undefined_mode(UndefinedMode::Zero);
+        assert_eq!(&process_str(text, &mut context).unwrap(), "otherwise\n1\n");
+
+        context
+            .undefined_mode(UndefinedMode::Passthrough)
+            .undefine("N");
+        assert_eq!(
+            &process_str(text, &mut context).unwrap(),
+            "#if LATER
+later
+defined
+#else
+otherwise
+#endif
+#set N = LATER + 1
+N
+"
+        );
+        assert_eq!(
+            &process_str(
+                "#if LATER\n#elif 1\na\n#endif\n#if 1 || LATER\nb\n#endif\n",
+                &mut context
+            )
+            .unwrap(),
+            "#if LATER\n#elif 1\na\n#endif\nb\n"
+        );
+        assert!(process_str("#if LATER ||\n#endif\n", &mut context).is_err());
+
+        context.treat_undefined_as_zero(false);
+        assert!(process_str(text, &mut context).is_err());
+    }
+
+    #[test]
+    fn contains() {
+        let mut context = Context::new();
+        context
+            .define("FEATURES", " a, b ,c")
+            .define("EMPTY", "")
+            .define("ONE", "1")
+            .define("b", "a")
+            .define("ALIAS", "LIST")
+            .define("LIST", "FEATURES")
+            .define("LOOP", "LOOP");
+        let text = "#if FEATURES contains b
+has_b
+#endif
+#if FEATURES contains d || EMPTY contains a || EMPTY contains \"\"
+d
+#endif
+#if !(FEATURES contains \"c\") == 0 && ONE contains 1 && \"x,y\" contains y
+c
+#endif
+";
+        assert_eq!(&process_str(text, &mut context).unwrap(), "has_b\nc\n");
+        assert!(process_str("#if FEATURES contains\n#endif\n", &mut context).is_err());
+        assert!(process_str("#if MISSING contains a\n#endif\n", &mut context).is_err());
+        assert_eq!(
+            &process_str("#if ALIAS contains c\nc\n#endif\n", &mut context).unwrap(),
+            "c\n"
+        );
+        assert_eq!(
+            &process_str("#if LOOP contains LOOP\nloop\n#endif\n", &mut context).unwrap(),
+            "loop\n"
+        );
+
+        context.list_delimiter(';').define("FEATURES", "a,b;c");
+        assert_eq!(
+            &process_str(
+                "#if FEATURES contains c && !(FEATURES contains b)\nc\n#endif\n",
+                &mut context
+            )
+            .unwrap(),
+            "c\n"
+        );
+    }
+
+    #[test]
+    fn snapshot() {
+        let mut context = Context::new();
+        context.define("FOO", "1").define("BAR", "2");
+        let snapshot = context.snapshot();
+
+        process_str("#undef FOO\n#define BAZ 3\n", &mut context).unwrap();
+        context.define("BAR", "4").trim_trailing_newline(true);
+        assert_eq!(
+            &process_str("FOO BAR BAZ\n", &mut context).unwrap(),
+            "FOO 4 3"
+        );
+
+        context.restore(snapshot.clone());
+        assert_eq!(context.len(), 2);
+        assert_eq!(context.get_macro("FOO").unwrap(), "1");
+        assert_eq!(context.get_macro("BAR").unwrap(), "2");
+        assert!(context.get_macro("BAZ").is_none());
+        // Options aren't part of the snapshot
+        assert_eq!(
+            &process_str("FOO BAR BAZ\n", &mut context).unwrap(),
+            "1 2 BAZ"
+        );
+
+        context.undefine("FOO").restore(snapshot);
+        assert_eq!(context.get_macro("FOO").unwrap(), "1");
+    }
+
+    #[test]
+    fn bare_word_comparisons() {
+        let mut context = Context::new();
+        context.define("MODE", "release").define("N", "1");
+        let text = "#if MODE == release
+release
+#endif
+#if MODE != debug && MODE == \"release\" && \"debug\" != MODE
+not debug
+#endif
+#if MODE == debug || (N != 1 && other == other)
+debug
+#endif
+";
+        assert!(process_str(text, &mut context).is_err());
+
+        context.bare_word_comparisons(true);
+        assert_eq!(
+            &process_str(text, &mut context).unwrap(),
+            "release\nnot debug\n"
+        );
+
+        // Other uses of undefined identifiers are still errors
+        for expr in &[
+            "MODE",
+            "N == release",
+            "!debug",
+            "debug + 1",
+            "debug < release",
+        ] {
+            let text = format!("#if {}\n#endif\n", expr);
+            assert!(process_str(&text, &mut context).is_err(), "{}", expr);
+        }
+        context.treat_undefined_as_zero(true);
+        assert_eq!(
+            &process_str("#if N == release\n#else\nzero\n#endif\n", &mut context).unwrap(),
+            "zero\n"
+        );
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn process_streams() {
+        let mut output = io::BufWriter::new(Vec::new());
+        super::process_streams(
+            io::Cursor::new("#if FOO\nfoo\n#endif\nbar\n"),
+            &mut output,
+            Context::new().define("FOO", "1"),
+        )
+        .unwrap();
+        // The output was flushed, so nothing is left in the buffer
+        assert_eq!(output.buffer().len(), 0);
+        assert_eq!(output.get_ref(), b"foo\nbar\n");
+    }
+
+    #[test]
+    fn builtin_macros() {
+        let mut context = Context::new();
+        context.include_resolver(|name, _| {
+            Ok(Some((
+                PathBuf::from(format!("inc/{}", name)),
+                "__FILE__ __LINE__\n".to_string(),
+            )))
+        });
+        context.file_name("main.c");
+        let text = "__LINE__ __FILE__
+#include \"a.h\"
+#if __LINE__ == 3 && defined(__LINE__) && __FILE__ == \"main.c\"
+__FILE__ __LINE__
+#endif
+";
+        assert_eq!(
+            &process_str(text, &mut context).unwrap(),
+            "1 \"main.c\"\n\"inc/a.h\" 1\n\"main.c\" 4\n"
+        );
+        context.file_name(r#"a\"b"#);
+        assert_eq!(
+            &process_str("__FILE__\n", &mut context).unwrap(),
+            r#""a\\\"b"
+"#
+        );
+
+        // Built-ins can't be redefined, in-stream or through the API
+        for directive in &[
+            "#define __LINE__ 5",
+            "#undef __FILE__",
+            "#set __LINE__ = 1",
+            "#define __FILE__(x) x",
+        ] {
+            let text = format!("{}\n", directive);
+            match process_str(&text, &mut context) {
+                Err(Error::Syntax {
+                    msg: "Cannot redefine built-in macro",
+                    ..
+                }) => {}
+                other => panic!("Unexpected result for {}: {:?}", directive, other),
+            }
+        }
+        context.define("__LINE__", "5");
+        assert!(context.get_macro("__LINE__").is_none());
+        assert_eq!(&process_str("__LINE__\n", &mut context).unwrap(), "1\n");
+        match context.define_from_str("A=1\n__LINE__=5") {
+            Err(Error::Syntax { line: 2, msg }) => {
+                assert_eq!(msg, "Cannot redefine built-in macro")
+            }
+            other => panic!("Unexpected result: {:?}", other),
+        }
+        assert!(context.get_macro("__LINE__").is_none());
+        assert!(context.rename("A", "__FILE__").is_err());
+        assert_eq!(context.get_or_define("__FILE__", || "a".into()), "a");
+        for name in BUILTIN_MACROS {
+            match context.try_define(*name, "5") {
+                Err(Error::Syntax { line: 0, msg }) => {
+                    assert_eq!(msg, "Cannot redefine built-in macro")
+                }
+                other => panic!("Unexpected result for {}: {:?}", name, other),
+            }
+        }
+        assert!(context.try_define_fn("__FILE__", vec![], "").is_err());
+
+        // Skipped branches aren't checked
+        let text = "#if 0\n#define __LINE__ 1\n#undef __FILE__\n#set __LINE__ = 2\n#endif\n";
+        assert_eq!(process_str(text, &mut context).unwrap(), "");
+    }
+
+    #[test]
+    fn compare_macros() {
+        let mut context = Context::new();
+        context
+            .define("MAJOR", "3")
+            .define("MINOR", "3")
+            .define("PATCH", "12")
+            .define("OCTAL", "03")
+            .define("TWO", "2");
+        let text = "#if MAJOR == MINOR
+same
+#endif
+#if MAJOR == PATCH || MAJOR != MINOR
+different
+#endif
+#if MAJOR == OCTAL && TWO != PATCH && TWO + 1 == MINOR
+integers
+#endif
+";
+        assert_eq!(
+            &process_str(text, &mut context).unwrap(),
+            "same\nintegers\n"
+        );
+
+        // Both operands are compared as integers, not as booleans
+        context.define("MINOR", "4");
+        assert_eq!(&process_str(text, &mut context).unwrap(), "different\n");
+    }
+
+    #[test]
+    fn chained_equality() {
+        let check = |expr: &str| {
+            let text = format!("#if {}\ntrue\n#endif\n", expr);
+            process_str(&text, &mut Context::new()).unwrap() == "true\n"
+        };
+        assert!(check("0 == 0"));
+        assert!(check("1 == 1 == 1"));
+        assert!(!check("2 == 2 == 2"));
+        assert!(check("(2 == 2) == 1"));
+        assert!(!check("0 == 0 == 0"));
+        assert!(check("1 != 2 == 1"));
+        assert!(check("3 == 3 != 0"));
+        assert!(check("2 == (2 == 1) + 2"));
+    }
+
+    #[test]
+    fn annotate_directives() {
+        let text = "#define FOO 1 // the foo
+#if FOO // check foo
+#if 0 // never
+hidden
+#endif
+foo
+#elif 1 // not reached
+#else
+#endif // done
+";
+        let mut context = Context::new();
+        context.annotate_directives(true);
+        assert_eq!(
+            &process_str(text, &mut context).unwrap(),
+            "#define FOO 1 // the foo
+#if FOO // check foo
+#if 0 // never
+#endif
+foo
+#elif 1 // not reached
+#else
+#endif // done
+"
+        );
+
+        // Directives which are passed through keep their comments, and aren't written twice
+        let text = "#if LATER // later\nlater\n#endif // end\n";
+        context.undefined_mode(UndefinedMode::Passthrough);
+        assert_eq!(&process_str(text, &mut context).unwrap(), text);
+        context.annotate_directives(false);
+        assert_eq!(&process_str(text, &mut context).unwrap(), text);
+    }
+
+    #[test]
+    fn open_lines() {
+        let text = "#if 1
+#if 0
+#endif
+#if 1
+#if 0
+#elif UNDEFINED
+#endif
+#endif
+#endif
+";
+        let mut open_lines = vec![100];
+        let result = process_with_open_lines(
+            text.as_bytes(),
+            Vec::new(),
+            &mut Context::new(),
+            &mut open_lines,
+        );
+        match result {
+            Err(Error::UndefinedIdentifier { line: 6, .. }) => {}
+            other => panic!("Unexpected result: {:?}", other),
+        }
+        assert_eq!(open_lines, [1, 4, 5]);
+
+        // The lines are left alone when processing succeeds
+        let mut open_lines = vec![100];
+        process_with_open_lines(
+            "#if 1\n#endif\n".as_bytes(),
+            Vec::new(),
+            &mut Context::new(),
+            &mut open_lines,
+        )
+        .unwrap();
+        assert_eq!(open_lines, [100]);
+    }
+
+    #[test]
+    fn min_max() {
+        let mut context = Context::new();
+        context.define("A", "4").define("B", "12");
+        let text = "#if max(A, B) > 10 && min(A, B) == 4 && max(-1, min(A * 2, B - 5)) == 7
+both
+#endif
+#if min(A,B) > 10
+min
+#endif
+#set M = max(max(1, 2), 3) + 1
+M
+";
+        assert_eq!(&process_str(text, &mut context).unwrap(), "both\n4\n");
+
+        for expr in &["min(1)", "max(1, 2", "min(1, 2, 3)", "max(\"a\", 1)", "min"] {
+            let text = format!("#if {}\n#endif\n", expr);
+            assert!(process_str(&text, &mut context).is_err(), "{}", expr);
+        }
+    }
+
+    #[test]
+    fn output_newline() {
+        let mut context = Context::new();
+        let crlf = "a\r\n#if 1\r\nb\r\n#endif\r\nc";
+        let lf = "a\n#if 1\nb\n#endif\nc";
+        assert_eq!(&process_str(crlf, &mut context).unwrap(), "a\r\nb\r\nc");
+
+        context.output_newline(Newline::Lf);
+        assert_eq!(&process_str(crlf, &mut context).unwrap(), "a\nb\nc");
+        assert_eq!(&process_str("a\r\nb\n", &mut context).unwrap(), "a\nb\n");
+
+        context.output_newline(Newline::Crlf);
+        assert_eq!(&process_str(lf, &mut context).unwrap(), "a\r\nb\r\nc");
+        assert_eq!(&process_str("a\n", &mut context).unwrap(), "a\r\n");
+
+        context.trim_trailing_newline(true);
+        assert_eq!(
+            &process_str("a\nb\n\n", &mut context).unwrap(),
+            "a\r\nb\r\n"
+        );
+    }
+
+    #[test]
+    fn directive_comments_not_expanded() {
+        let mut context = Context::new();
+        context
+            .define("FOO", "1")
+            .define_fn("ONE", vec!["x".into()], "x");
+
+        // A macro which would fail to expand is ignored in a comment
+        let text = "#if FOO // FOO or ONE(1, 2)\nfoo\n#endif // ONE(2)\n";
+        assert_eq!(&process_str(text, &mut context).unwrap(), "foo\n");
+
+        context.annotate_directives(true);
+        assert_eq!(&process_str(text, &mut context).unwrap(), text);
+
+        context.expand_directive_comments(true);
+        assert!(process_str(text, &mut context).is_err());
+        assert_eq!(
+            &process_str("#if FOO // FOO or ONE(3)\n#endif\n", &mut context).unwrap(),
+            "#if FOO // 1 or 3\n#endif\n"
+        );
+    }
+
+    #[test]
+    fn cancellable() {
+        // Sets the flag once a number of lines have been written
+        struct CancelAfter<'a> {
+            lines: usize,
+            cancel: &'a AtomicBool,
+            output: Vec<u8>,
+        }
+        impl<'a> Write for CancelAfter<'a> {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                self.output.extend_from_slice(buf);
+                let lines = self.output.iter().filter(|&&b| b == b'\n').count();
+                if lines >= self.lines {
+                    self.cancel.store(true, atomic::Ordering::Relaxed);
+                }
+                Ok(buf.len())
+            }
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let cancel = AtomicBool::new(false);
+        let mut output = CancelAfter {
+            lines: 2,
+            cancel: &cancel,
+            output: Vec::new(),
+        };
+        let text = "a\n#if 1\nb\n#include \"config.h\"\nc\n";
+        let result = process_cancellable(
+            text.as_bytes(),
+            &mut output,
+            test_resolver(&mut Context::new()),
+            &cancel,
+        );
+        match result {
+            Err(Error::Cancelled) => {}
+            other => panic!("Unexpected result: {:?}", other),
+        }
+        assert_eq!(output.output, b"a\nb\n");
+        assert_eq!(Error::Cancelled.to_string(), "Processing was cancelled");
+
+        // Lines of included files are checked too
+        output.lines = 3;
+        output.output.clear();
+        cancel.store(false, atomic::Ordering::Relaxed);
+        let result = process_cancellable(
+            text.as_bytes(),
+            &mut output,
+            test_resolver(&mut Context::new()),
+            &cancel,
+        );
+        assert!(matches!(result, Err(Error::Cancelled)));
+        assert_eq!(output.output, b"a\nb\nconfig\n");
+    }
+
+    #[test]
+    fn nested_ternary() {
+        let text = "#set R = A ? B : C ? D : E
+#set S = A ? C ? 1 : 2 : 3
+R S
+";
+        let cases = [
+            ("1", "1", "\"b\" 1"),
+            ("1", "0", "\"b\" 2"),
+            ("0", "1", "\"d\" 3"),
+            ("0", "0", "\"e\" 3"),
+        ];
+        for &(a, c, expected) in &cases {
+            let mut context = Context::new();
+            context
+                .define("A", a)
+                .define("C", c)
+                .define("B", "\"b\"")
+                .define("D", "\"d\"")
+                .define("E", "\"e\"");
+            // Grouping from the left would give `(A ? B : C) ? D : E` instead
+            assert_eq!(
+                process_str(text, &mut context).unwrap(),
+                format!("{}\n", expected),
+                "A = {}, C = {}",
+                a,
+                c
+            );
+        }
+    }
+
+    #[test]
+    fn undefined_identifier_name() {
+        let mut context = Context::new();
+        context.define("A", "1").define("ALIAS", "B");
+        for &(expr, name) in &[("A == B", "B"), ("ALIAS", "B"), ("A && (C || A)", "C")] {
+            let text = format!("\n#if {}\n#endif\n", expr);
+            let error = process_str(&text, &mut context).unwrap_err();
+            assert_eq!(
+                error.to_string(),
+                format!("Undefined identifier '{}' on line 2", name)
+            );
+            match error {
+                Error::UndefinedIdentifier {
+                    line: 2,
+                    name: ref n,
+                } if n == name => {}
+                other => panic!("Unexpected error for {}: {:?}", expr, other),
+            }
+        }
+    }
+
+    #[test]
+    fn recognized_directives() {
+        let mut context = Context::new();
+        context
+            .define("FOO", "1")
+            .recognized_directives(&["#if", "#else", "#endif"]);
+        assert_eq!(
+            process_str(
+                "#!/bin/sh\n#heading\n#if FOO\nfoo\n#else\n#heading 2\n#endif\n",
+                &mut context
+            )
+            .unwrap(),
+            "#!/bin/sh\n#heading\nfoo\n"
+        );
+        assert_eq!(
+            process_str("#define BAR 2\nBAR\n", &mut context).unwrap(),
+            "#define BAR 2\nBAR\n"
+        );
+    }
+
+    #[test]
+    fn process_bytes() {
+        let mut context = Context::new();
+        context.define("FOO", "bar");
+        assert_eq!(
+            super::process_bytes(b"#if 1\nFOO\n#endif\n", &mut context).unwrap(),
+            b"bar\n"
+        );
+        match super::process_bytes(b"\xff\n", &mut context) {
+            Err(Error::Io(e)) => assert_eq!(e.kind(), io::ErrorKind::InvalidData),
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn lazy_elif_chain() {
+        let trace = SharedBuf::default();
+        let mut context = Context::new();
+        context
+            .define("FOO", "2")
+            .set_trace(Box::new(trace.clone()));
+
+        let mut text = String::from("#if FOO == 1\none\n#elif FOO == 2\ntwo\n");
+        for i in 3..1000 {
+            text.push_str(&format!(
+                "#elif FOO == {} || 1 / 0 || UNDEFINED\nother\n",
+                i
+            ));
+        }
+        text.push_str("#else\nnone\n#endif\n");
+
+        assert_eq!(process_str(&text, &mut context).unwrap(), "two\n");
+        // Only the branches up to the one which was taken are evaluated
+        assert_eq!(
+            trace.contents(),
+            "line 1: #if FOO == 1 -> false\nline 3: #elif FOO == 2 -> true\n"
+        );
+    }
+
+    #[test]
+    fn repeat() {
+        let mut context = Context::new();
+        context.define("N", "2");
+        assert_eq!(
+            process_str(
+                "#repeat N + 1\nitem __REPEAT_INDEX__\n#endrepeat\nend\n",
+                &mut context
+            )
+            .unwrap(),
+            "item 0\nitem 1\nitem 2\nend\n"
+        );
+        assert!(context.get_macro("__REPEAT_INDEX__").is_none());
+
+        // Nested blocks each have their own index, and conditions are evaluated every time
+        assert_eq!(
+            process_str(
+                "#repeat 2\n#repeat 2\n__REPEAT_INDEX__\n#endrepeat\n#if __REPEAT_INDEX__\nouter __REPEAT_INDEX__\n#endif\n#endrepeat\n",
+                &mut context
+            )
+            .unwrap(),
+            "0\n1\n0\n1\nouter 1\n"
+        );
+
+        assert_eq!(
+            process_str(
+                "#if 0\n#repeat 1 / 0\nfoo\n#endrepeat\n#endif\n",
+                &mut context
+            )
+            .unwrap(),
+            ""
+        );
+        assert_eq!(
+            process_str("#repeat 0\nfoo\n#endrepeat\n", &mut context).unwrap(),
+            ""
+        );
+        assert_eq!(
+            process_str(
+                "#repeat 2\n__REPEAT_INDEX__\n#endrepeat\n",
+                Context::new().forbid_names(&[REPEAT_INDEX])
+            )
+            .unwrap(),
+            "0\n1\n"
+        );
+        match process_str("#repeat 2\nfoo\n#if 1\n#endrepeat\n", &mut context) {
+            Err(Error::Syntax { line: 1, .. }) => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+        match process_str("foo\n#repeat 2\nfoo\n", &mut context) {
+            Err(Error::Syntax { line: 2, .. }) => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+        match process_str("\n#endrepeat\n", &mut context) {
+            Err(Error::Syntax { line: 2, .. }) => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+        match process_str(
+            "#repeat 1\nfoo\n\n#if UNDEFINED\n#endif\n#endrepeat\n",
+            &mut context,
+        ) {
+            Err(Error::UndefinedIdentifier { line: 4, .. }) => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn switch() {
+        let text = "#switch OS
+#case 1
+one
+#case 2
+two
+#case 1 + 1
+also two
+#default
+other
+#endswitch
+";
+        let mut context = Context::new();
+        context.define("OS", "2");
+        assert_eq!(process_str(text, &mut context).unwrap(), "two\n");
+        context.define("OS", "3");
+        assert_eq!(process_str(text, &mut context).unwrap(), "other\n");
+
+        // Strings are compared too, and a switch may be nested in a condition
+        context.define("ARCH", "\"arm\"");
+        let text = "#if defined(ARCH)
+#switch ARCH
+#case \"x86\"
+x86
+#case \"arm\"
+#if OS == 3
+arm
+#endif
+#endswitch
+#else
+none
+#endif
+#if 0
+#switch UNDEFINED
+#case 1
+#endswitch
+#endif
+";
+        assert_eq!(process_str(text, &mut context).unwrap(), "arm\n");
+
+        match process_str("#switch 1\n#case \"1\"\n#endswitch\n", &mut context) {
+            Err(Error::Syntax { line: 2, .. }) => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+        match process_str("#switch 1\n#else\n#endswitch\n", &mut context) {
+            Err(Error::Syntax { line: 2, .. }) => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+        match process_str("#if 1\n#case 1\n#endif\n", &mut context) {
+            Err(Error::Syntax { line: 2, .. }) => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn define_comments() {
+        let text = "#define URL http://example.com // the site\nURL\n";
+        let mut context = Context::new();
+        assert_eq!(process_str(text, &mut context).unwrap(), "http:\n");
+        assert_eq!(context.get_macro("URL").unwrap(), "http:");
+
+        context.define_comments(false);
+        assert_eq!(
+            process_str(text, &mut context).unwrap(),
+            "http://example.com // the site\n"
+        );
+        // Comments are still removed from other directives
+        assert_eq!(
+            process_str("#if 1 // URL\nyes\n#endif\n", &mut context).unwrap(),
+            "yes\n"
+        );
+    }
+
+    #[test]
+    fn produces_output() {
+        let mut context = Context::new();
+        assert!(!super::produces_output(
+            "#if 0\nfoo\n#if 1\nbar\n#endif\n#endif\n".as_bytes(),
+            &mut context
+        )
+        .unwrap());
+        assert!(!super::produces_output("".as_bytes(), &mut context).unwrap());
+
+        // Processing stops as soon as a line is written, before the error
+        assert!(super::produces_output("foo\n#if\n".as_bytes(), &mut context).unwrap());
+        assert!(super::produces_output("#if 0\n#endif\n\n".as_bytes(), &mut context).unwrap());
+        assert!(super::produces_output("#if\n".as_bytes(), &mut context).is_err());
+    }
+
+    #[test]
+    fn macro_source() {
+        let mut context = Context::new();
+        context
+            .define_with_source("FOO", "1", "defaults.h:1")
+            .define_with_source("BAR", "2", "command line");
+        assert_eq!(context.get_macro("FOO").unwrap(), "1");
+        assert_eq!(context.get_macro_source("FOO"), Some("defaults.h:1"));
+        assert_eq!(context.get_macro_source("MISSING"), None);
+
+        // The source moves with a renamed macro, and is lost when it's redefined
+        context.rename("FOO", "BAZ").unwrap();
+        assert_eq!(context.get_macro_source("BAZ"), Some("defaults.h:1"));
+        process_str("#define BAR 3\n", &mut context).unwrap();
+        assert_eq!(context.get_macro("BAR").unwrap(), "3");
+        assert_eq!(context.get_macro_source("BAR"), None);
+    }
+
+    #[test]
+    fn process_with_deps() {
+        let (output, deps) = super::process_with_deps(
+            "#include \"both.h\"\n#include \"sys.h\"\n#include \"config.h\"\n".as_bytes(),
+            test_resolver(&mut Context::new()),
+        )
+        .unwrap();
+        assert_eq!(output, "system\nconfig\nlocal\nconfig\n");
+        assert_eq!(
+            deps,
+            [
+                PathBuf::from("both.h"),
+                PathBuf::from("sys.h"),
+                PathBuf::from("config.h")
+            ]
+        );
+    }
+
+    #[test]
+    fn directive_names() {
+        let mut context = Context::new();
+        context.directive_names(DirectiveNames::with_prefix("%"));
+        let text = "# Heading
+%define FOO 2
+%if FOO == 1
+one
+%elif FOO == 2
+#two
+%else
+other
+%endif
+%repeat 2
+__REPEAT_INDEX__
+%endrepeat
+";
+        assert_eq!(
+            process_str(text, &mut context).unwrap(),
+            "# Heading\n#two\n0\n1\n"
+        );
+        match process_str("%unknown\n", &mut context) {
+            Err(Error::Syntax { line: 1, .. }) => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+        // The default names aren't directives any more
+        assert_eq!(process_str("#if 0\n", &mut context).unwrap(), "#if 0\n");
+    }
+
+    #[test]
+    fn interpret_escapes_in_defines() {
+        let text =
+            "#define TEXT one\\ntwo \\\"three\\\"\n#define PATH C:\\\\temp\n#define F(x) x\\tx\n";
+        let mut context = Context::new();
+        process_str(text, &mut context).unwrap();
+        assert_eq!(
+            context.get_macro("TEXT").unwrap(),
+            "one\\ntwo \\\"three\\\""
+        );
+        assert_eq!(context.get_macro("PATH").unwrap(), "C:\\\\temp");
+
+        context.interpret_escapes_in_defines(true);
+        process_str(text, &mut context).unwrap();
+        assert_eq!(context.get_macro("TEXT").unwrap(), "one\ntwo \"three\"");
+        assert_eq!(context.get_macro("PATH").unwrap(), "C:\\temp");
+        assert_eq!(process_str("F(a)", &mut context).unwrap(), "a\ta");
+        match process_str("#define BAD \\q\n", &mut context) {
+            Err(Error::Syntax { line: 1, .. }) => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn expand_macro() {
+        let mut context = Context::new();
+        context
+            .define("A", "B + 1")
+            .define("B", "C * C")
+            .define("C", "2")
+            .define("SELF", "SELF + 1")
+            .define("PING", "PONG")
+            .define("PONG", "PING")
+            .define("CALL", "TWICE(C) TWICE")
+            .define_fn("TWICE", vec!["x".into()], "x x");
+        assert_eq!(context.expand_macro("A").unwrap(), "2 * 2 + 1");
+        assert_eq!(context.expand_macro("SELF").unwrap(), "SELF + 1");
+        assert_eq!(context.expand_macro("PING").unwrap(), "PING");
+        assert_eq!(context.expand_macro("CALL").unwrap(), "2 2 TWICE");
+        assert!(context.expand_macro("TWICE").is_none());
+        assert!(context.expand_macro("UNDEFINED").is_none());
+    }
+
+    #[test]
+    fn defined_paste() {
+        let mut context = Context::new();
+        context
+            .define("PREFIX", "FEATURE")
+            .define("FEATURE_FAST", "1")
+            .define("FEATURE_2", "1");
+        let check = |expr: &str, context: &mut Context| {
+            process_str(&format!("#if {}\nyes\n#endif\n", expr), context).unwrap() == "yes\n"
+        };
+        assert!(check("defined(PREFIX ## _FAST)", &mut context));
+        assert!(check("defined PREFIX##_ ## 2", &mut context));
+        assert!(!check("defined(PREFIX ## _SLOW)", &mut context));
+        // Without pasting, the name isn't expanded
+        assert!(check("defined(PREFIX) && !defined(FEATURE)", &mut context));
+        context.define("PREFIX", "OTHER");
+        assert!(!check("defined(PREFIX ## _FAST)", &mut context));
+        assert!(process_str("#if defined(PREFIX ## )\n#endif\n", &mut context).is_err());
+    }
+
+    #[test]
+    fn clone_shares_handlers() {
+        let trace = SharedBuf::default();
+        let mut context = Context::new();
+        test_resolver(&mut context)
+            .define("FOO", "1")
+            .set_trace(Box::new(trace.clone()));
+
+        let mut clone = context.clone();
+        clone.define("FOO", "2");
+        assert_eq!(context.get_macro("FOO").unwrap(), "1");
+        assert_eq!(
+            process_str("#include <sys.h>\n#if FOO\n#endif\n", &mut clone).unwrap(),
+            "system\n"
+        );
+        assert_eq!(trace.contents(), "line 2: #if FOO -> true\n");
+    }
+
+    #[test]
+    fn require_switch_default() {
+        let text = "#switch VALUE\n#case 1\none\n#endswitch\n";
+        let with_default = "#switch VALUE\n#case 1\none\n#default\nother\n#endswitch\n";
+        let mut context = Context::new();
+        context.define("VALUE", "1").require_switch_default(true);
+        assert_eq!(process_str(text, &mut context).unwrap(), "one\n");
+
+        context.define("VALUE", "2");
+        assert_eq!(process_str(with_default, &mut context).unwrap(), "other\n");
+        match process_str(&format!("\n{}", text), &mut context) {
+            Err(Error::Syntax { line, msg }) => {
+                assert_eq!(line, 2);
+                assert_eq!(msg, "Unmatched #switch with no default");
+            }
+            other => panic!("unexpected result: {:?}", other),
+        }
+        // Blocks which aren't evaluated don't need to match
+        assert_eq!(
+            process_str(&format!("#if 0\n{}#endif\n", text), &mut context).unwrap(),
+            ""
+        );
+
+        context.require_switch_default(false);
+        assert_eq!(process_str(text, &mut context).unwrap(), "");
+    }
+
+    #[test]
+    fn process_size() {
+        let text = "#define FOO some text\n#if 1\nFOO FOO\n#else\nnone\n#endif\nend";
+        let mut context = Context::new();
+        context.trim_trailing_newline(true);
+        assert_eq!(
+            super::process_size(text.as_bytes(), &mut context.clone()).unwrap(),
+            process_str(text, &mut context).unwrap().len()
+        );
+        assert_eq!(super::process_size("".as_bytes(), &mut context).unwrap(), 0);
+    }
+
+    #[test]
+    fn elifdef() {
+        let text = "#if A
+a
+#elifdef B
+b
+#elif C == 1
+c
+#elifndef D
+not d
+#else
+other
+#endif
+";
+        let mut context = Context::new();
+        context.define("A", "0").define("C", "1").define("D", "");
+        assert_eq!(process_str(text, &mut context).unwrap(), "c\n");
+        context.define("B", "");
+        assert_eq!(process_str(text, &mut context).unwrap(), "b\n");
+        context.undefine("B").undefine("D").define("C", "2");
+        assert_eq!(process_str(text, &mut context).unwrap(), "not d\n");
+        assert_eq!(
+            process_str(
+                "#ifdef A\na\n#endif\n#ifndef A\nnot a\n#endif\n",
+                &mut context
+            )
+            .unwrap(),
+            "a\n"
+        );
+
+        // The operand is a single name
+        for (text, expected) in [
+            (
+                "#ifdef A B\n#endif\n",
+                "Expected a single macro name after `#ifdef`",
+            ),
+            (
+                "#ifndef (A)\n#endif\n",
+                "Expected a single macro name after `#ifndef`",
+            ),
+            (
+                "#if 0\n#elifdef 1\n#endif\n",
+                "Expected a single macro name after `#elifdef`",
+            ),
+            (
+                "#if 0\n#elifndef A + B\n#endif\n",
+                "Expected a single macro name after `#elifndef`",
+            ),
+        ] {
+            match process_str(text, &mut context) {
+                Err(Error::Syntax { msg, .. }) => assert_eq!(msg, expected),
+                other => panic!("unexpected result: {:?}", other),
+            }
+        }
+        assert_eq!(
+            process_str("#if 0\n#ifdef A B\n#endif\n#endif\n", &mut context).unwrap(),
+            ""
+        );
+
+        // Nothing may follow the `#else` branch
+        for tail in &["#elifdef B", "#elif 1", "#else"] {
+            match process_str(&format!("#if 1\n#else\n{}\n#endif\n", tail), &mut context) {
+                Err(Error::Syntax { line: 3, .. }) => {}
+                other => panic!("unexpected result: {:?}", other),
+            }
+        }
+        match process_str("#switch 1\n#default\n#case 1\n#endswitch\n", &mut context) {
+            Err(Error::Syntax { line: 3, .. }) => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn process_diff() {
+        let text = "start
+#if FAST
+fast
+fast again
+#else
+slow
+#endif
+end
+";
+        let diff = super::process_diff(
+            text,
+            Context::new().define("FAST", "0"),
+            Context::new().define("FAST", "1"),
+        )
+        .unwrap();
+        assert_eq!(
+            diff,
+            [
+                DiffLine::Unchanged("start".into()),
+                DiffLine::Removed("slow".into()),
+                DiffLine::Added("fast".into()),
+                DiffLine::Added("fast again".into()),
+                DiffLine::Unchanged("end".into()),
+            ]
+        );
+
+        let mut context = Context::new();
+        context.define("FAST", "1");
+        assert!(
+            super::process_diff(text, &mut context.clone(), &mut context)
+                .unwrap()
+                .iter()
+                .all(|line| matches!(line, DiffLine::Unchanged(_)))
+        );
+    }
+
+    #[test]
+    fn max_expansions_per_line() {
+        let mut context = Context::new();
+        context
+            .define("A", "1")
+            .define("B", "A + A")
+            .define_fn("F", vec!["x".into()], "x")
+            .max_expansions_per_line(3);
+        assert_eq!(
+            process_str("A A A\nA A A\n", &mut context).unwrap(),
+            "1 1 1\n1 1 1\n"
+        );
+        for text in &["A A A A\n", "F(A) F(A)\n", "#if B + B\n#endif\n"] {
+            match process_str(text, &mut context) {
+                Err(Error::Syntax { line: 1, msg }) => {
+                    assert_eq!(msg, "Too many expansions on line")
+                }
+                other => panic!("unexpected result: {:?}", other),
+            }
+        }
+        assert_eq!(
+            process_str("#if B\nyes\n#endif\n", &mut context).unwrap(),
+            "yes\n"
+        );
+    }
+
+    #[test]
+    fn process_with_report() {
+        let mut context = Context::new();
+        test_resolver(&mut context).define("FOO", "1");
+        let mut output = Vec::new();
+        let report = super::process_with_report(
+            "#include \"both.h\"\n#if HAVE_CONFIG && FOO\n__LINE__\n#elif 1\n#endif\n".as_bytes(),
+            &mut output,
+            &mut context,
+        )
+        .unwrap();
+        assert_eq!(String::from_utf8(output).unwrap(), "system\nconfig\n3\n");
+        assert_eq!(report.lines_in, 5);
+        assert_eq!(report.lines_out, 3);
+        assert_eq!(
+            report.macros_used.iter().collect::<Vec<_>>(),
+            ["FOO", "HAVE_CONFIG", "__LINE__"]
+        );
+        assert_eq!(report.files_included.len(), 3);
+        assert!(context.used_macros.is_none());
+
+        #[cfg(feature = "serde")]
+        assert_eq!(
+            serde_json::to_value(&report).unwrap(),
+            serde_json::json!({
+                "lines_in": 5,
+                "lines_out": 3,
+                "conditions": { "2": true },
+                "macros_used": ["FOO", "HAVE_CONFIG", "__LINE__"],
+                "files_included": ["both.h", "sys.h", "config.h"],
+            })
+        );
+    }
+
+    #[test]
+    fn forbid_names() {
+        let mut context = Context::new();
+        context.forbid_names(&["if", "for"]).define("iff", "1");
+        assert_eq!(
+            process_str("if (iff) {}\n", &mut context).unwrap(),
+            "if (1) {}\n"
+        );
+        for text in &["#define if 1\n", "#define for(x) x\n", "#set if = 1\n"] {
+            match process_str(text, &mut context) {
+                Err(Error::Syntax { line: 1, msg }) => {
+                    assert_eq!(msg, "Cannot define forbidden macro name")
+                }
+                other => panic!("unexpected result: {:?}", other),
+            }
+        }
+        assert_eq!(process_str("#undef for\n", &mut context).unwrap(), "");
+        assert!(context.define_from_str("A=1\nfor=2").is_err());
+        assert_eq!(context.get_macro("A").unwrap(), "1");
+
+        match context.try_define("if", "1") {
+            Err(Error::Syntax { line: 0, msg }) => {
+                assert_eq!(msg, "Cannot define forbidden macro name")
+            }
+            other => panic!("unexpected result: {:?}", other),
+        }
+        assert!(context.try_define_fn("for", vec![], "").is_err());
+        assert!(context.try_define_env_passthrough("if", "HOME").is_err());
+        assert!(context.get_macro("if").is_none());
+        assert!(context.rename("A", "if").is_err());
+        assert_eq!(context.get_macro("A").unwrap(), "1");
+
+        let text = "#if 0\n#define if 1\n#undef for\n#set if = 2\n#endif\n";
+        assert_eq!(process_str(text, &mut context).unwrap(), "");
+
+        // Macros which are already defined are removed once their names are forbidden
+        let mut context = Context::new();
+        context
+            .define("if", "1")
+            .define("else", "2")
+            .forbid_names(&["if"]);
+        assert_eq!(process_str("if else\n", &mut context).unwrap(), "if 2\n");
+    }
+
+    #[test]
+    fn define_forbidden_name() {
+        let mut context = Context::new();
+        context.forbid_names(&["if"]);
+        match context.try_define("if", "1") {
+            Err(Error::Syntax { line: 0, msg }) => {
+                assert_eq!(msg, "Cannot define forbidden macro name")
+            }
+            other => panic!("unexpected result: {:?}", other),
+        }
+        // The infallible methods leave the name undefined
+        context
+            .define("if", "1")
+            .define_fn("if", vec![], "2")
+            .define_env_passthrough("if", "HOME");
+        assert_eq!(process_str("if() if\n", &mut context).unwrap(), "if() if\n");
+        assert_eq!(context.get_or_define("if", || "3".into()), "3");
+        assert!(context.get_macro("if").is_none());
+    }
+
+    // Generates a single long line of repeated words
+    struct Words(usize);
+
+    impl io::Read for Words {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let len = buf.len().min(self.0) / 8 * 8;
+            for chunk in buf[..len].chunks_mut(8) {
+                chunk.copy_from_slice(b"FOO bar ");
+            }
+            self.0 -= len;
+            Ok(len)
+        }
+    }
+
+    // Records the total and the largest size of the writes made to it
+    #[derive(Default)]
+    struct WriteSizes {
+        total: usize,
+        largest: usize,
+    }
+
+    impl Write for WriteSizes {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.total += buf.len();
+            self.largest = self.largest.max(buf.len());
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn long_lines() {
+        let input = io::Read::chain(
+            io::BufReader::new(Words(8 * 1024 * 1024)),
+            "\n#if 0\nhidden\n#endif\nend\n".as_bytes(),
+        );
+        let mut output = WriteSizes::default();
+        let mut context = Context::new();
+        context.define("FOO", "1");
+        process(input, &mut output, &mut context).unwrap();
+        // Each `FOO` becomes `1`, and the line is written in pieces
+        assert_eq!(output.total, 6 * 1024 * 1024 + "\nend\n".len());
+        assert!(output.largest < 1024 * 1024);
+
+        // Long lines which can't be split are still processed as a whole
+        let text = format!(
+            "F({})\n#if 1\n{}\n#endif\n",
+            "a".repeat(100_000),
+            "b".repeat(100_000)
+        );
+        let expected = format!("{}\n{}\n", "a".repeat(100_000), "b".repeat(100_000));
+        context.define_fn("F", vec!["x".into()], "x");
+        assert_eq!(process_str(&text, &mut context).unwrap(), expected);
+        context.undefine("F").define("LONG", "#define X");
+        let text = format!("LONG {}\n", "c ".repeat(50_000));
+        assert_eq!(process_str(&text, &mut context).unwrap(), "");
+        assert_eq!(context.get_macro("X").unwrap().len(), 100_000 - 1);
+    }
+
+    #[test]
+    fn case_insensitive_string_compare() {
+        let text = "#if \"Release\" == \"release\"\nequal\n#endif\n#if \"Release\" != \"release\"\ndifferent\n#endif\n";
+        let mut context = Context::new();
+        assert_eq!(process_str(text, &mut context).unwrap(), "different\n");
+
+        context.case_insensitive_string_compare(true);
+        assert_eq!(process_str(text, &mut context).unwrap(), "equal\n");
+        // Ordering and integer comparisons are unaffected
+        assert_eq!(
+            process_str("#if \"B\" < \"a\" && 10 == 10\nyes\n#endif\n", &mut context).unwrap(),
+            "yes\n"
+        );
+
+        // `#case` compares values as `==` does
+        let text = "#switch \"A\"\n#case \"a\"\nlower\n#default\nother\n#endswitch\n";
+        assert_eq!(process_str(text, &mut context).unwrap(), "lower\n");
+        context.case_insensitive_string_compare(false);
+        assert_eq!(process_str(text, &mut context).unwrap(), "other\n");
+    }
+
+    #[test]
+    fn to_cli_flags() {
+        let mut context = Context::new();
+        context
+            .define("SPACES", "a b")
+            .define("QUOTE", "it's")
+            .define("PATH", "/usr/include")
+            .define_fn("F", vec!["x".into(), "y".into()], "x+y");
+        assert_eq!(
+            context.to_cli_flags(),
+            [
+                "'-DF(x,y)=x+y'",
+                "-DPATH=/usr/include",
+                "'-DQUOTE=it'\\''s'",
+                "'-DSPACES=a b'"
+            ]
+        );
+        assert!(Context::new().to_cli_flags().is_empty());
+    }
+
+    #[test]
+    fn comments_after_strings() {
+        let mut context = Context::new();
+        context.define("PATH", "\"http://x\"");
+        assert_eq!(
+            process_str(
+                "#if PATH == \"http://x\" // a // b\nyes\n#endif\n",
+                &mut context
+            )
+            .unwrap(),
+            "yes\n"
+        );
+        assert_eq!(
+            process_str(
+                "#if '/' == 47 && \"\\\"//\" != \"\" // \"\nyes\n#endif\n",
+                &mut context
+            )
+            .unwrap(),
+            "yes\n"
+        );
+        assert_eq!(
+            parse_directive("#if 8 / 2 == 4 // a / b"),
+            Some(("#if", Some("8 / 2 == 4")))
+        );
+        assert_eq!(
+            parse_directive("#define MSG it's // note"),
+            Some(("#define", Some("MSG it's")))
+        );
+        process_str(
+            "#define URL \"http://example.com\" // the site\n",
+            &mut context,
+        )
+        .unwrap();
+        assert_eq!(context.get_macro("URL").unwrap(), "\"http://example.com\"");
+    }
+
+    #[test]
+    fn strip_bom() {
+        let text = "\u{feff}#if FOO\nfoo\n#endif\nbar\n";
+        let mut context = Context::new();
+        context.define("FOO", "1");
+        assert_eq!(process_str(text, &mut context).unwrap(), "foo\nbar\n");
+        context.define("FOO", "0");
+        assert_eq!(process_str(text, &mut context).unwrap(), "bar\n");
+
+        context.strip_bom(false);
+        assert_eq!(process_str(text, &mut context).unwrap(), "\u{feff}bar\n");
+        assert_eq!(
+            process_str("\u{feff}text\n", &mut context).unwrap(),
+            "\u{feff}text\n"
+        );
+        // Only a mark at the very start is treated specially
+        assert_eq!(
+            process_str("a\n\u{feff}b\n", &mut context).unwrap(),
+            "a\n\u{feff}b\n"
+        );
+        context.include_resolver(|_, _| Ok(Some(("bom.h".into(), "\u{feff}included\n".into()))));
+        assert_eq!(
+            process_str("#include \"bom.h\"\n", &mut context).unwrap(),
+            "included\n"
+        );
+    }
+
+    #[test]
+    fn assert() {
+        let mut context = Context::new();
+        context.define("SIZE", "4");
+        assert_eq!(
+            process_str("#assert SIZE > 2 // big enough\ntext\n", &mut context).unwrap(),
+            "text\n"
+        );
+        match process_str("text\n#assert SIZE > 8 && defined(SIZE)\n", &mut context) {
+            Err(Error::Directive { line, msg }) => {
+                assert_eq!(line, 2);
+                assert_eq!(msg, "Assertion failed: SIZE > 8 && defined(SIZE)");
+            }
+            other => panic!("unexpected result: {:?}", other),
+        }
+        // Assertions in inactive branches are ignored
+        assert_eq!(
+            process_str("#if 0\n#assert 0\n#endif\n", &mut context).unwrap(),
+            ""
+        );
+        let mut output = Vec::new();
+        let (_, errors) =
+            process_collect_errors("#assert 0\nafter\n".as_bytes(), &mut output, &mut context);
+        assert_eq!(output, b"after\n");
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].to_string(), "Assertion failed: 0 on line 1");
+    }
+
+    #[test]
+    fn define_trim() {
+        let text = "#define FOO   spaced  \n#define BAR(x)   x  // comment\n";
+        let mut context = Context::new();
+        process_str(text, &mut context).unwrap();
+        assert_eq!(context.get_macro("FOO").unwrap(), "  spaced");
+        assert_eq!(process_str("BAR(1).", &mut context).unwrap(), "  1.");
+
+        context.define_trim(TrimPolicy::None);
+        process_str(text, &mut context).unwrap();
+        assert_eq!(context.get_macro("FOO").unwrap(), "  spaced  ");
+        assert_eq!(process_str("BAR(1).", &mut context).unwrap(), "  1  .");
 
-/// Preprocesses a generic buffer.
-///
-/// This function takes any generic BufRead input and Write output and preprocesses it.
-///
-/// # Example
-///
-/// ```
-/// let mut output = Vec::new();
-/// minipre::process("
-///     foo text
-///     #if !FOO
-///     more text
-///     #endif
-///     bar text".as_bytes(), &mut output, minipre::Context::new().define("FOO", "0"));
-///
-/// assert_eq!(String::from_utf8(output).unwrap(), "
-///     foo text
-///     more text
-///     bar text");
-/// ```
-pub fn process<I: BufRead, O: Write>(
-    mut input: I,
-    mut output: O,
-    context: &mut Context,
-) -> Result<(), Error> {
-    let mut buf = String::new();
-    let mut stack = Vec::new();
-    let mut state = State::Active;
-    let mut line = 0;
+        context.define_trim(TrimPolicy::Both);
+        process_str(text, &mut context).unwrap();
+        assert_eq!(context.get_macro("FOO").unwrap(), "spaced");
+        assert_eq!(process_str("BAR(1).", &mut context).unwrap(), "1.");
+        // The line terminator is never part of the value
+        process_str("#define EMPTY\r\n", context.define_trim(TrimPolicy::None)).unwrap();
+        assert_eq!(context.get_macro("EMPTY").unwrap(), "");
+    }
+
+    #[test]
+    fn multi_line_macros() {
+        let mut context = Context::new();
+        context
+            .define("TWO", "first\nsecond")
+            .define("BLOCK", "#if FLAG\nflagged\n#endif\n#define INNER 1");
+        assert_eq!(
+            process_str("x TWO y\nTWO\n", &mut context).unwrap(),
+            "x first\nsecond y\nfirst\nsecond\n"
+        );
+        context.define("FLAG", "1");
+        assert_eq!(
+            process_str("BLOCK\nINNER\n", &mut context).unwrap(),
+            "flagged\n1\n"
+        );
+        context.define("FLAG", "0");
+        assert_eq!(process_str("BLOCK\n", &mut context).unwrap(), "");
+        // Line numbers still count the lines of the input
+        match process_str("TWO\n#if\n#endif\n", &mut context) {
+            Err(Error::Syntax { line, .. }) => assert_eq!(line, 2),
+            other => panic!("unexpected result: {:?}", other),
+        }
+        context.no_directive_injection(true);
+        assert_eq!(
+            process_str("BLOCK\n", &mut context).unwrap(),
+            "#if FLAG\nflagged\n#endif\n#define INNER 1\n"
+        );
+    }
 
-    let regex = context.build_regex();
-    let mut replacer = context.replacer();
+    #[test]
+    fn process_frozen() {
+        let mut context = Context::new();
+        context.define("FOO", "1");
+        let frozen = std::sync::Arc::new(context.freeze());
+        context.define("FOO", "2");
 
-    while input.read_line(&mut buf)? > 0 {
-        line += 1;
-        {
-            let new_line = regex.replace_all(&buf, replacer.by_ref());
-            let substr = new_line.trim();
-            if substr.starts_with("#") {
-                let mut parts = substr.splitn(2, "//").next().unwrap().splitn(2, " ");
-                let name = parts.next().unwrap();
-                let maybe_expr = parts.next().map(|s| s.trim()).and_then(|s| {
-                    if s.is_empty() {
-                        None
-                    } else {
-                        Some(s)
-                    }
-                });
+        let threads: Vec<_> = (0..4)
+            .map(|i| {
+                let frozen = frozen.clone();
+                std::thread::spawn(move || {
+                    let text = format!("#if FOO == 1\nFOO {}\n#endif\n", i);
+                    let mut output = Vec::new();
+                    super::process_frozen(text.as_bytes(), &mut output, &frozen).unwrap();
+                    String::from_utf8(output).unwrap()
+                })
+            })
+            .collect();
+        for (i, thread) in threads.into_iter().enumerate() {
+            assert_eq!(thread.join().unwrap(), format!("1 {}\n", i));
+        }
 
-                match name {
-                    "#if" => {
-                        let expr = maybe_expr.ok_or_else(|| Error::Syntax {
-                            line,
-                            msg: "Expected expression after `#if`",
-                        })?;
-                        stack.push(state);
-                        if state == State::Active {
-                            if !context.evaluate(expr, line)? {
-                                state = State::Inactive;
-                            }
-                        } else {
-                            state = State::Skip;
-                        }
-                    }
-                    "#elif" => {
-                        let expr = maybe_expr.ok_or_else(|| Error::Syntax {
-                            line,
-                            msg: "Expected expression after `#elif`",
-                        })?;
-                        if state == State::Inactive {
-                            if context.evaluate(expr, line)? {
-                                state = State::Active;
-                            }
-                        } else {
-                            state = State::Skip;
-                        }
-                    }
-                    "#else" => {
-                        if maybe_expr.is_some() {
-                            return Err(Error::Syntax {
-                                line,
-                                msg: "Unexpected expression after `#else`",
-                            });
-                        }
-                        if state == State::Inactive {
-                            state = State::Active;
-                        } else {
-                            state = State::Skip;
-                        }
-                    }
-                    "#endif" => {
-                        if maybe_expr.is_some() {
-                            return Err(Error::Syntax {
-                                line,
-                                msg: "Unexpected expression after `#else`",
-                            });
-                        }
-                        state = stack.pop().ok_or_else(|| Error::Syntax {
-                            line,
-                            msg: "Unexpected `#endif` with no matching `#if`",
-                        })?;
-                    }
-                    _ => {
-                        return Err(Error::Syntax {
-                            line,
-                            msg: "Unrecognised preprocessor directive",
-                        });
-                    }
+        for text in ["#define BAR 1\n", "#set BAR = 1\n", "#undef FOO\n"] {
+            match super::process_frozen(text.as_bytes(), &mut Vec::new(), &frozen) {
+                Err(Error::Syntax { line: 1, msg }) => {
+                    assert_eq!(msg, "Cannot change the macros of a frozen context")
                 }
-            } else if state == State::Active {
-                output.write_all(new_line.as_bytes())?;
+                other => panic!("unexpected result: {:?}", other),
             }
         }
-        buf.clear();
+        // Directives which aren't reached don't fail
+        let text = "#ifndef FOO\n#define FOO 1\n#endif\n";
+        super::process_frozen(text.as_bytes(), &mut Vec::new(), &frozen).unwrap();
+        assert_eq!(frozen.context().get_macro("FOO").unwrap(), "1");
+
+        // Copies of the context share its macros, until a `#repeat` block changes them
+        let mut copy = frozen.context().clone();
+        assert!(Arc::ptr_eq(&copy.defs, &frozen.context().defs));
+        let text = "#repeat 1\n#endrepeat\n";
+        process(text.as_bytes(), &mut Vec::new(), &mut copy).unwrap();
+        assert!(!Arc::ptr_eq(&copy.defs, &frozen.context().defs));
+        assert!(copy.get_macro(REPEAT_INDEX).is_none());
     }
-    Ok(())
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    #[test]
+    fn null_directive() {
+        let mut context = Context::new();
+        assert_eq!(
+            process_str("a\n#\n  #  // comment\nb\n", &mut context).unwrap(),
+            "a\nb\n"
+        );
+        match process_str("# foo\n", &mut context) {
+            Err(Error::Syntax { line: 1, msg }) => {
+                assert_eq!(msg, "Unrecognised preprocessor directive")
+            }
+            other => panic!("unexpected result: {:?}", other),
+        }
+        context.directive_names(DirectiveNames::with_prefix("%"));
+        assert_eq!(process_str("a\n%\nb\n", &mut context).unwrap(), "a\nb\n");
+    }
 
     #[test]
-    fn pass_through() {
+    fn process_with_outputs() {
+        let text = "header
+#output \"first\"
+#if FOO
+one
+#else
+#output \"second\"
+#endif
+two
+#output \"second\"
+three
+#output
+footer";
+        let mut outputs = BTreeMap::new();
+        outputs.insert("first".to_string(), Vec::new());
+        outputs.insert("second".to_string(), Vec::new());
+        let mut output = Vec::new();
+        let mut context = Context::new();
+        context.define("FOO", "1").trim_trailing_newline(true);
+        super::process_with_outputs(text.as_bytes(), &mut output, &mut outputs, &mut context)
+            .unwrap();
+        assert_eq!(output, b"header\nfooter");
+        assert_eq!(outputs["first"], b"one\ntwo");
+        assert_eq!(outputs["second"], b"three");
+
+        for (text, msg) in [
+            ("#output \"third\"\n", "Unknown output"),
+            ("#output third\n", "Expected \"name\" after `#output`"),
+        ] {
+            match super::process_with_outputs(
+                text.as_bytes(),
+                &mut output,
+                &mut outputs,
+                &mut context,
+            ) {
+                Err(Error::Syntax { line: 1, msg: m }) => assert_eq!(m, msg),
+                other => panic!("unexpected result: {:?}", other),
+            }
+        }
+        match process_str("#output \"first\"\n", &mut context) {
+            Err(Error::Syntax { line: 1, msg }) => assert_eq!(msg, "No named outputs are set"),
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn tight_operators() {
+        let mut context = Context::new();
+        context.define("FOO", "1").define("BAR", "2");
+        for expr in [
+            "FOO==1",
+            "FOO ==1",
+            "FOO== 1",
+            "FOO+BAR==3",
+            "(FOO)<BAR&&BAR>=2",
+            "!FOO||BAR!=FOO",
+        ] {
+            let text = format!("#if {}\nyes\n#endif\n", expr);
+            assert_eq!(
+                process_str(&text, &mut context).unwrap(),
+                "yes\n",
+                "{}",
+                expr
+            );
+        }
         assert_eq!(
-            &process_str(
-                "
-            some
-            multiline
-            text
-            with # symbols
-        ",
-                &mut Context::new()
-            )
-            .unwrap(),
-            "
-            some
-            multiline
-            text
-            with # symbols
-        "
+            process_str("#if FOO==2\nyes\n#endif\n", &mut context).unwrap(),
+            ""
+        );
+        // Macros are expanded next to operators in text too
+        assert_eq!(
+            process_str("FOO==BAR+FOO\n", &mut context).unwrap(),
+            "1==2+1\n"
         );
     }
 
     #[test]
-    fn variable() {
+    fn identifier_chars() {
+        let mut context = Context::new();
+        context.identifier_chars(
+            |c| c == '$' || c == '_' || c.is_ascii_alphabetic(),
+            |c| c == '_' || c.is_ascii_alphanumeric(),
+        );
+        context.define_from_str("$foo=1\nbar2=2").unwrap();
         assert_eq!(
-            &process_str(
-                "
-            some
-            #if FOO
-            multiline
-            text
-            #endif
-            with # symbols
-        ",
-                Context::new().define("FOO", "0")
+            process_str(
+                "#if $foo + bar2 == 3\n$foo bar2 x$foo $foo$ ($foo)\n#endif\n",
+                &mut context
             )
             .unwrap(),
-            "
-            some
-            with # symbols
-        "
+            "1 2 x$foo $foo$ (1)\n"
+        );
+        assert_eq!(
+            process_str("#define $f($x) [$x]\n$f(bar2)\n", &mut context).unwrap(),
+            "[2]\n"
         );
 
+        // Names can't start with a digit, or another character which only continues a name
+        assert!(context.define_from_str("1foo=1").is_err());
+        for text in ["#define 1foo 1\n", "#define a$b 1\n", "#if 1foo\n#endif\n"] {
+            assert!(process_str(text, &mut context).is_err(), "{}", text);
+        }
+        context.identifier_chars(
+            |c| c.is_ascii_lowercase(),
+            |c| c.is_ascii_lowercase() || c == '-',
+        );
+        assert!(context.define_from_str("-a=1").is_err());
+        context.define_from_str("a-b=1").unwrap();
         assert_eq!(
-            &process_str(
-                "
-            some
-            #if FOO
-            multiline
-            text
-            #endif
-            with # symbols
-        ",
-                Context::new().define("FOO", "1")
-            )
-            .unwrap(),
-            "
-            some
-            multiline
-            text
-            with # symbols
-        "
+            process_str("#if a-b\na-b a\n#endif\n", &mut context).unwrap(),
+            "1 a\n"
         );
     }
 
     #[test]
-    fn constant() {
+    fn gated_by_markers() {
+        let text = "#pragma once
+#if FOO
+FOO before
+#minipre on
+#if FOO
+FOO between
+#else
+hidden
+#endif
+  #minipre off  
+#include <stdio.h>
+FOO after
+#minipre on
+FOO again
+";
+        let mut context = Context::new();
+        context
+            .define("FOO", "1")
+            .gated_by_markers("#minipre on", "#minipre off");
         assert_eq!(
-            &process_str(
-                "
-            some
-            #if 0
-            multiline
-            text
-            #endif
-            with # symbols
-        ",
-                &mut Context::new()
-            )
-            .unwrap(),
-            "
-            some
-            with # symbols
-        "
+            process_str(text, &mut context).unwrap(),
+            "#pragma once\n#if FOO\nFOO before\n1 between\n#include <stdio.h>\nFOO after\n1 again\n"
         );
-
+        // Without an `on` marker, everything is written as it is
         assert_eq!(
-            &process_str(
-                "
-            some
-            #if 1
-            multiline
-            text
-            #endif
-            with # symbols
-        ",
-                &mut Context::new()
-            )
-            .unwrap(),
-            "
-            some
-            multiline
-            text
-            with # symbols
-        "
+            process_str("#if 0\nFOO\n", &mut context).unwrap(),
+            "#if 0\nFOO\n"
         );
     }
 
     #[test]
-    fn negation() {
+    fn include_str() {
+        let mut context = Context::new();
+        context.define("FOO", "1").include_resolver(|name, _| {
+            Ok(match name {
+                "raw.txt" => Some((
+                    "raw.txt".into(),
+                    "#if 0\nFOO\n#endif\n#define BAR 2\n".into(),
+                )),
+                "partial.txt" => Some(("partial.txt".into(), "no newline".into())),
+                _ => None,
+            })
+        });
+        let text =
+            "before\n#include_str \"raw.txt\"\nBAR\n#if 0\n#include_str \"missing.txt\"\n#endif\n";
         assert_eq!(
-            &process_str(
-                "
-            some
-            #if !FOO
-            multiline
-            text
-            #endif
-            with # symbols
-        ",
-                Context::new().define("FOO", "1")
-            )
-            .unwrap(),
-            "
-            some
-            with # symbols
-        "
+            process_str(text, &mut context).unwrap(),
+            "before\n#if 0\nFOO\n#endif\n#define BAR 2\nBAR\n"
+        );
+        assert!(context.get_macro("BAR").is_none());
+        assert_eq!(
+            process_str("#include_str <partial.txt>\n!\n", &mut context).unwrap(),
+            "no newline\n!\n"
         );
+        let (_, deps) =
+            super::process_with_deps("#include_str \"raw.txt\"\n".as_bytes(), &mut context)
+                .unwrap();
+        assert_eq!(deps, [PathBuf::from("raw.txt")]);
+        assert!(process_str("#include_str \"missing.txt\"\n", &mut context).is_err());
+    }
 
+    #[test]
+    fn analyze() {
+        let text = "#if defined(A) && B > 1
+  #ifdef C
+    #if A == \"D\" || __has_include(\"E\")
+    #endif
+  #elif min(F, 2)
+  #else
+  #endif
+  text G
+#elifndef H
+#endif
+#ifndef A
+#endif
+";
+        let analysis = super::analyze(text.as_bytes(), &Context::new()).unwrap();
         assert_eq!(
-            &process_str(
-                "
-            some
-            #if !FOO
-            multiline
-            text
-            #endif
-            with # symbols
-        ",
-                Context::new().define("FOO", "0")
-            )
-            .unwrap(),
-            "
-            some
-            multiline
-            text
-            with # symbols
-        "
+            analysis,
+            Analysis {
+                ifs: 4,
+                elifs: 2,
+                elses: 1,
+                endifs: 4,
+                max_depth: 3,
+                macros: ["A", "B", "C", "F", "H"]
+                    .iter()
+                    .map(|&name| name.into())
+                    .collect(),
+            }
         );
+        match super::analyze("#if A\n#endif\n#endif\n".as_bytes(), &Context::new()) {
+            Err(Error::Syntax { line: 3, msg }) => {
+                assert_eq!(msg, "Unexpected `#endif` with no matching `#if`")
+            }
+            other => panic!("unexpected result: {:?}", other),
+        }
     }
 
     #[test]
-    fn else_() {
+    fn define_pattern() {
+        let mut context = Context::new();
+        context
+            .define("LEVEL", "WARN")
+            .define_pattern(r"\bLOG_(\w+)\(", "log(\"$1\", ")
+            .unwrap()
+            .define_pattern(r"(?P<a>\d+)\.\.(?P<b>\d+)", "range(${a}, ${b})")
+            .unwrap();
         assert_eq!(
-            &process_str(
-                "
-            some
-            #if FOO
-            multiline
-            #else
-            text
-            #endif
-            with # symbols
-        ",
-                Context::new().define("FOO", "0")
-            )
-            .unwrap(),
-            "
-            some
-            text
-            with # symbols
-        "
+            process_str("LOG_INFO(x); LOG_LEVEL(y);\nfor 1..10\n", &mut context).unwrap(),
+            "log(\"INFO\", x); log(\"LEVEL\", y);\nfor range(1, 10)\n"
         );
-
+        // Patterns apply to the expansion of other macros, but not within directives
+        context.define("SPAN", "0..4");
         assert_eq!(
-            &process_str(
-                "
-            some
-            #if FOO
-            multiline
-            #else
-            text
-            #endif
-            with # symbols
-        ",
-                Context::new().define("FOO", "1")
-            )
-            .unwrap(),
-            "
-            some
-            multiline
-            with # symbols
-        "
+            process_str("#if 1 // 2..3\nSPAN\n#endif\n", &mut context).unwrap(),
+            "range(0, 4)\n"
         );
+        assert!(context.define_pattern("(", "").is_err());
     }
 
     #[test]
-    fn elif() {
+    fn expand_content() {
+        let text = "#if FEATURE && LEVEL > 1\nFEATURE LEVEL\n#elif 1\nother\n#endif\n";
+        let mut context = Context::new();
+        context.define("FEATURE", "1").define("LEVEL", "2");
+        assert_eq!(process_str(text, &mut context).unwrap(), "1 2\n");
+
+        context.expand_content(false);
+        assert_eq!(process_str(text, &mut context).unwrap(), "FEATURE LEVEL\n");
+        // Content can't become a directive either
+        context.define("GUARD", "#endif");
+        assert_eq!(process_str("GUARD\n", &mut context).unwrap(), "GUARD\n");
+    }
+
+    #[test]
+    fn directive_spans() {
+        let input = "foo\n  #define A 1  \r\n#repeat 2\n#undef A\n#endrepeat\n";
+        let mut output = Vec::new();
+        let spans = process_with_spans(input.as_bytes(), &mut output, &mut Context::new()).unwrap();
+        let found: Vec<_> = spans
+            .iter()
+            .map(|span| (span.line, span.name.as_str(), &input[span.start..span.end]))
+            .collect();
         assert_eq!(
-            &process_str(
-                "
-            some
-            #if FOO
-            multiline
-            #elif 1
-            text
-            #endif
-            with # symbols
-        ",
-                Context::new().define("FOO", "0")
-            )
-            .unwrap(),
-            "
-            some
-            text
-            with # symbols
-        "
+            found,
+            [
+                (2, "#define", "#define A 1"),
+                (3, "#repeat", "#repeat 2"),
+                (4, "#undef", "#undef A"),
+                (5, "#endrepeat", "#endrepeat"),
+            ]
         );
+    }
+
+    #[test]
+    fn cpp_compat() {
+        let mut context = Context::new();
+        context.cpp_compat(true);
+        let text = "#if UNDEFINED\nfoo\n#elif !OTHER && UNDEFINED + 1 == 1\nbar\n#endif\n";
+        assert_eq!(process_str(text, &mut context).unwrap(), "bar\n");
+        for text in ["#set X = UNDEFINED\n", "#assert UNDEFINED\n"] {
+            match process_str(text, &mut context) {
+                Err(Error::UndefinedIdentifier { line: 1, name }) => {
+                    assert_eq!(name, "UNDEFINED")
+                }
+                other => panic!("{:?}", other),
+            }
+        }
+        context.cpp_compat(false);
+        assert!(process_str("#if UNDEFINED\n#endif\n", &mut context).is_err());
+    }
 
+    #[test]
+    fn get_or_define() {
+        let mut context = Context::new();
+        assert_eq!(process_str("FOO\n", &mut context).unwrap(), "FOO\n");
+        let mut calls = 0;
         assert_eq!(
-            &process_str(
-                "
-            some
-            #if FOO
-            multiline
-            #elif 1
-            text
-            #endif
-            with # symbols
-        ",
-                Context::new().define("FOO", "1")
-            )
-            .unwrap(),
-            "
-            some
-            multiline
-            with # symbols
-        "
+            context.get_or_define("FOO", || {
+                calls += 1;
+                "1".into()
+            }),
+            "1"
         );
-
         assert_eq!(
-            &process_str(
-                "
-            some
-            #if FOO
-            multiline
-            #elif 0
-            text
-            #endif
-            with # symbols
-        ",
-                Context::new().define("FOO", "0")
-            )
-            .unwrap(),
-            "
-            some
-            with # symbols
-        "
+            context.get_or_define("FOO", || {
+                calls += 1;
+                "2".into()
+            }),
+            "1"
         );
+        assert_eq!(calls, 1);
+        assert_eq!(process_str("FOO\n", &mut context).unwrap(), "1\n");
+    }
+
+    #[test]
+    fn leading_comment_prefix() {
+        let mut context = Context::new();
+        context
+            .leading_comment_prefix(Some("//".into()))
+            .define("FOO", "1");
+        let text = "// #if FOO\nfoo\n  //#else\nbar\n// #endif\n// FOO\n//\n";
+        assert_eq!(process_str(text, &mut context).unwrap(), "foo\n// 1\n//\n");
+        let analysis = super::analyze(text.as_bytes(), &context).unwrap();
+        assert_eq!((analysis.ifs, analysis.elses, analysis.endifs), (1, 1, 1));
 
+        context.leading_comment_prefix(None);
         assert_eq!(
-            &process_str(
-                "
-            some
-            #if FOO
-            multiline
-            #elif 0
-            text
-            #else
-            with # symbols
-            #endif
-        ",
-                Context::new().define("FOO", "0")
-            )
-            .unwrap(),
-            "
-            some
-            with # symbols
-        "
+            process_str("// #if 0\n", &mut context).unwrap(),
+            "// #if 0\n"
         );
+    }
+
+    #[test]
+    fn empty_macros_in_arithmetic() {
+        let mut context = Context::new();
+        context.define("N", "");
+        for text in ["#if N + 1\n#endif\n", "#if 1 + N\n#endif\n"] {
+            match process_str(text, &mut context) {
+                Err(Error::Syntax { line: 1, msg }) => {
+                    assert_eq!(msg, "Expected term, found a macro which is empty")
+                }
+                other => panic!("{:?}", other),
+            }
+        }
 
+        context.cpp_compat(true);
+        let text = "#if N + 1 > 0\na\n#endif\n#if N\nb\n#elif 1 + N == 1\nc\n#endif\n";
+        assert_eq!(process_str(text, &mut context).unwrap(), "a\nc\n");
         assert_eq!(
-            &process_str(
-                "
-            some
-            #if FOO
-            multiline
-            #elif 1
-            text
-            #else
-            with # symbols
-            #endif
-        ",
-                Context::new().define("FOO", "0")
-            )
-            .unwrap(),
-            "
-            some
-            text
-        "
+            process_str("#if N\n#endif\n#if UNDEFINED + N\n#endif\n", &mut context).unwrap(),
+            ""
         );
     }
 
     #[test]
-    fn equality() {
-        assert_eq!(
-            &process_str(
-                "
-            some
-            #if FOO == 1
-            multiline
-            text
-            #endif
-            with # symbols
-        ",
-                Context::new().define("FOO", "0")
-            )
-            .unwrap(),
-            "
-            some
-            with # symbols
-        "
+    fn process_collect_undefined() {
+        let mut context = Context::new();
+        context
+            .define("FOO", "1")
+            .undefined_mode(UndefinedMode::Passthrough);
+        let text = "#if !A && FOO\na\n#endif\n#if 0 && SKIPPED\n#elif B + C == 0\nb\n#endif\n#set D = A\nD\n";
+        let (output, names) =
+            super::process_collect_undefined(text.as_bytes(), &mut context).unwrap();
+        assert_eq!(output, "a\nb\n0\n");
+        assert_eq!(names.iter().collect::<Vec<_>>(), ["A", "B", "C"]);
+        assert!(context.undefined_names.is_none());
+    }
+
+    #[test]
+    fn difference() {
+        let mut a = Context::new();
+        a.define("A", "1")
+            .define("B", "2")
+            .define("C", "3")
+            .define_fn("F", vec!["x".into()], "x");
+        let mut b = Context::new();
+        b.define("B", "4")
+            .define_fn("C", vec![], "")
+            .define("D", "5");
+        assert_eq!(a.difference(&b).collect::<Vec<_>>(), [("A", "1")]);
+        assert_eq!(b.difference(&a).collect::<Vec<_>>(), [("D", "5")]);
+        assert_eq!(a.difference(&a).count(), 0);
+    }
+
+    #[test]
+    fn process_with_sink() {
+        let mut context = Context::new();
+        context.define("FOO", "1");
+        let text = "a FOO\n#if FOO\nb\n#endif\n#define FOO 2\nc FOO\nd\n";
+        let mut lines = Vec::new();
+        super::process_with_sink(
+            text.as_bytes(),
+            |line| {
+                lines.push(line.to_string());
+                Ok(())
+            },
+            &mut context,
+        )
+        .unwrap();
+        assert_eq!(lines, ["a 1", "b", "c 2", "d"]);
+
+        let mut lines = Vec::new();
+        let result = super::process_with_sink(
+            text.as_bytes(),
+            |line| {
+                if line == "b" {
+                    return Err(Error::Cancelled);
+                }
+                lines.push(line.to_string());
+                Ok(())
+            },
+            &mut context,
         );
+        assert!(matches!(result, Err(Error::Cancelled)));
+        assert_eq!(lines, ["a 2"]);
+    }
 
+    #[test]
+    fn nested_macro_args() {
+        let mut context = Context::new();
+        context
+            .define_fn("FIRST", vec!["a".into(), "b".into()], "<a>")
+            .define_fn("SECOND", vec!["a".into(), "b".into()], "<b>")
+            .define_fn("ADD", vec!["a".into(), "b".into()], "(a + b)")
+            .define_fn("MUL", vec!["a".into(), "b".into()], "(a * b)");
+        let text = "FIRST((1, 2), 3) SECOND(ADD(1, 2), 3)\nFIRST(\"a, b\", c) SECOND(',', \"\\\", )\")\nFIRST(it's, x)\n";
         assert_eq!(
-            &process_str(
-                "
-            some
-            #if FOO == 0
-            multiline
-            text
-            #endif
-            with # symbols
-        ",
-                Context::new().define("FOO", "0")
-            )
-            .unwrap(),
-            "
-            some
-            multiline
-            text
-            with # symbols
-        "
+            process_str(text, &mut context).unwrap(),
+            "<(1, 2)> <3>\n<\"a, b\"> <\"\\\", )\">\n<it's>\n"
+        );
+        assert_eq!(
+            process_str("#if ADD(MUL(2, 3), 4) == 10\nyes\n#endif\n", &mut context).unwrap(),
+            "yes\n"
         );
     }
 
     #[test]
-    fn expansion() {
+    fn variadic_macros() {
+        let mut context = Context::new();
+        let text = "#define LOG(fmt, ...) printf(fmt, __VA_ARGS__)\n#define ELIDE(fmt, ...) printf(fmt , ## __VA_ARGS__)\n#define ALL(...) [__VA_ARGS__]\nLOG(\"%d %s\", 1, \"a, b\")\nLOG(\"%d\", (1, 2))\nLOG(\"\")\nELIDE(\"%d\", x,y)\nELIDE(\"\")\nALL() ALL(1) ALL(1,2)\n";
         assert_eq!(
-            &process_str(
-                "
-            some
-            FOO-BAR
-            multiline
-        ",
-                Context::new().define("FOO", "0")
+            process_str(text, &mut context).unwrap(),
+            concat!(
+                "printf(\"%d %s\", 1, \"a, b\")\n",
+                "printf(\"%d\", (1, 2))\n",
+                "printf(\"\", )\n",
+                "printf(\"%d\" ,x, y)\n",
+                "printf(\"\")\n",
+                "[] [1] [1, 2]\n",
             )
-            .unwrap(),
-            "
-            some
-            0-BAR
-            multiline
-        "
         );
-
         assert_eq!(
-            &process_str(
-                "
-            some
-            FOO_BAR
-            multiline
-        ",
-                Context::new().define("FOO", "0")
+            process_str(
+                "#define SUM(a, ...) (a + __VA_ARGS__)\n#if SUM(1, 2) == 3\nyes\n#endif\n",
+                &mut context
             )
             .unwrap(),
-            "
-            some
-            FOO_BAR
-            multiline
-        "
+            "yes\n"
+        );
+        assert!(process_str("#define BAD(..., a) a\n", &mut context).is_err());
+        assert!(process_str("LOG()\n", &mut context).is_ok());
+        assert!(process_str("#define TWO(a, b, ...) a\nTWO(1)\n", &mut context).is_err());
+    }
+
+    #[test]
+    fn max_output_size() {
+        let mut context = Context::new();
+        context.define("A", "x".repeat(1000)).max_output_size(2000);
+        assert_eq!(process_str("A\n", &mut context).unwrap().len(), 1001);
+        let mut output = Vec::new();
+        match process(
+            "ok\nA A\nnot written\n".as_bytes(),
+            &mut output,
+            &mut context,
+        ) {
+            Err(Error::Syntax { line: 2, msg }) => assert_eq!(msg, "Output size limit exceeded"),
+            other => panic!("{:?}", other),
+        }
+        assert_eq!(output, b"ok\n");
+
+        let text = "#repeat 1000000\n#repeat 1000000\nabc\n#endrepeat\n#endrepeat\n";
+        match process_str(text, &mut context) {
+            Err(Error::Syntax { line: 5, .. }) => {}
+            other => panic!("{:?}", other),
+        }
+        let lines: Result<Vec<_>, _> =
+            super::process_lines("A A\n".as_bytes(), &mut context).collect();
+        assert!(lines.is_err());
+
+        let mut context = Context::new();
+        context.max_output_size(4);
+        let mut processor = LineProcessor::new(Vec::new(), context.clone());
+        let err = processor.write_all(b"abc\ndef\nghi\n").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        let mut processor = LineProcessor::new(Vec::new(), context);
+        processor.write_all(b"abc\ndef").unwrap();
+        match processor.finish() {
+            Err(Error::Syntax { line: 2, msg }) => assert_eq!(msg, "Output size limit exceeded"),
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn block_scoped_defines() {
+        let text = "#define A 1\n#if 1\n#define A 2\n#define B 3\n#ifdef B\n#undef A\n#endif\nA B\n#endif\nA B\n";
+        let mut context = Context::new();
+        context.block_scoped_defines(true);
+        assert_eq!(process_str(text, &mut context).unwrap(), "2 3\n1 B\n");
+        assert_eq!(context.get_macro("A").unwrap(), "1");
+        assert!(context.get_macro("B").is_none());
+
+        let mut context = Context::new();
+        assert_eq!(process_str(text, &mut context).unwrap(), "A 3\nA 3\n");
+        assert_eq!(context.get_macro("B").unwrap(), "3");
+    }
+
+    #[test]
+    fn parse_structure() {
+        let text = "a\n#if A // comment\n#ifdef B\n#elif C\n#if D\n#endif\n#endif\n#elifndef E\n#else\n  #if F\n  #endif\n#endif\n#if G\n#endif\n";
+        fn branch(
+            directive: &str,
+            condition: Option<&str>,
+            line: u32,
+            blocks: Vec<ConditionalBlock>,
+        ) -> ConditionalBranch {
+            ConditionalBranch {
+                directive: directive.into(),
+                condition: condition.map(Into::into),
+                line,
+                blocks,
+            }
+        }
+        fn block(start: u32, end: u32, branches: Vec<ConditionalBranch>) -> ConditionalBlock {
+            ConditionalBlock {
+                start,
+                end,
+                branches,
+            }
+        }
+        assert_eq!(
+            super::parse_structure(text.as_bytes()).unwrap(),
+            [
+                block(
+                    2,
+                    12,
+                    vec![
+                        branch(
+                            "#if",
+                            Some("A"),
+                            2,
+                            vec![block(
+                                3,
+                                7,
+                                vec![
+                                    branch("#ifdef", Some("B"), 3, vec![]),
+                                    branch(
+                                        "#elif",
+                                        Some("C"),
+                                        4,
+                                        vec![block(
+                                            5,
+                                            6,
+                                            vec![branch("#if", Some("D"), 5, vec![])]
+                                        )]
+                                    ),
+                                ]
+                            )]
+                        ),
+                        branch("#elifndef", Some("E"), 8, vec![]),
+                        branch(
+                            "#else",
+                            None,
+                            9,
+                            vec![block(10, 11, vec![branch("#if", Some("F"), 10, vec![])])]
+                        ),
+                    ]
+                ),
+                block(13, 14, vec![branch("#if", Some("G"), 13, vec![])]),
+            ]
         );
+
+        for (text, error_line) in [
+            ("#if A\n#if B\n#endif\n", 1),
+            ("#endif\n", 1),
+            ("x\n#else\n", 2),
+        ] {
+            match super::parse_structure(text.as_bytes()) {
+                Err(Error::Syntax { line, .. }) => assert_eq!(line, error_line),
+                other => panic!("{:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn unwind_safe() {
+        let mut context = Context::new();
+        context
+            .include_resolver(|name, _| Ok(Some((PathBuf::from(name), "a\n".into()))))
+            .identifier_chars(|c| c.is_alphabetic(), |c| c.is_alphanumeric())
+            .set_trace(Box::new(io::sink()));
+        let result = std::panic::catch_unwind(|| {
+            let mut context = context.clone();
+            process_str("#include \"a.h\"\n", &mut context).unwrap()
+        });
+        assert_eq!(result.unwrap(), "a\n");
+        let result = std::panic::catch_unwind(move || process_str("b\n", &mut context).unwrap());
+        assert_eq!(result.unwrap(), "b\n");
     }
 }